@@ -0,0 +1,78 @@
+//! Throughput of `Db` with many concurrent clients, sharded against a single
+//! lock (one shard, the design before sharding).
+//!
+//! Run with `cargo bench --bench db`. Every client is a task on the
+//! multi-threaded runtime issuing `GET`s and `SET`s on random keys, without
+//! any network in between, so the numbers show lock contention only.
+
+use bytes::Bytes;
+use kv_store_client::db::{Db, DEFAULT_SHARDS};
+use std::time::{Duration, Instant};
+
+const CLIENTS: [usize; 3] = [8, 64, 256];
+const OPERATIONS_PER_CLIENT: usize = 20_000;
+const KEYS: u64 = 10_000;
+/// One in this many operations is a `SET`, the others are `GET`s.
+const WRITE_EVERY: u64 = 5;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // with a single core the clients never run at the same time and every
+    // design performs the same
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} worker threads", threads);
+    println!(
+        "{:>8} {:>8} {:>14} {:>10}",
+        "shards", "clients", "ops/s", "elapsed"
+    );
+    for clients in CLIENTS {
+        for shards in [1, DEFAULT_SHARDS] {
+            let (operations, elapsed) = runtime.block_on(run(shards, clients));
+            println!(
+                "{:>8} {:>8} {:>14.0} {:>10.2?}",
+                shards,
+                clients,
+                operations as f64 / elapsed.as_secs_f64(),
+                elapsed
+            );
+        }
+    }
+}
+
+async fn run(shards: usize, clients: usize) -> (usize, Duration) {
+    let db = Db::with_shards(shards);
+    for key in 0..KEYS {
+        db.set(format!("key:{}", key), Bytes::from_static(b"value"), None);
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|client| {
+            let db = db.clone();
+            tokio::spawn(async move { client_loop(db, client as u64) })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    (clients * OPERATIONS_PER_CLIENT, start.elapsed())
+}
+
+fn client_loop(db: Db, seed: u64) {
+    // xorshift, good enough to pick keys and cheap next to the locking
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    for _ in 0..OPERATIONS_PER_CLIENT {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let key = format!("key:{}", state % KEYS);
+        if state.is_multiple_of(WRITE_EVERY) {
+            db.set(key, Bytes::from_static(b"updated"), None);
+        } else {
+            std::hint::black_box(db.get(&key).unwrap());
+        }
+    }
+}
@@ -1,12 +1,13 @@
-use mini_redis::{client, Result};
+use kv_store_client::client::Client;
+use kv_store_client::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Open a connection to the mini-redis address.
-    let mut client = client::connect("127.0.0.1:6379").await?;
+    // Open a connection to the server.
+    let mut client = Client::connect("127.0.0.1:6379").await?;
 
     // Set the key "hello" with value "world"
-    client.set("hello", "world".into()).await?;
+    client.set("hello", "world").await?;
 
     // Get key "hello"
     let result = client.get("hello").await?;
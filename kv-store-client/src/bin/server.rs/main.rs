@@ -1,58 +1,85 @@
-use bytes::Bytes;
-use mini_redis::{Connection, Frame};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::net::{TcpListener, TcpStream};
+use clap::Parser;
+use kv_store_client::server::{self, Aof, Fsync, Snapshots};
+use kv_store_client::Db;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpListener;
 
-type Db = Arc<Mutex<HashMap<String, Bytes>>>;
+#[derive(Parser)]
+#[clap(about = "Redis compatible key-value server")]
+struct Options {
+    /// Port to listen on
+    #[clap(long, default_value_t = 6379)]
+    port: u16,
 
-#[tokio::main]
-async fn main() {
-    // Bind the listener to the address
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    /// Snapshot file, loaded at startup and written by SAVE and BGSAVE
+    #[clap(long, default_value = "dump.rdb")]
+    dbfilename: PathBuf,
 
-    println!("Listening");
+    /// Seconds between automatic snapshots when keys changed, 0 disables them
+    #[clap(long, default_value_t = 60)]
+    save_interval: u64,
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    /// Append every write to a file, replayed at startup instead of loading
+    /// the snapshot
+    #[clap(long)]
+    appendonly: bool,
 
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        // Clone the handle to the hash map.
-        let db = db.clone();
+    /// Append-only file, rewritten by BGREWRITEAOF
+    #[clap(long, default_value = "appendonly.aof")]
+    appendfilename: PathBuf,
 
-        println!("Accepted");
-        tokio::spawn(async move {
-            process(socket, db).await;
-        });
-    }
+    /// When the append-only file is synced to disk
+    #[clap(long, value_enum, default_value_t = Fsync::EverySec)]
+    appendfsync: Fsync,
 }
 
-async fn process(socket: TcpStream, db: Db) {
-    use mini_redis::Command::{self, Get, Set};
-
-    // Connection, provided by `mini-redis`, handles parsing frames from
-    // the socket
-    let mut connection = Connection::new(socket);
-
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut db = db.lock().unwrap();
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                let db = db.lock().unwrap();
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
-                }
-            }
-            cmd => panic!("unimplemented {:?}", cmd),
-        };
-
-        // Write the response to the client
-        connection.write_frame(&response).await.unwrap();
+#[tokio::main]
+async fn main() {
+    let options = Options::parse();
+
+    let db = Db::new();
+    let snapshots = Snapshots::new(db.clone(), options.dbfilename);
+    let loaded = if options.appendonly {
+        let path = options.appendfilename;
+        Aof::open(db.clone(), path.clone(), options.appendfsync).map(|(aof, commands)| {
+            println!("replayed {} commands from {}", commands, path.display());
+            Some(aof)
+        })
+    } else {
+        snapshots.load().map(|keys| {
+            println!("loaded {} keys from {}", keys, snapshots.path().display());
+            None
+        })
+    };
+    let aof = match loaded {
+        Ok(aof) => aof,
+        Err(error) => {
+            println!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    if options.save_interval > 0 {
+        let interval = Duration::from_secs(options.save_interval);
+        tokio::spawn(snapshots.clone().save_periodically(interval));
     }
+
+    // Bind the listener to the address
+    let listener = TcpListener::bind(("127.0.0.1", options.port))
+        .await
+        .unwrap();
+
+    println!("Listening");
+
+    server::run(
+        listener,
+        db,
+        snapshots.clone(),
+        aof,
+        tokio::signal::ctrl_c(),
+    )
+    .await;
+
+    // keys written since the last snapshot are not lost on Ctrl-C
+    snapshots.save_logged().await;
 }
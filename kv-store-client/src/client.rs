@@ -0,0 +1,392 @@
+//! An async client for the server.
+//!
+//! `Client` is one connection with a typed method per command, `Pipeline`
+//! sends several commands in one round trip, `Pool` shares connections
+//! between tasks and `Subscriber` receives pub/sub messages.
+
+mod pipeline;
+mod pool;
+mod subscriber;
+
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PooledClient};
+pub use subscriber::{Message, Subscriber};
+
+use crate::{Connection, Frame};
+use bytes::Bytes;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// One connection to the server.
+///
+/// An error reply from the server is returned as a `ReplyError` and leaves
+/// the connection usable. Any other error, or a call cancelled before its
+/// reply arrived, breaks it: later replies would not match their commands,
+/// so every further call fails.
+#[derive(Debug)]
+pub struct Client {
+    connection: Connection,
+    /// Set while replies are outstanding, and left set if they never
+    /// arrive.
+    busy: bool,
+}
+
+/// An error reply, such as `WRONGTYPE Operation against a key holding the
+/// wrong kind of value`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplyError {
+    message: String,
+}
+
+impl ReplyError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> crate::Result<Client> {
+        let socket = TcpStream::connect(addr).await?;
+        Ok(Client {
+            connection: Connection::new(socket),
+            busy: false,
+        })
+    }
+
+    /// Whether an error or a cancelled call left the connection unusable.
+    pub fn is_broken(&self) -> bool {
+        self.busy
+    }
+
+    /// Sends all commands of `pipeline` at once and returns their replies
+    /// in order. Error replies are left in place as `Frame::Error`, one
+    /// failed command does not hide the replies to the others.
+    pub async fn execute(&mut self, pipeline: &Pipeline) -> crate::Result<Vec<Frame>> {
+        let commands = pipeline.commands();
+        self.start()?;
+        self.connection.write_frames(commands).await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        while replies.len() < commands.len() {
+            replies.push(self.read_frame().await?);
+        }
+        self.busy = false;
+        Ok(replies)
+    }
+
+    pub async fn get(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bytes(self.call(Pipeline::new().get(key)).await?)
+    }
+
+    pub async fn set(&mut self, key: &str, value: impl Into<Bytes>) -> crate::Result<()> {
+        ok(self.call(Pipeline::new().set(key, value)).await?)
+    }
+
+    /// Sets `key` to expire after `expire`, with millisecond precision.
+    pub async fn set_expires(
+        &mut self,
+        key: &str,
+        value: impl Into<Bytes>,
+        expire: Duration,
+    ) -> crate::Result<()> {
+        ok(self
+            .call(Pipeline::new().set_expires(key, value, expire))
+            .await?)
+    }
+
+    /// Returns how many of the keys existed.
+    pub async fn del(&mut self, keys: &[&str]) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().del(keys)).await?)
+    }
+
+    /// Returns whether the key exists. A zero duration deletes it.
+    pub async fn expire(&mut self, key: &str, expire: Duration) -> crate::Result<bool> {
+        flag(self.call(Pipeline::new().expire(key, expire)).await?)
+    }
+
+    /// Time left before the key expires, `None` if it does not exist and
+    /// `Some(None)` if it has no expiry.
+    pub async fn ttl(&mut self, key: &str) -> crate::Result<Option<Option<Duration>>> {
+        match self.call(Pipeline::new().ttl(key)).await? {
+            Frame::Integer(-2) => Ok(None),
+            Frame::Integer(-1) => Ok(Some(None)),
+            Frame::Integer(millis) if millis >= 0 => {
+                Ok(Some(Some(Duration::from_millis(millis as u64))))
+            }
+            reply => Err(reply.to_error()),
+        }
+    }
+
+    /// Returns whether the key had an expiry.
+    pub async fn persist(&mut self, key: &str) -> crate::Result<bool> {
+        flag(self.call(Pipeline::new().persist(key)).await?)
+    }
+
+    /// Returns the length of the list.
+    pub async fn lpush<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().lpush(key, values)).await?)
+    }
+
+    /// Returns the length of the list.
+    pub async fn rpush<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().rpush(key, values)).await?)
+    }
+
+    pub async fn lpop(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bytes(self.call(Pipeline::new().lpop(key)).await?)
+    }
+
+    pub async fn rpop(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bytes(self.call(Pipeline::new().rpop(key)).await?)
+    }
+
+    /// The items `start..=stop`, negative indexes count from the end.
+    pub async fn lrange(&mut self, key: &str, start: i64, stop: i64) -> crate::Result<Vec<Bytes>> {
+        bytes_array(self.call(Pipeline::new().lrange(key, start, stop)).await?)
+    }
+
+    /// Returns how many fields are new.
+    pub async fn hset<F: Into<Bytes>, V: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        fields: impl IntoIterator<Item = (F, V)>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().hset(key, fields)).await?)
+    }
+
+    pub async fn hget(
+        &mut self,
+        key: &str,
+        field: impl Into<Bytes>,
+    ) -> crate::Result<Option<Bytes>> {
+        optional_bytes(self.call(Pipeline::new().hget(key, field)).await?)
+    }
+
+    /// Returns how many of the fields existed.
+    pub async fn hdel<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        fields: impl IntoIterator<Item = T>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().hdel(key, fields)).await?)
+    }
+
+    /// The fields and their values, in no particular order.
+    pub async fn hgetall(&mut self, key: &str) -> crate::Result<Vec<(Bytes, Bytes)>> {
+        let parts = bytes_array(self.call(Pipeline::new().hgetall(key)).await?)?;
+        pairs(parts, Ok)
+    }
+
+    /// Returns how many members are new.
+    pub async fn sadd<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = T>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().sadd(key, members)).await?)
+    }
+
+    /// Returns how many of the members existed.
+    pub async fn srem<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = T>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().srem(key, members)).await?)
+    }
+
+    /// The members in no particular order.
+    pub async fn smembers(&mut self, key: &str) -> crate::Result<Vec<Bytes>> {
+        bytes_array(self.call(Pipeline::new().smembers(key)).await?)
+    }
+
+    /// Adds members or updates their scores. Returns how many are new.
+    pub async fn zadd<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = (f64, T)>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().zadd(key, members)).await?)
+    }
+
+    /// The members `start..=stop` ordered by score.
+    pub async fn zrange(&mut self, key: &str, start: i64, stop: i64) -> crate::Result<Vec<Bytes>> {
+        bytes_array(self.call(Pipeline::new().zrange(key, start, stop)).await?)
+    }
+
+    /// The members `start..=stop` ordered by score, with their scores.
+    pub async fn zrange_withscores(
+        &mut self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> crate::Result<Vec<(Bytes, f64)>> {
+        let reply = self
+            .call(Pipeline::new().zrange_withscores(key, start, stop))
+            .await?;
+        pairs(bytes_array(reply)?, float)
+    }
+
+    pub async fn zscore(
+        &mut self,
+        key: &str,
+        member: impl Into<Bytes>,
+    ) -> crate::Result<Option<f64>> {
+        match optional_bytes(self.call(Pipeline::new().zscore(key, member)).await?)? {
+            Some(score) => float(score).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns how many subscribers received the message.
+    pub async fn publish(
+        &mut self,
+        channel: &str,
+        message: impl Into<Bytes>,
+    ) -> crate::Result<usize> {
+        count(self.call(Pipeline::new().publish(channel, message)).await?)
+    }
+
+    /// Returns `message`, or `PONG` without one.
+    pub async fn ping(&mut self, message: Option<Bytes>) -> crate::Result<Bytes> {
+        match self.call(Pipeline::new().ping(message)).await? {
+            Frame::Simple(text) => Ok(Bytes::from(text)),
+            Frame::Bulk(data) => Ok(data),
+            reply => Err(reply.to_error()),
+        }
+    }
+
+    /// Writes a snapshot and returns once it is on disk.
+    pub async fn save(&mut self) -> crate::Result<()> {
+        ok(self.call(Pipeline::new().save()).await?)
+    }
+
+    /// Starts writing a snapshot in the background.
+    pub async fn bgsave(&mut self) -> crate::Result<()> {
+        ok(self.call(Pipeline::new().bgsave()).await?)
+    }
+
+    /// Starts rewriting the append-only file in the background.
+    pub async fn bgrewriteaof(&mut self) -> crate::Result<()> {
+        ok(self.call(Pipeline::new().bgrewriteaof()).await?)
+    }
+
+    /// Turns the connection into a subscriber of `channels`. Only pub/sub
+    /// commands can be sent on it from then on.
+    pub async fn subscribe(self, channels: &[&str]) -> crate::Result<Subscriber> {
+        Subscriber::new(self, channels).await
+    }
+
+    /// Runs the one command of `pipeline`, an error reply is an error.
+    async fn call(&mut self, pipeline: &Pipeline) -> crate::Result<Frame> {
+        match self.execute(pipeline).await?.pop() {
+            Some(Frame::Error(message)) => Err(ReplyError { message }.into()),
+            Some(reply) => Ok(reply),
+            None => Err("no command to run".into()),
+        }
+    }
+
+    /// Marks replies as outstanding, unless an earlier call left the
+    /// connection broken.
+    fn start(&mut self) -> crate::Result<()> {
+        self.usable()?;
+        self.busy = true;
+        Ok(())
+    }
+
+    fn usable(&self) -> crate::Result<()> {
+        if self.busy {
+            return Err("connection broken by an earlier error or cancelled call".into());
+        }
+        Ok(())
+    }
+
+    async fn read_frame(&mut self) -> crate::Result<Frame> {
+        match self.connection.read_frame().await? {
+            Some(frame) => Ok(frame),
+            None => Err("connection closed by the server".into()),
+        }
+    }
+}
+
+fn ok(reply: Frame) -> crate::Result<()> {
+    match reply {
+        Frame::Simple(_) => Ok(()),
+        reply => Err(reply.to_error()),
+    }
+}
+
+fn optional_bytes(reply: Frame) -> crate::Result<Option<Bytes>> {
+    match reply {
+        Frame::Bulk(data) => Ok(Some(data)),
+        Frame::Null => Ok(None),
+        reply => Err(reply.to_error()),
+    }
+}
+
+fn count(reply: Frame) -> crate::Result<usize> {
+    match reply {
+        Frame::Integer(value) if value >= 0 => Ok(value as usize),
+        reply => Err(reply.to_error()),
+    }
+}
+
+fn flag(reply: Frame) -> crate::Result<bool> {
+    match reply {
+        Frame::Integer(0) => Ok(false),
+        Frame::Integer(1) => Ok(true),
+        reply => Err(reply.to_error()),
+    }
+}
+
+fn bytes_array(reply: Frame) -> crate::Result<Vec<Bytes>> {
+    match reply {
+        Frame::Array(parts) => parts
+            .into_iter()
+            .map(|part| match part {
+                Frame::Bulk(data) => Ok(data),
+                part => Err(part.to_error()),
+            })
+            .collect(),
+        reply => Err(reply.to_error()),
+    }
+}
+
+/// Splits a flat array of names and values into pairs.
+fn pairs<T>(
+    parts: Vec<Bytes>,
+    value: impl Fn(Bytes) -> crate::Result<T>,
+) -> crate::Result<Vec<(Bytes, T)>> {
+    if !parts.len().is_multiple_of(2) {
+        return Err("odd number of items in a reply of pairs".into());
+    }
+    let mut parts = parts.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(name), Some(data)) = (parts.next(), parts.next()) {
+        pairs.push((name, value(data)?));
+    }
+    Ok(pairs)
+}
+
+fn float(data: Bytes) -> crate::Result<f64> {
+    std::str::from_utf8(&data)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| format!("invalid float {:?}", data).into())
+}
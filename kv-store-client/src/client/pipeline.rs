@@ -0,0 +1,225 @@
+use crate::Frame;
+use bytes::Bytes;
+use std::time::Duration;
+
+/// Commands sent together by `Client::execute`, which returns one reply
+/// per command in the same order.
+///
+/// The methods match those of `Client` and can be chained:
+///
+/// ```no_run
+/// # async fn example(client: &mut kv_store_client::client::Client) -> kv_store_client::Result<()> {
+/// use kv_store_client::client::Pipeline;
+///
+/// let replies = client
+///     .execute(Pipeline::new().set("a", "1").rpush("list", ["x", "y"]).get("a"))
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    commands: Vec<Frame>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub(super) fn commands(&self) -> &[Frame] {
+        &self.commands
+    }
+
+    /// Adds any command, e.g. `["SET", "key", "value"]`.
+    pub fn command<I, T>(&mut self, parts: I) -> &mut Pipeline
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        self.commands.push(Frame::bulk_array(parts));
+        self
+    }
+
+    pub fn get(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("GET", key, [])
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<Bytes>) -> &mut Pipeline {
+        self.keyed("SET", key, [value.into()])
+    }
+
+    pub fn set_expires(
+        &mut self,
+        key: &str,
+        value: impl Into<Bytes>,
+        expire: Duration,
+    ) -> &mut Pipeline {
+        self.keyed("SET", key, [value.into(), "PX".into(), millis(expire)])
+    }
+
+    pub fn del(&mut self, keys: &[&str]) -> &mut Pipeline {
+        let keys = keys
+            .iter()
+            .map(|key| Bytes::copy_from_slice(key.as_bytes()));
+        self.command(std::iter::once(Bytes::from("DEL")).chain(keys))
+    }
+
+    pub fn expire(&mut self, key: &str, expire: Duration) -> &mut Pipeline {
+        self.keyed("PEXPIRE", key, [millis(expire)])
+    }
+
+    /// `PTTL`, the reply is in milliseconds.
+    pub fn ttl(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("PTTL", key, [])
+    }
+
+    pub fn persist(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("PERSIST", key, [])
+    }
+
+    pub fn lpush<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> &mut Pipeline {
+        self.keyed("LPUSH", key, values.into_iter().map(Into::into))
+    }
+
+    pub fn rpush<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> &mut Pipeline {
+        self.keyed("RPUSH", key, values.into_iter().map(Into::into))
+    }
+
+    pub fn lpop(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("LPOP", key, [])
+    }
+
+    pub fn rpop(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("RPOP", key, [])
+    }
+
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> &mut Pipeline {
+        self.keyed("LRANGE", key, [number(start), number(stop)])
+    }
+
+    pub fn hset<F: Into<Bytes>, V: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        fields: impl IntoIterator<Item = (F, V)>,
+    ) -> &mut Pipeline {
+        let fields = fields
+            .into_iter()
+            .flat_map(|(field, value)| [field.into(), value.into()]);
+        self.keyed("HSET", key, fields)
+    }
+
+    pub fn hget(&mut self, key: &str, field: impl Into<Bytes>) -> &mut Pipeline {
+        self.keyed("HGET", key, [field.into()])
+    }
+
+    pub fn hdel<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        fields: impl IntoIterator<Item = T>,
+    ) -> &mut Pipeline {
+        self.keyed("HDEL", key, fields.into_iter().map(Into::into))
+    }
+
+    pub fn hgetall(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("HGETALL", key, [])
+    }
+
+    pub fn sadd<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = T>,
+    ) -> &mut Pipeline {
+        self.keyed("SADD", key, members.into_iter().map(Into::into))
+    }
+
+    pub fn srem<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = T>,
+    ) -> &mut Pipeline {
+        self.keyed("SREM", key, members.into_iter().map(Into::into))
+    }
+
+    pub fn smembers(&mut self, key: &str) -> &mut Pipeline {
+        self.keyed("SMEMBERS", key, [])
+    }
+
+    pub fn zadd<T: Into<Bytes>>(
+        &mut self,
+        key: &str,
+        members: impl IntoIterator<Item = (f64, T)>,
+    ) -> &mut Pipeline {
+        let members = members
+            .into_iter()
+            .flat_map(|(score, member)| [Bytes::from(score.to_string()), member.into()]);
+        self.keyed("ZADD", key, members)
+    }
+
+    pub fn zrange(&mut self, key: &str, start: i64, stop: i64) -> &mut Pipeline {
+        self.keyed("ZRANGE", key, [number(start), number(stop)])
+    }
+
+    pub fn zrange_withscores(&mut self, key: &str, start: i64, stop: i64) -> &mut Pipeline {
+        let arguments = [number(start), number(stop), "WITHSCORES".into()];
+        self.keyed("ZRANGE", key, arguments)
+    }
+
+    pub fn zscore(&mut self, key: &str, member: impl Into<Bytes>) -> &mut Pipeline {
+        self.keyed("ZSCORE", key, [member.into()])
+    }
+
+    pub fn publish(&mut self, channel: &str, message: impl Into<Bytes>) -> &mut Pipeline {
+        self.keyed("PUBLISH", channel, [message.into()])
+    }
+
+    pub fn ping(&mut self, message: Option<Bytes>) -> &mut Pipeline {
+        self.command(std::iter::once(Bytes::from("PING")).chain(message))
+    }
+
+    pub fn save(&mut self) -> &mut Pipeline {
+        self.command(["SAVE"])
+    }
+
+    pub fn bgsave(&mut self) -> &mut Pipeline {
+        self.command(["BGSAVE"])
+    }
+
+    pub fn bgrewriteaof(&mut self) -> &mut Pipeline {
+        self.command(["BGREWRITEAOF"])
+    }
+
+    fn keyed(
+        &mut self,
+        name: &'static str,
+        key: &str,
+        arguments: impl IntoIterator<Item = Bytes>,
+    ) -> &mut Pipeline {
+        let start = [Bytes::from(name), Bytes::copy_from_slice(key.as_bytes())];
+        self.command(start.into_iter().chain(arguments))
+    }
+}
+
+fn number(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+fn millis(duration: Duration) -> Bytes {
+    Bytes::from(duration.as_millis().to_string())
+}
@@ -0,0 +1,96 @@
+use super::Client;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Connections to one server shared between tasks.
+///
+/// `get` hands out an idle connection, or opens a new one while fewer than
+/// `max_size` are in use, and otherwise waits for one to be returned.
+/// Connections go back to the pool when their `PooledClient` is dropped,
+/// unless they are broken.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addr: String,
+    idle: Mutex<Vec<Client>>,
+    /// One per connection that may be in use.
+    permits: Arc<Semaphore>,
+}
+
+/// A connection taken from a `Pool`, usable as a `Client`.
+pub struct PooledClient {
+    /// Only `None` once dropped or detached.
+    client: Option<Client>,
+    shared: Arc<Shared>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    /// Panics if `max_size` is 0.
+    pub fn new(addr: impl Into<String>, max_size: usize) -> Pool {
+        assert!(max_size > 0, "a pool needs at least one connection");
+        Pool {
+            shared: Arc::new(Shared {
+                addr: addr.into(),
+                idle: Mutex::new(Vec::new()),
+                permits: Arc::new(Semaphore::new(max_size)),
+            }),
+        }
+    }
+
+    pub async fn get(&self) -> crate::Result<PooledClient> {
+        // the semaphore is never closed
+        let permit = self.shared.permits.clone().acquire_owned().await.unwrap();
+        let idle = self.shared.idle.lock().unwrap().pop();
+        let client = match idle {
+            Some(client) => client,
+            None => Client::connect(self.shared.addr.as_str()).await?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            shared: self.shared.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Connections opened and not in use.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.lock().unwrap().len()
+    }
+}
+
+impl PooledClient {
+    /// Takes the connection out of the pool, e.g. to subscribe with it. The
+    /// pool may open another one instead.
+    pub fn detach(mut self) -> Client {
+        self.client.take().unwrap()
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if !client.is_broken() {
+                self.shared.idle.lock().unwrap().push(client);
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use super::Client;
+use crate::Frame;
+use async_stream::try_stream;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use tokio_stream::Stream;
+
+/// A connection subscribed to pub/sub channels, see `Client::subscribe`.
+#[derive(Debug)]
+pub struct Subscriber {
+    client: Client,
+    channels: Vec<String>,
+    /// Messages that arrived while waiting for a subscription to be
+    /// confirmed.
+    received: VecDeque<Message>,
+}
+
+/// A message published on a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub channel: String,
+    pub content: Bytes,
+}
+
+impl Subscriber {
+    pub(super) async fn new(client: Client, channels: &[&str]) -> crate::Result<Subscriber> {
+        let mut subscriber = Subscriber {
+            client,
+            channels: Vec::new(),
+            received: VecDeque::new(),
+        };
+        subscriber.subscribe(channels).await?;
+        Ok(subscriber)
+    }
+
+    /// The channels subscribed to.
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    /// Waits for the next message. Returns `None` once the server closed
+    /// the connection or no channel is subscribed to any more.
+    pub async fn next_message(&mut self) -> crate::Result<Option<Message>> {
+        if let Some(message) = self.received.pop_front() {
+            return Ok(Some(message));
+        }
+        if self.channels.is_empty() {
+            return Ok(None);
+        }
+        // unlike a reply, a message may be waited for and given up on, a
+        // partly received frame stays buffered
+        self.client.usable()?;
+        let frame = match self.client.connection.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(None),
+            Err(error) => {
+                self.client.busy = true;
+                return Err(error);
+            }
+        };
+        message(frame).map(Some).map_err(|frame| frame.to_error())
+    }
+
+    /// The messages until `next_message` returns `None`.
+    pub fn into_stream(mut self) -> impl Stream<Item = crate::Result<Message>> {
+        try_stream! {
+            while let Some(message) = self.next_message().await? {
+                yield message;
+            }
+        }
+    }
+
+    pub async fn subscribe(&mut self, channels: &[&str]) -> crate::Result<()> {
+        if channels.is_empty() {
+            return Ok(());
+        }
+        self.send("SUBSCRIBE", channels).await?;
+        for channel in channels {
+            self.confirmation("subscribe", channel).await?;
+            if !self.channels.iter().any(|subscribed| subscribed == channel) {
+                self.channels.push(channel.to_string());
+            }
+        }
+        self.client.busy = false;
+        Ok(())
+    }
+
+    /// Unsubscribes from `channels`, or from all of them if it is empty.
+    pub async fn unsubscribe(&mut self, channels: &[&str]) -> crate::Result<()> {
+        let all: Vec<String>;
+        let channels = if channels.is_empty() {
+            all = self.channels.clone();
+            all.iter().map(String::as_str).collect()
+        } else {
+            channels.to_vec()
+        };
+        if channels.is_empty() {
+            return Ok(());
+        }
+        self.send("UNSUBSCRIBE", &channels).await?;
+        for channel in channels {
+            self.confirmation("unsubscribe", channel).await?;
+            self.channels.retain(|subscribed| subscribed != channel);
+        }
+        self.client.busy = false;
+        Ok(())
+    }
+
+    async fn send(&mut self, name: &'static str, channels: &[&str]) -> crate::Result<()> {
+        self.client.start()?;
+        let channels = channels
+            .iter()
+            .map(|channel| Bytes::copy_from_slice(channel.as_bytes()));
+        let command = Frame::bulk_array(std::iter::once(Bytes::from(name)).chain(channels));
+        self.client.connection.write_frame(&command).await?;
+        Ok(())
+    }
+
+    /// Waits for the reply `[kind, channel, count]`, keeping the messages
+    /// received in the meantime.
+    async fn confirmation(&mut self, kind: &str, channel: &str) -> crate::Result<()> {
+        loop {
+            let frame = match message(self.client.read_frame().await?) {
+                Ok(message) => {
+                    self.received.push_back(message);
+                    continue;
+                }
+                Err(frame) => frame,
+            };
+            if let Frame::Array(parts) = &frame {
+                if let [Frame::Bulk(reply_kind), Frame::Bulk(reply_channel), Frame::Integer(_)] =
+                    &parts[..]
+                {
+                    if reply_kind == kind.as_bytes() && reply_channel == channel.as_bytes() {
+                        return Ok(());
+                    }
+                }
+            }
+            return Err(frame.to_error());
+        }
+    }
+}
+
+/// Converts a `[message, channel, content]` frame, returns anything else.
+fn message(frame: Frame) -> Result<Message, Frame> {
+    if let Frame::Array(parts) = &frame {
+        if let [Frame::Bulk(kind), Frame::Bulk(channel), Frame::Bulk(content)] = &parts[..] {
+            if let (true, Ok(channel)) = (kind == "message", std::str::from_utf8(channel)) {
+                return Ok(Message {
+                    channel: channel.to_string(),
+                    content: content.clone(),
+                });
+            }
+        }
+    }
+    Err(frame)
+}
@@ -0,0 +1,67 @@
+use crate::frame::{self, Frame};
+use bytes::{Buf, BytesMut};
+use std::io::Cursor;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Reads and writes frames on a stream, usually a `TcpStream`.
+#[derive(Debug)]
+pub struct Connection<S = TcpStream> {
+    stream: S,
+    buffer: BytesMut,
+    output: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(4 * 1024),
+            output: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// Returns `None` once the peer closed the connection between frames.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        let mut buf = Cursor::new(&self.buffer[..]);
+        match Frame::parse(&mut buf) {
+            Ok(frame) => {
+                let length = buf.position() as usize;
+                self.buffer.advance(length);
+                Ok(Some(frame))
+            }
+            Err(frame::Error::Incomplete) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+        self.write_frames(std::slice::from_ref(frame)).await
+    }
+
+    /// Writes the frames with as few writes as possible, for pipelining.
+    pub async fn write_frames(&mut self, frames: &[Frame]) -> std::io::Result<()> {
+        self.output.clear();
+        for frame in frames {
+            frame.encode(&mut self.output);
+        }
+        self.stream.write_all(&self.output).await?;
+        self.stream.flush().await
+    }
+}
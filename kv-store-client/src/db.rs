@@ -0,0 +1,875 @@
+use crate::value::{index_range, SortedSet, Value, WrongType};
+use crate::Frame;
+use bytes::Bytes;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+
+/// Messages a subscriber may fall behind by before it starts missing some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Shards used by `Db::new`.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Receives every write applied to a `Db` as the commands that redo it.
+///
+/// Deadlines are absolute (`SET key value PXAT ms`, `PEXPIREAT key ms`), so
+/// the commands mean the same when replayed by another process.
+pub trait Journal: Send + Sync {
+    /// Called while `shard` is locked, so the writes to a key are recorded
+    /// in the order they are applied, and never at the same time as
+    /// `Db::iter_shard_with` copies the shard. It should not block for
+    /// long.
+    fn record(&self, shard: usize, command: Frame);
+}
+
+/// State shared by all connections: the key-value entries and the pub/sub
+/// channels.
+///
+/// Entries are split into shards by key hash, each behind its own lock, so
+/// commands on different keys rarely wait for each other.
+///
+/// Expired keys are never returned. They are removed when read and by a
+/// background task that sleeps until the next key is due.
+#[derive(Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<Bytes>>>>,
+}
+
+struct Shared {
+    shards: Vec<Mutex<State>>,
+    hasher: RandomState,
+    /// Writes since the database was created, see `Db::changes`.
+    changes: AtomicU64,
+    journal: OnceLock<Arc<dyn Journal>>,
+    /// Wakes the purge task when a key gets an earlier expiry than the one
+    /// it is sleeping until.
+    purge_task: Notify,
+}
+
+struct State {
+    /// Position of the shard in `Shared::shards`.
+    index: usize,
+    entries: HashMap<String, Entry>,
+    /// Keys with an expiry ordered by it, so the purge task only ever
+    /// looks at keys that are due.
+    expirations: BTreeSet<(Instant, String)>,
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+/// The end of a list `push` and `pop` work on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl State {
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.iter().next().map(|(when, _)| *when)
+    }
+
+    /// The entry for `key` unless it has expired, in which case it is
+    /// removed.
+    fn live_entry(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+        let expired = match self.entries.get(key) {
+            None => return None,
+            Some(entry) => entry.expires_at.is_some_and(|when| when <= now),
+        };
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        Some(entry)
+    }
+
+    /// Replaces the expiry of an existing key. Returns whether the purge
+    /// task may have to wake up earlier than planned.
+    fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let next = self.next_expiration();
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if let Some(when) = std::mem::replace(&mut entry.expires_at, expires_at) {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        match expires_at {
+            Some(when) => {
+                self.expirations.insert((when, key.to_string()));
+                next.is_none_or(|next| when < next)
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the keys that are due and returns when the next one is.
+    fn purge_expired(&mut self, now: Instant) -> Option<Instant> {
+        while let Some((when, key)) = self.expirations.iter().next().cloned() {
+            if when > now {
+                return Some(when);
+            }
+            self.expirations.remove(&(when, key.clone()));
+            self.entries.remove(&key);
+        }
+        None
+    }
+}
+
+impl Db {
+    /// Creates an empty database with `DEFAULT_SHARDS` shards and spawns its
+    /// purge task, which runs for as long as the runtime does.
+    pub fn new() -> Db {
+        Db::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Like `new`, with `shards` locks. A single shard behaves like one
+    /// global lock around all the keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> Db {
+        assert!(shards > 0, "a database needs at least one shard");
+        let shards = (0..shards)
+            .map(|index| {
+                Mutex::new(State {
+                    index,
+                    entries: HashMap::new(),
+                    expirations: BTreeSet::new(),
+                })
+            })
+            .collect();
+        let shared = Arc::new(Shared {
+            shards,
+            hasher: RandomState::new(),
+            changes: AtomicU64::new(0),
+            journal: OnceLock::new(),
+            purge_task: Notify::new(),
+        });
+        tokio::spawn(purge_expired_keys(shared.clone()));
+        Db {
+            shared,
+            channels: Arc::default(),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// Counts the writes made through the commands, so persistence can tell
+    /// whether anything changed since it last ran. Keys expiring are not
+    /// counted.
+    pub fn changes(&self) -> u64 {
+        self.shared.changes.load(Ordering::Relaxed)
+    }
+
+    /// Records every write from now on in `journal`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database already has a journal.
+    pub fn set_journal(&self, journal: Arc<dyn Journal>) {
+        if self.shared.journal.set(journal).is_err() {
+            panic!("the database already has a journal");
+        }
+    }
+
+    /// Counts a write and passes it to the journal. Called with the shard
+    /// still locked.
+    fn changed(&self, shard: usize, commands: impl FnOnce() -> Vec<Frame>) {
+        self.shared.changes.fetch_add(1, Ordering::Relaxed);
+        if let Some(journal) = self.shared.journal.get() {
+            for command in commands() {
+                journal.record(shard, command);
+            }
+        }
+    }
+
+    /// Locks the shard `key` belongs to.
+    fn shard(&self, key: &str) -> MutexGuard<'_, State> {
+        let index = self.shared.hasher.hash_one(key) as usize % self.shared.shards.len();
+        self.shared.shards[index].lock().unwrap()
+    }
+
+    /// Runs `f` on the value of `key`. `None` if the key does not exist.
+    fn read<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&Value) -> Result<T, WrongType>,
+    ) -> Result<Option<T>, WrongType> {
+        let mut state = self.shard(key);
+        state
+            .live_entry(key, Instant::now())
+            .map(|entry| f(&entry.value))
+            .transpose()
+    }
+
+    /// Runs `f` on the value of `key`, first stored by `create` if the key
+    /// does not exist. `None` if it does not and `create` returns `None`.
+    ///
+    /// `f` returns its result and whether it changed the value, in which
+    /// case `command` is passed to the journal. A collection left empty is
+    /// removed.
+    fn update<T>(
+        &self,
+        key: &str,
+        create: impl FnOnce() -> Option<Value>,
+        command: impl FnOnce() -> Frame,
+        f: impl FnOnce(&mut Value) -> Result<(T, bool), WrongType>,
+    ) -> Result<Option<T>, WrongType> {
+        let mut state = self.shard(key);
+        let entry = match state.live_entry(key, Instant::now()) {
+            Some(entry) => entry,
+            None => {
+                let value = match create() {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                let entry = Entry {
+                    value,
+                    expires_at: None,
+                };
+                state.entries.entry(key.to_string()).or_insert(entry)
+            }
+        };
+        let (result, changed) = f(&mut entry.value)?;
+        if entry.value.is_empty() {
+            state.remove(key);
+        }
+        if changed {
+            self.changed(state.index, || vec![command()]);
+        }
+        Ok(Some(result))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>, WrongType> {
+        self.read(key, |value| match value {
+            Value::String(data) => Ok(data.clone()),
+            _ => Err(WrongType),
+        })
+    }
+
+    /// Stores the value, replacing any previous value and expiry. With
+    /// `expires_at` the key is gone from then on.
+    pub fn set(&self, key: String, value: Bytes, expires_at: Option<Instant>) {
+        self.set_value(key, Value::String(value), expires_at)
+    }
+
+    /// Like `set`, with a value of any type.
+    pub fn set_value(&self, key: String, value: Value, expires_at: Option<Instant>) {
+        let mut state = self.shard(&key);
+        state.remove(&key);
+        state.entries.insert(
+            key.clone(),
+            Entry {
+                value,
+                expires_at: None,
+            },
+        );
+        let notify = state.set_expiry(&key, expires_at);
+        self.changed(state.index, || {
+            let value = &state.entries[&key].value;
+            let mut commands = restore_commands(&key, value, expires_at);
+            if !matches!(value, Value::String(_)) {
+                commands.insert(0, command("DEL", &key, []));
+            }
+            commands
+        });
+        drop(state);
+
+        if notify {
+            self.shared.purge_task.notify_one();
+        }
+    }
+
+    /// Removes the key. Returns false if it does not exist.
+    pub fn del(&self, key: &str) -> bool {
+        let mut state = self.shard(key);
+        if state.live_entry(key, Instant::now()).is_none() {
+            return false;
+        }
+        state.remove(key);
+        self.changed(state.index, || vec![command("DEL", key, [])]);
+        true
+    }
+
+    /// Makes the key expire at `when`, right away if that has passed.
+    /// Returns false if it does not exist.
+    pub fn expire(&self, key: &str, when: Instant) -> bool {
+        let now = Instant::now();
+        let mut state = self.shard(key);
+        if state.live_entry(key, now).is_none() {
+            return false;
+        }
+        let notify = if when <= now {
+            state.remove(key);
+            self.changed(state.index, || vec![command("DEL", key, [])]);
+            false
+        } else {
+            let notify = state.set_expiry(key, Some(when));
+            self.changed(state.index, || vec![expire_command(key, when)]);
+            notify
+        };
+        drop(state);
+
+        if notify {
+            self.shared.purge_task.notify_one();
+        }
+        true
+    }
+
+    /// Removes the expiry of the key. Returns false if it does not exist or
+    /// has no expiry.
+    pub fn persist(&self, key: &str) -> bool {
+        let mut state = self.shard(key);
+        match state.live_entry(key, Instant::now()) {
+            Some(entry) if entry.expires_at.is_some() => {
+                state.set_expiry(key, None);
+                self.changed(state.index, || vec![command("PERSIST", key, [])]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `None` if the key does not exist, `Some(None)` if it never expires.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let now = Instant::now();
+        let mut state = self.shard(key);
+        state
+            .live_entry(key, now)
+            .map(|entry| entry.expires_at.map(|when| when - now))
+    }
+
+    /// Adds the values one after the other at `end` of the list and returns
+    /// its length.
+    pub fn push(&self, key: &str, values: Vec<Bytes>, end: ListEnd) -> Result<usize, WrongType> {
+        let name = match end {
+            ListEnd::Left => "LPUSH",
+            ListEnd::Right => "RPUSH",
+        };
+        let length = self.update(
+            key,
+            || Some(Value::List(VecDeque::new())),
+            || command(name, key, values.iter().cloned()),
+            |value| {
+                let list = match value {
+                    Value::List(list) => list,
+                    _ => return Err(WrongType),
+                };
+                for value in values.iter().cloned() {
+                    match end {
+                        ListEnd::Left => list.push_front(value),
+                        ListEnd::Right => list.push_back(value),
+                    }
+                }
+                Ok((list.len(), true))
+            },
+        )?;
+        Ok(length.unwrap_or_default())
+    }
+
+    pub fn pop(&self, key: &str, end: ListEnd) -> Result<Option<Bytes>, WrongType> {
+        let name = match end {
+            ListEnd::Left => "LPOP",
+            ListEnd::Right => "RPOP",
+        };
+        let popped = self.update(
+            key,
+            || None,
+            || command(name, key, []),
+            |value| {
+                let list = match value {
+                    Value::List(list) => list,
+                    _ => return Err(WrongType),
+                };
+                let popped = match end {
+                    ListEnd::Left => list.pop_front(),
+                    ListEnd::Right => list.pop_back(),
+                };
+                let changed = popped.is_some();
+                Ok((popped, changed))
+            },
+        )?;
+        Ok(popped.flatten())
+    }
+
+    /// The items from `start` to `stop` included, see `index_range`.
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Bytes>, WrongType> {
+        let items = self.read(key, |value| match value {
+            Value::List(list) => Ok(list
+                .range(index_range(list.len(), start, stop))
+                .cloned()
+                .collect()),
+            _ => Err(WrongType),
+        })?;
+        Ok(items.unwrap_or_default())
+    }
+
+    /// Sets the fields of the hash and returns how many are new.
+    pub fn hset(&self, key: &str, fields: Vec<(Bytes, Bytes)>) -> Result<usize, WrongType> {
+        let added = self.update(
+            key,
+            || Some(Value::Hash(HashMap::new())),
+            || {
+                let arguments = fields.iter().flat_map(|(field, value)| [field, value]);
+                command("HSET", key, arguments.cloned())
+            },
+            |value| {
+                let hash = match value {
+                    Value::Hash(hash) => hash,
+                    _ => return Err(WrongType),
+                };
+                let added = fields
+                    .iter()
+                    .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                    .count();
+                Ok((added, true))
+            },
+        )?;
+        Ok(added.unwrap_or_default())
+    }
+
+    pub fn hget(&self, key: &str, field: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        let value = self.read(key, |value| match value {
+            Value::Hash(hash) => Ok(hash.get(field).cloned()),
+            _ => Err(WrongType),
+        })?;
+        Ok(value.flatten())
+    }
+
+    /// Removes the fields from the hash and returns how many existed.
+    pub fn hdel(&self, key: &str, fields: Vec<Bytes>) -> Result<usize, WrongType> {
+        let removed = self.update(
+            key,
+            || None,
+            || command("HDEL", key, fields.iter().cloned()),
+            |value| {
+                let hash = match value {
+                    Value::Hash(hash) => hash,
+                    _ => return Err(WrongType),
+                };
+                let removed = fields
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count();
+                Ok((removed, removed > 0))
+            },
+        )?;
+        Ok(removed.unwrap_or_default())
+    }
+
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(Bytes, Bytes)>, WrongType> {
+        let fields = self.read(key, |value| match value {
+            Value::Hash(hash) => Ok(hash
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()),
+            _ => Err(WrongType),
+        })?;
+        Ok(fields.unwrap_or_default())
+    }
+
+    /// Adds the members to the set and returns how many are new.
+    pub fn sadd(&self, key: &str, members: Vec<Bytes>) -> Result<usize, WrongType> {
+        let added = self.update(
+            key,
+            || Some(Value::Set(HashSet::new())),
+            || command("SADD", key, members.iter().cloned()),
+            |value| {
+                let set = match value {
+                    Value::Set(set) => set,
+                    _ => return Err(WrongType),
+                };
+                let added = members
+                    .iter()
+                    .filter(|member| set.insert((*member).clone()))
+                    .count();
+                Ok((added, added > 0))
+            },
+        )?;
+        Ok(added.unwrap_or_default())
+    }
+
+    /// Removes the members from the set and returns how many existed.
+    pub fn srem(&self, key: &str, members: Vec<Bytes>) -> Result<usize, WrongType> {
+        let removed = self.update(
+            key,
+            || None,
+            || command("SREM", key, members.iter().cloned()),
+            |value| {
+                let set = match value {
+                    Value::Set(set) => set,
+                    _ => return Err(WrongType),
+                };
+                let removed = members.iter().filter(|member| set.remove(*member)).count();
+                Ok((removed, removed > 0))
+            },
+        )?;
+        Ok(removed.unwrap_or_default())
+    }
+
+    pub fn smembers(&self, key: &str) -> Result<Vec<Bytes>, WrongType> {
+        let members = self.read(key, |value| match value {
+            Value::Set(set) => Ok(set.iter().cloned().collect()),
+            _ => Err(WrongType),
+        })?;
+        Ok(members.unwrap_or_default())
+    }
+
+    /// Adds the members to the sorted set or updates their scores, and
+    /// returns how many are new.
+    pub fn zadd(&self, key: &str, members: Vec<(f64, Bytes)>) -> Result<usize, WrongType> {
+        let added = self.update(
+            key,
+            || Some(Value::SortedSet(SortedSet::default())),
+            || {
+                let arguments = members
+                    .iter()
+                    .flat_map(|(score, member)| [Bytes::from(score.to_string()), member.clone()]);
+                command("ZADD", key, arguments)
+            },
+            |value| {
+                let set = match value {
+                    Value::SortedSet(set) => set,
+                    _ => return Err(WrongType),
+                };
+                let added = members
+                    .iter()
+                    .filter(|(score, member)| set.insert(member.clone(), *score))
+                    .count();
+                Ok((added, true))
+            },
+        )?;
+        Ok(added.unwrap_or_default())
+    }
+
+    /// The members from rank `start` to `stop` included with their scores,
+    /// see `index_range`.
+    pub fn zrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(Bytes, f64)>, WrongType> {
+        let members = self.read(key, |value| match value {
+            Value::SortedSet(set) => {
+                let range = index_range(set.len(), start, stop);
+                Ok(set
+                    .iter()
+                    .skip(range.start)
+                    .take(range.len())
+                    .map(|(member, score)| (member.clone(), score))
+                    .collect())
+            }
+            _ => Err(WrongType),
+        })?;
+        Ok(members.unwrap_or_default())
+    }
+
+    pub fn zscore(&self, key: &str, member: &[u8]) -> Result<Option<f64>, WrongType> {
+        let score = self.read(key, |value| match value {
+            Value::SortedSet(set) => Ok(set.score(member)),
+            _ => Err(WrongType),
+        })?;
+        Ok(score.flatten())
+    }
+
+    /// The live keys of shard `index` with their values and expiry.
+    ///
+    /// The shard is copied while locked, so the other shards stay usable
+    /// while the caller walks through it. Strings and list items are
+    /// reference counted and not copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not below `shard_count()`.
+    pub fn iter_shard(&self, index: usize) -> vec::IntoIter<(String, Value, Option<Instant>)> {
+        self.iter_shard_with(index, || {})
+    }
+
+    /// Like `iter_shard`, calling `copied` before the shard is unlocked, so a
+    /// journal can tell the writes the copy has from those it misses.
+    pub fn iter_shard_with(
+        &self,
+        index: usize,
+        copied: impl FnOnce(),
+    ) -> vec::IntoIter<(String, Value, Option<Instant>)> {
+        let now = Instant::now();
+        let state = self.shared.shards[index].lock().unwrap();
+        let entries: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|when| when > now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+            .collect();
+        copied();
+        entries.into_iter()
+    }
+
+    /// Number of stored keys, including expired ones not purged yet.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shared
+            .shards
+            .iter()
+            .map(|state| state.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    /// Returns how many subscribers received the message.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .and_then(|sender| sender.send(message).ok())
+            .unwrap_or(0)
+    }
+
+    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Forgets the channel once its last subscriber is gone.
+    pub fn unsubscribed(&self, channel: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(channel) {
+            if sender.receiver_count() == 0 {
+                channels.remove(channel);
+            }
+        }
+    }
+}
+
+impl Default for Db {
+    fn default() -> Db {
+        Db::new()
+    }
+}
+
+/// The commands that create a key as it is, when it does not exist yet.
+/// Strings get a `SET` with `PXAT`, collections a command adding all their
+/// items and a `PEXPIREAT`.
+pub fn restore_commands(key: &str, value: &Value, expires_at: Option<Instant>) -> Vec<Frame> {
+    let restore = match value {
+        Value::String(data) => {
+            let mut arguments = vec![data.clone()];
+            if let Some(when) = expires_at {
+                arguments.push(Bytes::from_static(b"PXAT"));
+                arguments.push(Bytes::from(unix_millis(when).to_string()));
+            }
+            return vec![command("SET", key, arguments)];
+        }
+        Value::List(list) => command("RPUSH", key, list.iter().cloned()),
+        Value::Hash(hash) => command(
+            "HSET",
+            key,
+            hash.iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()]),
+        ),
+        Value::Set(set) => command("SADD", key, set.iter().cloned()),
+        Value::SortedSet(set) => command(
+            "ZADD",
+            key,
+            set.iter()
+                .flat_map(|(member, score)| [Bytes::from(score.to_string()), member.clone()]),
+        ),
+    };
+    match expires_at {
+        Some(when) => vec![restore, expire_command(key, when)],
+        None => vec![restore],
+    }
+}
+
+fn command(name: &'static str, key: &str, arguments: impl IntoIterator<Item = Bytes>) -> Frame {
+    let mut parts = vec![
+        Frame::Bulk(Bytes::from_static(name.as_bytes())),
+        Frame::Bulk(Bytes::copy_from_slice(key.as_bytes())),
+    ];
+    parts.extend(arguments.into_iter().map(Frame::Bulk));
+    Frame::Array(parts)
+}
+
+fn expire_command(key: &str, when: Instant) -> Frame {
+    command(
+        "PEXPIREAT",
+        key,
+        [Bytes::from(unix_millis(when).to_string())],
+    )
+}
+
+/// Converts a deadline to a Unix time in milliseconds, which unlike an
+/// `Instant` still means something to the next process.
+pub fn unix_millis(when: Instant) -> u64 {
+    let when = SystemTime::now() + when.saturating_duration_since(Instant::now());
+    when.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Purges every shard, one lock at a time, then sleeps until the earliest
+/// key due in any of them.
+async fn purge_expired_keys(shared: Arc<Shared>) {
+    loop {
+        let now = Instant::now();
+        let next = shared
+            .shards
+            .iter()
+            .filter_map(|state| state.lock().unwrap().purge_expired(now))
+            .min();
+        match next {
+            Some(when) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(when) => {}
+                    _ = shared.purge_task.notified() => {}
+                }
+            }
+            None => shared.purge_task.notified().await,
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn keys_expire() {
+    let db = Db::new();
+    db.set(
+        "a".to_string(),
+        Bytes::from("1"),
+        Some(Instant::now() + Duration::from_secs(10)),
+    );
+    db.set("b".to_string(), Bytes::from("2"), None);
+    assert_eq!(db.ttl("a"), Some(Some(Duration::from_secs(10))));
+    assert_eq!(db.ttl("b"), Some(None));
+    assert_eq!(db.ttl("c"), None);
+
+    tokio::time::advance(Duration::from_secs(9)).await;
+    assert_eq!(db.get("a"), Ok(Some(Bytes::from("1"))));
+    assert!(db.expire("b", Instant::now() + Duration::from_secs(5)));
+    assert!(!db.expire("c", Instant::now() + Duration::from_secs(5)));
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(db.get("a"), Ok(None));
+    assert_eq!(db.ttl("a"), None);
+    assert_eq!(db.ttl("b"), Some(Some(Duration::from_secs(4))));
+
+    // setting a key again or persisting it drops the expiry
+    assert!(db.persist("b"));
+    assert!(!db.persist("b"));
+    db.set(
+        "a".to_string(),
+        Bytes::from("3"),
+        Some(Instant::now() + Duration::from_secs(1)),
+    );
+    db.set("a".to_string(), Bytes::from("4"), None);
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert_eq!(db.get("a"), Ok(Some(Bytes::from("4"))));
+    assert_eq!(db.get("b"), Ok(Some(Bytes::from("2"))));
+
+    assert!(db.expire("a", Instant::now()));
+    assert_eq!(db.get("a"), Ok(None));
+}
+
+#[tokio::test(start_paused = true)]
+async fn expired_keys_are_purged_in_the_background() {
+    let db = Db::new();
+    for i in 0..100 {
+        let expire = Duration::from_secs(if i % 2 == 0 { 10 } else { 20 });
+        db.set(
+            format!("key{}", i),
+            Bytes::from("value"),
+            Some(Instant::now() + expire),
+        );
+    }
+    db.set("kept".to_string(), Bytes::from("value"), None);
+    assert_eq!(db.len(), 101);
+
+    // nobody reads the keys, the purge task removes them
+    tokio::time::sleep(Duration::from_secs(11)).await;
+    assert_eq!(db.len(), 51);
+
+    // an earlier expiry wakes the purge task up before the next due key
+    db.set(
+        "soon".to_string(),
+        Bytes::from("value"),
+        Some(Instant::now() + Duration::from_secs(1)),
+    );
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(db.len(), 51);
+
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(db.len(), 1);
+}
+
+#[tokio::test]
+async fn keys_are_spread_over_shards() {
+    let db = Db::with_shards(4);
+    for i in 0..100 {
+        db.set(format!("key{}", i), Bytes::from(i.to_string()), None);
+    }
+    assert!(db.del("key7"));
+    assert!(!db.del("key7"));
+    assert_eq!(db.get("key7"), Ok(None));
+
+    let mut keys = Vec::new();
+    for index in 0..db.shard_count() {
+        let shard: Vec<_> = db.iter_shard(index).collect();
+        assert!(!shard.is_empty());
+        for (key, value, expires_at) in shard {
+            assert_eq!(db.get(&key).unwrap().map(Value::String), Some(value));
+            assert_eq!(expires_at, None);
+            keys.push(key);
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    assert_eq!(keys.len(), 99);
+}
+
+#[tokio::test]
+async fn collections_hold_their_own_type() {
+    let db = Db::new();
+    let items =
+        |items: &[&'static str]| -> Vec<Bytes> { items.iter().copied().map(Bytes::from).collect() };
+    assert_eq!(db.push("list", items(&["b", "a"]), ListEnd::Left), Ok(2));
+    assert_eq!(db.push("list", items(&["c"]), ListEnd::Right), Ok(3));
+    assert_eq!(db.lrange("list", 0, -1), Ok(items(&["a", "b", "c"])));
+    assert_eq!(db.pop("list", ListEnd::Right), Ok(Some(Bytes::from("c"))));
+
+    assert_eq!(db.sadd("set", items(&["x", "y", "x"])), Ok(2));
+    assert_eq!(db.srem("set", items(&["x", "z"])), Ok(1));
+    assert_eq!(db.smembers("set"), Ok(items(&["y"])));
+
+    // every command on a key of another type fails and changes nothing
+    assert_eq!(db.get("list"), Err(WrongType));
+    assert_eq!(db.sadd("list", items(&["x"])), Err(WrongType));
+    assert_eq!(db.hget("set", b"x"), Err(WrongType));
+    assert_eq!(db.zscore("list", b"a"), Err(WrongType));
+    assert_eq!(db.lrange("list", 0, -1), Ok(items(&["a", "b"])));
+
+    // empty collections are removed
+    assert_eq!(db.srem("set", items(&["y"])), Ok(1));
+    assert_eq!(db.ttl("set"), None);
+    assert_eq!(db.sadd("set", items(&["x"])), Ok(1));
+}
@@ -0,0 +1,276 @@
+//! Frames of the Redis protocol (RESP2).
+//!
+//! Unlike `mini_redis::Frame`, integers are signed, replies such as `TTL`
+//! use negative values.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::io::Cursor;
+
+/// Bulk strings longer than this are rejected, the same limit Redis uses.
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+/// Arrays nested deeper than this are rejected, parsing recurses for every
+/// level. Commands are a flat array and replies only nest a few levels.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a frame yet.
+    Incomplete,
+    /// Invalid frame encoding.
+    Other(crate::Error),
+}
+
+impl Frame {
+    /// A command or reply made of bulk strings, e.g. `["SET", "key", "value"]`.
+    pub fn bulk_array<I, T>(parts: I) -> Frame
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        Frame::Array(
+            parts
+                .into_iter()
+                .map(|part| Frame::Bulk(part.into()))
+                .collect(),
+        )
+    }
+
+    /// Parses one frame from the start of `src`, leaving the cursor after it.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        Frame::parse_nested(src, 0)
+    }
+
+    /// Parses a frame inside `depth` arrays.
+    fn parse_nested(src: &mut Cursor<&[u8]>, depth: usize) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => Ok(Frame::Simple(get_string(src)?)),
+            b'-' => Ok(Frame::Error(get_string(src)?)),
+            b':' => Ok(Frame::Integer(get_integer(src)?)),
+            b'$' => match get_integer(src)? {
+                -1 => Ok(Frame::Null),
+                length => {
+                    let length = to_length(length)?;
+                    if length > MAX_BULK_LENGTH {
+                        return Err("protocol error; bulk string is too long".into());
+                    }
+                    if src.remaining() < length + 2 {
+                        return Err(Error::Incomplete);
+                    }
+                    let data = Bytes::copy_from_slice(&src.chunk()[..length]);
+                    src.advance(length);
+                    if get_u8(src)? != b'\r' || get_u8(src)? != b'\n' {
+                        return Err("protocol error; bulk string is not terminated".into());
+                    }
+                    Ok(Frame::Bulk(data))
+                }
+            },
+            b'*' => match get_integer(src)? {
+                -1 => Ok(Frame::Null),
+                length => {
+                    let length = to_length(length)?;
+                    if length > 0 && depth == MAX_DEPTH {
+                        return Err("protocol error; arrays are nested too deeply".into());
+                    }
+                    // the length is untrusted, it only bounds the loop
+                    let mut parts = Vec::with_capacity(length.min(1024));
+                    for _ in 0..length {
+                        parts.push(Frame::parse_nested(src, depth + 1)?);
+                    }
+                    Ok(Frame::Array(parts))
+                }
+            },
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+        }
+    }
+
+    /// Appends the wire encoding of the frame to `dst`.
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Simple(text) => {
+                dst.put_u8(b'+');
+                dst.put_slice(text.as_bytes());
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Error(message) => {
+                dst.put_u8(b'-');
+                dst.put_slice(message.as_bytes());
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Integer(value) => {
+                dst.put_slice(format!(":{}\r\n", value).as_bytes());
+            }
+            Frame::Bulk(data) => {
+                dst.put_slice(format!("${}\r\n", data.len()).as_bytes());
+                dst.put_slice(data);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Array(parts) => {
+                dst.put_slice(format!("*{}\r\n", parts.len()).as_bytes());
+                for part in parts {
+                    part.encode(dst);
+                }
+            }
+        }
+    }
+
+    /// Converts an unexpected reply into an error.
+    pub fn to_error(&self) -> crate::Error {
+        match self {
+            Frame::Error(message) => message.clone().into(),
+            frame => format!("unexpected frame: {}", frame).into(),
+        }
+    }
+}
+
+impl PartialEq<&str> for Frame {
+    fn eq(&self, other: &&str) -> bool {
+        match self {
+            Frame::Simple(text) => text == other,
+            Frame::Bulk(data) => data == other.as_bytes(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Simple(text) => text.fmt(f),
+            Frame::Error(message) => write!(f, "error: {}", message),
+            Frame::Integer(value) => value.fmt(f),
+            Frame::Bulk(data) => match std::str::from_utf8(data) {
+                Ok(text) => text.fmt(f),
+                Err(_) => write!(f, "{:?}", data),
+            },
+            Frame::Null => "(nil)".fmt(f),
+            Frame::Array(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    part.fmt(f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Other(message.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        message.to_string().into()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(f),
+            Error::Other(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+/// Reads up to the next `\r\n`.
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buffer = *src.get_ref();
+    match buffer[start..].windows(2).position(|pair| pair == b"\r\n") {
+        Some(length) => {
+            src.set_position((start + length + 2) as u64);
+            Ok(&buffer[start..start + length])
+        }
+        None => Err(Error::Incomplete),
+    }
+}
+
+fn get_string(src: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    let line = get_line(src)?.to_vec();
+    String::from_utf8(line).map_err(|_| "protocol error; invalid string".into())
+}
+
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    std::str::from_utf8(get_line(src)?)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| "protocol error; invalid integer".into())
+}
+
+fn to_length(length: i64) -> Result<usize, Error> {
+    usize::try_from(length).map_err(|_| "protocol error; invalid length".into())
+}
+
+#[test]
+fn round_trip() {
+    let frame = Frame::Array(vec![
+        Frame::Simple("OK".to_string()),
+        Frame::Error("ERR no".to_string()),
+        Frame::Integer(-2),
+        Frame::Bulk(Bytes::from_static(b"with\r\nnewline")),
+        Frame::Null,
+        Frame::bulk_array(["nested"]),
+    ]);
+    let mut encoded = BytesMut::new();
+    frame.encode(&mut encoded);
+
+    let mut src = Cursor::new(&encoded[..]);
+    assert_eq!(Frame::parse(&mut src).unwrap(), frame);
+    assert_eq!(src.position() as usize, encoded.len());
+
+    for end in 0..encoded.len() {
+        let mut src = Cursor::new(&encoded[..end]);
+        assert!(matches!(Frame::parse(&mut src), Err(Error::Incomplete)));
+    }
+}
+
+#[test]
+fn deeply_nested_arrays_are_rejected() {
+    let parse = |input: &str| Frame::parse(&mut Cursor::new(input.as_bytes()));
+    let nested = |depth: usize| "*1\r\n".repeat(depth) + "*0\r\n";
+
+    let mut frame = Frame::Array(vec![]);
+    for _ in 0..MAX_DEPTH {
+        frame = Frame::Array(vec![frame]);
+    }
+    assert_eq!(parse(&nested(MAX_DEPTH)).unwrap(), frame);
+
+    // rejected as soon as the depth is exceeded, not once all of it arrived
+    for input in [nested(200_000), "*1\r\n".repeat(MAX_DEPTH + 1)] {
+        match parse(&input) {
+            Err(Error::Other(error)) => {
+                assert_eq!(
+                    error.to_string(),
+                    "protocol error; arrays are nested too deeply"
+                )
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+mod connection;
+pub use connection::Connection;
+
+pub mod client;
+
+pub mod db;
+pub use db::Db;
+
+pub mod frame;
+pub use frame::Frame;
+
+pub mod rdb;
+
+pub mod server;
+
+pub mod value;
+pub use value::Value;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1 +1,449 @@
-fn main() {}
+use bytes::Bytes;
+use clap::Parser;
+use kv_store_client::client::{Client, Pipeline};
+use kv_store_client::Frame;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[clap(
+    about = "Command-line client for the key-value server",
+    disable_help_flag = true,
+    trailing_var_arg = true
+)]
+struct Options {
+    /// Server hostname
+    #[clap(short = 'h', long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Server port
+    #[clap(short, long, default_value_t = 6379)]
+    port: u16,
+
+    /// Print replies as they are, without quotes or type annotations
+    #[clap(long)]
+    raw: bool,
+
+    /// Print help information
+    #[clap(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+
+    /// Command to run, e.g. `set foo bar`. Without one, commands are read
+    /// from the terminal, or one per line from stdin when it is not a
+    /// terminal
+    command: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Like `redis-cli` in a terminal: quoted strings, `(integer)`,
+    /// numbered array items.
+    Pretty,
+    Raw,
+}
+
+/// The connection to the server, opened again after it broke.
+struct Session {
+    addr: String,
+    client: Option<Client>,
+    format: Format,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let options = Options::parse();
+    let mut session = Session {
+        addr: format!("{}:{}", options.host, options.port),
+        client: None,
+        format: if options.raw {
+            Format::Raw
+        } else {
+            Format::Pretty
+        },
+    };
+
+    let succeeded = if !options.command.is_empty() {
+        let command = options.command.into_iter().map(Bytes::from).collect();
+        session.run(command).await
+    } else if io::stdin().is_terminal() {
+        session.repl().await;
+        true
+    } else {
+        session.run_stdin().await
+    };
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+impl Session {
+    /// Reads commands from the terminal until `quit` or Ctrl-D.
+    async fn repl(&mut self) {
+        let mut editor = Editor::<()>::new();
+        let history = history_path();
+        if let Some(path) = &history {
+            // there is none the first time
+            let _ = editor.load_history(path);
+        }
+        // shows the error right away, the prompt shows it failed
+        if let Err(error) = self.connect().await {
+            eprintln!("{}", error);
+        }
+
+        loop {
+            let prompt = match &self.client {
+                Some(client) if !client.is_broken() => format!("{}> ", self.addr),
+                _ => "not connected> ".to_string(),
+            };
+            let line = match editor.readline(&prompt) {
+                Ok(line) => line,
+                // Ctrl-C only drops the line being typed
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line.as_str());
+            if let Some(path) = &history {
+                let _ = editor.save_history(path);
+            }
+
+            match split_args(line.as_bytes()) {
+                Ok(command) if is_command(&command, "quit") || is_command(&command, "exit") => {
+                    break
+                }
+                Ok(command) => {
+                    self.run(command).await;
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+    }
+
+    /// Runs the commands on stdin, one per line. Returns whether all of
+    /// them succeeded.
+    async fn run_stdin(&mut self) -> bool {
+        let mut succeeded = true;
+        for line in io::stdin().lock().split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    eprintln!("reading stdin failed: {}", error);
+                    return false;
+                }
+            };
+            match split_args(&line) {
+                Ok(command) if command.is_empty() => {}
+                Ok(command) => succeeded &= self.run(command).await,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    succeeded = false;
+                }
+            }
+        }
+        succeeded
+    }
+
+    /// Runs one command and prints the reply. Returns whether it
+    /// succeeded, an error reply is a failure.
+    async fn run(&mut self, command: Vec<Bytes>) -> bool {
+        // without channels it is left to the server to reject
+        if is_command(&command, "subscribe") && command.len() > 1 {
+            return self.subscribe(&command[1..]).await;
+        }
+        let client = match self.connect().await {
+            Ok(client) => client,
+            Err(error) => {
+                eprintln!("{}", error);
+                return false;
+            }
+        };
+        let mut pipeline = Pipeline::new();
+        pipeline.command(command);
+        match client.execute(&pipeline).await {
+            Ok(replies) => {
+                let reply = &replies[0];
+                print(reply, self.format);
+                !matches!(reply, Frame::Error(_))
+            }
+            // the connection is opened again for the next command
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                false
+            }
+        }
+    }
+
+    /// Prints the messages published on `channels` until the connection
+    /// closes, like `redis-cli` does.
+    async fn subscribe(&mut self, channels: &[Bytes]) -> bool {
+        let channels: Vec<String> = channels
+            .iter()
+            .map(|channel| String::from_utf8_lossy(channel).into_owned())
+            .collect();
+        let channels: Vec<&str> = channels.iter().map(String::as_str).collect();
+        if let Err(error) = self.connect().await {
+            eprintln!("{}", error);
+            return false;
+        }
+        let client = self.client.take().unwrap();
+        let mut subscriber = match client.subscribe(&channels).await {
+            Ok(subscriber) => subscriber,
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return false;
+            }
+        };
+
+        if self.format == Format::Pretty {
+            println!("Reading messages... (press Ctrl-C to quit)");
+        }
+        for (count, channel) in subscriber.channels().iter().enumerate() {
+            let reply = Frame::Array(vec![
+                Frame::Bulk(Bytes::from("subscribe")),
+                Frame::Bulk(Bytes::from(channel.clone())),
+                Frame::Integer(count as i64 + 1),
+            ]);
+            print(&reply, self.format);
+        }
+        loop {
+            match subscriber.next_message().await {
+                Ok(Some(message)) => {
+                    let reply = Frame::Array(vec![
+                        Frame::Bulk(Bytes::from("message")),
+                        Frame::Bulk(Bytes::from(message.channel)),
+                        Frame::Bulk(message.content),
+                    ]);
+                    print(&reply, self.format);
+                }
+                Ok(None) => return true,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// The client, connected again if the connection broke.
+    async fn connect(&mut self) -> Result<&mut Client, String> {
+        if self.client.as_ref().is_none_or(Client::is_broken) {
+            self.client = None;
+            let client = Client::connect(self.addr.as_str())
+                .await
+                .map_err(|error| format!("Could not connect to {}: {}", self.addr, error))?;
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+}
+
+fn is_command(command: &[Bytes], name: &str) -> bool {
+    command
+        .first()
+        .is_some_and(|first| first.eq_ignore_ascii_case(name.as_bytes()))
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".kv_store_client_history"))
+}
+
+fn print(reply: &Frame, format: Format) {
+    let mut out = Vec::new();
+    match format {
+        Format::Pretty => format_pretty(reply, 0, &mut out),
+        Format::Raw => {
+            format_raw(reply, &mut out);
+            out.push(b'\n');
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    // a closed pipe is not worth a panic
+    let _ = stdout.write_all(&out).and_then(|()| stdout.flush());
+}
+
+/// Formats a reply the way `redis-cli` does in a terminal. Array items are
+/// numbered, nested arrays are indented by `indent` after their first
+/// line.
+fn format_pretty(reply: &Frame, indent: usize, out: &mut Vec<u8>) {
+    match reply {
+        Frame::Simple(text) => out.extend_from_slice(text.as_bytes()),
+        Frame::Error(message) => out.extend_from_slice(format!("(error) {}", message).as_bytes()),
+        Frame::Integer(value) => out.extend_from_slice(format!("(integer) {}", value).as_bytes()),
+        Frame::Bulk(data) => quote(data, out),
+        Frame::Null => out.extend_from_slice(b"(nil)"),
+        Frame::Array(parts) if parts.is_empty() => out.extend_from_slice(b"(empty array)"),
+        Frame::Array(parts) => {
+            let width = parts.len().to_string().len();
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    out.resize(out.len() + indent, b' ');
+                }
+                out.extend_from_slice(format!("{:>width$}) ", i + 1, width = width).as_bytes());
+                format_pretty(part, indent + width + 2, out);
+            }
+            // every item ended its line already
+            return;
+        }
+    }
+    out.push(b'\n');
+}
+
+/// Formats a reply without any decoration, one array item per line.
+fn format_raw(reply: &Frame, out: &mut Vec<u8>) {
+    match reply {
+        Frame::Simple(text) => out.extend_from_slice(text.as_bytes()),
+        Frame::Error(message) => out.extend_from_slice(message.as_bytes()),
+        Frame::Integer(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Frame::Bulk(data) => out.extend_from_slice(data),
+        Frame::Null => {}
+        Frame::Array(parts) => {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                format_raw(part, out);
+            }
+        }
+    }
+}
+
+/// Writes `data` in double quotes with the escapes `split_args` reads.
+fn quote(data: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for &byte in data {
+        match byte {
+            b'\\' | b'"' => out.extend_from_slice(&[b'\\', byte]),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            0x07 => out.extend_from_slice(b"\\a"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            byte if byte.is_ascii_graphic() || byte == b' ' => out.push(byte),
+            byte => out.extend_from_slice(format!("\\x{:02x}", byte).as_bytes()),
+        }
+    }
+    out.push(b'"');
+}
+
+/// Splits a command line into arguments like `redis-cli` does: on
+/// whitespace, except in `"double quotes"`, which support `\n`, `\xff`
+/// and similar escapes, or `'single quotes'`, which only support `\'`.
+fn split_args(line: &[u8]) -> Result<Vec<Bytes>, &'static str> {
+    const UNBALANCED: &str = "Invalid argument(s): unbalanced quotes";
+
+    let mut args = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        if bytes.peek().is_none() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        while let Some(byte) = bytes.next_if(|byte| !byte.is_ascii_whitespace()) {
+            match byte {
+                b'"' => loop {
+                    match bytes.next().ok_or(UNBALANCED)? {
+                        b'"' => break,
+                        b'\\' => match bytes.next().ok_or(UNBALANCED)? {
+                            b'n' => arg.push(b'\n'),
+                            b'r' => arg.push(b'\r'),
+                            b't' => arg.push(b'\t'),
+                            b'a' => arg.push(0x07),
+                            b'b' => arg.push(0x08),
+                            b'x' => {
+                                let digits = [bytes.next(), bytes.next()];
+                                let hex = match digits {
+                                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                                        .ok()
+                                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                                    _ => None,
+                                };
+                                arg.push(hex.ok_or("Invalid argument(s): bad \\x escape")?);
+                            }
+                            other => arg.push(other),
+                        },
+                        other => arg.push(other),
+                    }
+                },
+                b'\'' => loop {
+                    match bytes.next().ok_or(UNBALANCED)? {
+                        b'\'' => break,
+                        b'\\' if bytes.peek() == Some(&b'\'') => arg.push(bytes.next().unwrap()),
+                        other => arg.push(other),
+                    }
+                },
+                other => arg.push(other),
+            }
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+#[test]
+fn arguments_are_split_like_redis_cli() {
+    let args = |line: &str| -> Vec<String> {
+        split_args(line.as_bytes())
+            .unwrap()
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    };
+    assert_eq!(args("  set  foo bar "), ["set", "foo", "bar"]);
+    assert_eq!(args(r#"set "a b" 'c d'"#), ["set", "a b", "c d"]);
+    assert_eq!(args(r#"set "\x41\n\"" 'it\'s'"#), ["set", "A\n\"", "it's"]);
+    assert_eq!(
+        args(r#"set key"with quotes"inside"#),
+        ["set", "keywith quotesinside"]
+    );
+    assert_eq!(args(""), Vec::<String>::new());
+    assert!(split_args(b"set \"open").is_err());
+    assert!(split_args(b"set 'open").is_err());
+    assert!(split_args(b"set \"\\x4\"").is_err());
+}
+
+#[test]
+fn replies_are_formatted_like_redis_cli() {
+    let pretty = |reply: &Frame| {
+        let mut out = Vec::new();
+        format_pretty(reply, 0, &mut out);
+        String::from_utf8(out).unwrap()
+    };
+    let raw = |reply: &Frame| {
+        let mut out = Vec::new();
+        format_raw(reply, &mut out);
+        String::from_utf8(out).unwrap()
+    };
+
+    let nested = Frame::Array(vec![
+        Frame::bulk_array(["a", "b"]),
+        Frame::Integer(3),
+        Frame::Null,
+        Frame::Bulk(Bytes::from_static(b"line\n\"quoted\"\x01")),
+        Frame::Array(vec![]),
+        Frame::bulk_array(["5", "6", "7", "8", "9", "10", "11", "12", "13", "14"]),
+    ]);
+    assert_eq!(
+        pretty(&nested),
+        "1) 1) \"a\"\n   2) \"b\"\n2) (integer) 3\n3) (nil)\n\
+         4) \"line\\n\\\"quoted\\\"\\x01\"\n5) (empty array)\n\
+         6)  1) \"5\"\n    2) \"6\"\n    3) \"7\"\n    4) \"8\"\n    5) \"9\"\n\
+         \x20   6) \"10\"\n    7) \"11\"\n    8) \"12\"\n    9) \"13\"\n   10) \"14\"\n"
+    );
+    assert_eq!(pretty(&Frame::Error("ERR no".into())), "(error) ERR no\n");
+    assert_eq!(pretty(&Frame::Simple("OK".into())), "OK\n");
+
+    assert_eq!(raw(&Frame::bulk_array(["a", "b c"])), "a\nb c");
+    assert_eq!(raw(&Frame::Integer(-2)), "-2");
+    assert_eq!(raw(&Frame::Null), "");
+    assert_eq!(raw(&Frame::Error("ERR no".into())), "ERR no");
+}
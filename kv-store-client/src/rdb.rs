@@ -0,0 +1,369 @@
+//! Snapshots of a `Db` in a compact binary file.
+//!
+//! The file starts with `KVRDB` and a version byte, followed by one record
+//! per key and an end marker:
+//!
+//! ```text
+//! record  = flags u8, [expires_at u64], type u8, key, value
+//! key     = bytes
+//! value   = bytes                             string (type 0)
+//!         | count u32, bytes...               list (1) and set (3)
+//!         | count u32, (bytes, bytes)...      hash (2), field then value
+//!         | count u32, (bytes, score f64)...  sorted set (4)
+//! bytes   = length u32, data
+//! end     = 0xff, crc32 u32
+//! ```
+//!
+//! Integers and floats are big-endian. `expires_at` is only present when
+//! bit 0 of the flags is set and is a Unix time in milliseconds, since
+//! `Instant`s mean nothing to the next process. The checksum covers
+//! everything before it.
+//!
+//! Version 1 files have no type byte and only strings, they still load.
+
+use crate::db::{unix_millis, Db};
+use crate::value::{SortedSet, Value};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+const MAGIC: &[u8] = b"KVRDB";
+const VERSION: u8 = 2;
+
+const FLAG_EXPIRES: u8 = 0x01;
+const END: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_SORTED_SET: u8 = 4;
+
+/// Writes a snapshot of `db` to `path` and returns how many keys it holds.
+///
+/// The snapshot goes to a temporary file next to `path` first, which is
+/// synced and renamed over it, so a crash never leaves half a snapshot
+/// behind. This blocks, async callers should use `spawn_blocking`.
+pub fn save(db: &Db, path: &Path) -> io::Result<usize> {
+    let temporary = temporary_path(path);
+    let result = File::create(&temporary).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let keys = write(db, &mut writer)?;
+        let file = writer.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        Ok(keys)
+    });
+    match result {
+        Ok(keys) => {
+            fs::rename(&temporary, path)?;
+            Ok(keys)
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&temporary);
+            Err(error)
+        }
+    }
+}
+
+/// Loads the snapshot at `path` into `db` and returns how many keys were
+/// restored. A missing file is an empty snapshot, keys that expired in the
+/// meantime are skipped.
+pub fn load(db: &Db, path: &Path) -> crate::Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error.into()),
+    };
+    read(db, &data)
+        .map_err(|error| format!("invalid snapshot {}: {}", path.display(), error).into())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Computes the checksum of everything written through it.
+struct Checksummed<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write(db: &Db, writer: impl Write) -> io::Result<usize> {
+    let mut out = Checksummed {
+        inner: writer,
+        hasher: crc32fast::Hasher::new(),
+    };
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+
+    let mut keys = 0;
+    for index in 0..db.shard_count() {
+        for (key, value, expires_at) in db.iter_shard(index) {
+            match expires_at {
+                Some(when) => {
+                    out.write_all(&[FLAG_EXPIRES])?;
+                    out.write_all(&unix_millis(when).to_be_bytes())?;
+                }
+                None => out.write_all(&[0])?,
+            }
+            write_value(&mut out, key.as_bytes(), &value)?;
+            keys += 1;
+        }
+    }
+
+    out.write_all(&[END])?;
+    let checksum = out.hasher.clone().finalize();
+    out.inner.write_all(&checksum.to_be_bytes())?;
+    out.inner.flush()?;
+    Ok(keys)
+}
+
+fn write_value(out: &mut impl Write, key: &[u8], value: &Value) -> io::Result<()> {
+    let kind = match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::SortedSet(_) => TYPE_SORTED_SET,
+    };
+    out.write_all(&[kind])?;
+    write_bytes(out, key)?;
+    match value {
+        Value::String(data) => write_bytes(out, data),
+        Value::List(list) => {
+            write_length(out, list.len())?;
+            list.iter().try_for_each(|item| write_bytes(out, item))
+        }
+        Value::Hash(hash) => {
+            write_length(out, hash.len())?;
+            hash.iter().try_for_each(|(field, value)| {
+                write_bytes(out, field)?;
+                write_bytes(out, value)
+            })
+        }
+        Value::Set(set) => {
+            write_length(out, set.len())?;
+            set.iter().try_for_each(|member| write_bytes(out, member))
+        }
+        Value::SortedSet(set) => {
+            write_length(out, set.len())?;
+            set.iter().try_for_each(|(member, score)| {
+                write_bytes(out, member)?;
+                out.write_all(&score.to_be_bytes())
+            })
+        }
+    }
+}
+
+fn write_length(out: &mut impl Write, length: usize) -> io::Result<()> {
+    let length = u32::try_from(length)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value is too long"))?;
+    out.write_all(&length.to_be_bytes())
+}
+
+fn write_bytes(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    write_length(out, data.len())?;
+    out.write_all(data)
+}
+
+fn read(db: &Db, data: &[u8]) -> crate::Result<usize> {
+    if data.len() < MAGIC.len() + 1 + 1 + 4 || !data.starts_with(MAGIC) {
+        return Err("not a snapshot file".into());
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body).to_be_bytes() != checksum {
+        return Err("checksum mismatch".into());
+    }
+    let version = body[MAGIC.len()];
+    if version != 1 && version != VERSION {
+        return Err(format!("unsupported version {}", version).into());
+    }
+
+    let mut src = Reader {
+        data: &body[MAGIC.len() + 1..],
+    };
+    let wall_clock = SystemTime::now();
+    let mut keys = 0;
+    loop {
+        let flags = src.take(1)?[0];
+        if flags == END {
+            break;
+        }
+        if flags & !FLAG_EXPIRES != 0 {
+            return Err(format!("unknown record flags {:#04x}", flags).into());
+        }
+        let expires_at = if flags & FLAG_EXPIRES != 0 {
+            let millis = u64::from_be_bytes(src.take(8)?.try_into().unwrap());
+            if millis > i64::MAX as u64 {
+                return Err("expiry is out of range".into());
+            }
+            Some(UNIX_EPOCH + Duration::from_millis(millis))
+        } else {
+            None
+        };
+        let kind = if version == 1 {
+            TYPE_STRING
+        } else {
+            src.take(1)?[0]
+        };
+        let key =
+            String::from_utf8(src.take_bytes()?.to_vec()).map_err(|_| "key is not valid UTF-8")?;
+        let value = src.take_value(kind)?;
+
+        let expires_at = match expires_at {
+            Some(when) => match when.duration_since(wall_clock) {
+                Ok(remaining) if !remaining.is_zero() => Some(
+                    Instant::now()
+                        .checked_add(remaining)
+                        .ok_or("expiry is out of range")?,
+                ),
+                // expired while the server was down
+                _ => continue,
+            },
+            None => None,
+        };
+        db.set_value(key, value, expires_at);
+        keys += 1;
+    }
+    if !src.data.is_empty() {
+        return Err("data after the end marker".into());
+    }
+    Ok(keys)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> crate::Result<&'a [u8]> {
+        if self.data.len() < length {
+            return Err("unexpected end of file".into());
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_length(&mut self) -> crate::Result<usize> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn take_bytes(&mut self) -> crate::Result<&'a [u8]> {
+        let length = self.take_length()?;
+        self.take(length)
+    }
+
+    fn take_item(&mut self) -> crate::Result<Bytes> {
+        self.take_bytes().map(Bytes::copy_from_slice)
+    }
+
+    fn take_value(&mut self, kind: u8) -> crate::Result<Value> {
+        if kind == TYPE_STRING {
+            return Ok(Value::String(self.take_item()?));
+        }
+        // the count is untrusted, it only bounds the loops
+        let length = self.take_length()?;
+        Ok(match kind {
+            TYPE_LIST => Value::List(
+                (0..length)
+                    .map(|_| self.take_item())
+                    .collect::<crate::Result<_>>()?,
+            ),
+            TYPE_HASH => {
+                let mut hash = HashMap::new();
+                for _ in 0..length {
+                    hash.insert(self.take_item()?, self.take_item()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_SET => Value::Set(
+                (0..length)
+                    .map(|_| self.take_item())
+                    .collect::<crate::Result<_>>()?,
+            ),
+            TYPE_SORTED_SET => {
+                let mut set = SortedSet::default();
+                for _ in 0..length {
+                    let member = self.take_item()?;
+                    let score = f64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                    if score.is_nan() {
+                        return Err("score is not a number".into());
+                    }
+                    set.insert(member, score);
+                }
+                Value::SortedSet(set)
+            }
+            kind => return Err(format!("unknown value type {}", kind).into()),
+        })
+    }
+}
+
+#[tokio::test]
+async fn snapshots_round_trip() {
+    let path = std::env::temp_dir().join(format!("kv-store-rdb-{}.rdb", std::process::id()));
+    let db = Db::new();
+    db.set("plain".to_string(), Bytes::from("value"), None);
+    db.set(
+        "expiring".to_string(),
+        Bytes::from_static(b"\x00binary\xff"),
+        Some(Instant::now() + Duration::from_secs(100)),
+    );
+    db.set(String::new(), Bytes::new(), None);
+    let items = vec![Bytes::from("a"), Bytes::from("b")];
+    db.push("list", items.clone(), crate::db::ListEnd::Right)
+        .unwrap();
+    db.hset("hash", vec![(Bytes::from("f"), Bytes::from("v"))])
+        .unwrap();
+    db.sadd("set", items.clone()).unwrap();
+    db.zadd(
+        "zset",
+        vec![(1.5, Bytes::from("a")), (-0.25, Bytes::from("b"))],
+    )
+    .unwrap();
+    assert!(db.expire("zset", Instant::now() + Duration::from_secs(100)));
+    assert_eq!(save(&db, &path).unwrap(), 7);
+    assert!(!temporary_path(&path).exists());
+
+    let restored = Db::with_shards(3);
+    assert_eq!(load(&restored, &path).unwrap(), 7);
+    let values = |db: &Db| -> HashMap<String, Value> {
+        (0..db.shard_count())
+            .flat_map(|index| db.iter_shard(index))
+            .map(|(key, value, _)| (key, value))
+            .collect()
+    };
+    assert_eq!(values(&restored), values(&db));
+    for key in ["expiring", "zset"] {
+        let ttl = restored.ttl(key).unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(98) && ttl <= Duration::from_secs(100));
+    }
+
+    // any flipped bit is caught by the checksum
+    let mut data = fs::read(&path).unwrap();
+    data[10] ^= 0x01;
+    fs::write(&path, &data).unwrap();
+    let error = load(&Db::new(), &path).unwrap_err();
+    assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+
+    fs::remove_file(&path).unwrap();
+    assert_eq!(load(&Db::new(), &path).unwrap(), 0);
+}
@@ -0,0 +1,235 @@
+//! A Redis compatible server on top of `Db`.
+
+mod aof;
+mod cmd;
+mod snapshot;
+
+pub use aof::{Aof, AofError, Fsync};
+pub use snapshot::{SaveError, Snapshots};
+
+use crate::{Connection, Db, Frame};
+use bytes::Bytes;
+use cmd::Command;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+type Messages = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+
+/// Serves clients accepted on `listener` until `shutdown` completes.
+///
+/// `SAVE` and `BGSAVE` write to `snapshots`, every write goes to `aof` if
+/// there is one. Both must be for `db`. Connections still open when it
+/// returns keep running in their tasks.
+pub async fn run(
+    listener: TcpListener,
+    db: Db,
+    snapshots: Snapshots,
+    aof: Option<Aof>,
+    shutdown: impl Future,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let socket = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(error) => {
+                    println!("accept error: {}", error);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return,
+        };
+        // Clone the handle to the shared state.
+        let db = db.clone();
+        let snapshots = snapshots.clone();
+        let aof = aof.clone();
+
+        println!("Accepted");
+        tokio::spawn(async move {
+            if let Err(error) = process(socket, db, snapshots, aof).await {
+                println!("connection error: {}", error);
+            }
+        });
+    }
+}
+
+async fn process(
+    socket: TcpStream,
+    db: Db,
+    snapshots: Snapshots,
+    aof: Option<Aof>,
+) -> crate::Result<()> {
+    // Connection handles parsing frames from the socket
+    let mut connection = Connection::new(socket);
+
+    while let Some(frame) = connection.read_frame().await? {
+        let command = Command::from_frame(frame);
+        let writes = command.as_ref().is_ok_and(Command::is_write);
+        // writes that could not be persisted are refused, not applied
+        if let Some(Err(error)) = aof.as_ref().filter(|_| writes).map(Aof::writable) {
+            connection
+                .write_frame(&Frame::Error(format!("ERR {}", error)))
+                .await?;
+            continue;
+        }
+        let response = match command {
+            Ok(Command::Subscribe { channels }) => {
+                // only returns once the client unsubscribed from everything
+                subscribe(&mut connection, &db, channels).await?;
+                continue;
+            }
+            Ok(Command::Unsubscribe { channels }) => {
+                // nothing to unsubscribe from, but every channel gets a reply
+                if channels.is_empty() {
+                    connection
+                        .write_frame(&subscription_frame("unsubscribe", None, 0))
+                        .await?;
+                }
+                for channel in channels {
+                    connection
+                        .write_frame(&subscription_frame("unsubscribe", Some(channel), 0))
+                        .await?;
+                }
+                continue;
+            }
+            Ok(Command::Save) => match snapshots.save().await {
+                Ok(_) => Frame::Simple("OK".to_string()),
+                Err(error) => Frame::Error(format!("ERR {}", error)),
+            },
+            Ok(Command::BgSave) => match snapshots.bgsave() {
+                Ok(()) => Frame::Simple("Background saving started".to_string()),
+                Err(error) => Frame::Error(format!("ERR {}", error)),
+            },
+            Ok(Command::BgRewriteAof) => match &aof {
+                Some(aof) => match aof.bgrewrite() {
+                    Ok(()) => {
+                        Frame::Simple("Background append only file rewriting started".to_string())
+                    }
+                    Err(error) => Frame::Error(format!("ERR {}", error)),
+                },
+                None => Frame::Error("ERR append only file is disabled".to_string()),
+            },
+            Ok(command) => command.apply(&db),
+            Err(error) => error.into(),
+        };
+
+        // a write is only acknowledged once the append-only file has it
+        let response = match &aof {
+            Some(aof) if writes => match aof.written().await {
+                Ok(()) => response,
+                Err(error) => Frame::Error(format!("ERR {}", error)),
+            },
+            _ => response,
+        };
+
+        // Write the response to the client
+        connection.write_frame(&response).await?;
+    }
+    Ok(())
+}
+
+fn subscription_frame(kind: &'static str, channel: Option<String>, count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+        channel.map_or(Frame::Null, |channel| Frame::Bulk(Bytes::from(channel))),
+        Frame::Integer(count as i64),
+    ])
+}
+
+/// Forwards messages published on the channels to the client until it has
+/// unsubscribed from all of them or disconnects.
+async fn subscribe(
+    connection: &mut Connection,
+    db: &Db,
+    channels: Vec<String>,
+) -> crate::Result<()> {
+    let mut subscriptions = StreamMap::new();
+    let result = forward_messages(connection, db, channels, &mut subscriptions).await;
+
+    // channels nobody listens to any more are dropped
+    let channels: Vec<String> = subscriptions.keys().cloned().collect();
+    drop(subscriptions);
+    for channel in channels {
+        db.unsubscribed(&channel);
+    }
+    result
+}
+
+async fn forward_messages(
+    connection: &mut Connection,
+    db: &Db,
+    mut pending: Vec<String>,
+    subscriptions: &mut StreamMap<String, Messages>,
+) -> crate::Result<()> {
+    loop {
+        for channel in pending.drain(..) {
+            // a subscriber that falls behind misses messages
+            let messages = BroadcastStream::new(db.subscribe(channel.clone()))
+                .filter_map(|message| message.ok());
+            subscriptions.insert(channel.clone(), Box::pin(messages));
+            connection
+                .write_frame(&subscription_frame(
+                    "subscribe",
+                    Some(channel),
+                    subscriptions.len(),
+                ))
+                .await?;
+        }
+        if subscriptions.is_empty() {
+            return Ok(());
+        }
+
+        tokio::select! {
+            Some((channel, message)) = subscriptions.next() => {
+                let frame = Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"message")),
+                    Frame::Bulk(Bytes::from(channel)),
+                    Frame::Bulk(message),
+                ]);
+                connection.write_frame(&frame).await?;
+            }
+            frame = connection.read_frame() => {
+                let frame = match frame? {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                };
+                match Command::from_frame(frame) {
+                    Ok(Command::Subscribe { channels }) => pending.extend(channels),
+                    Ok(Command::Unsubscribe { mut channels }) => {
+                        if channels.is_empty() {
+                            channels = subscriptions.keys().cloned().collect();
+                        }
+                        for channel in channels {
+                            subscriptions.remove(&channel);
+                            db.unsubscribed(&channel);
+                            let frame = subscription_frame(
+                                "unsubscribe",
+                                Some(channel),
+                                subscriptions.len(),
+                            );
+                            connection.write_frame(&frame).await?;
+                        }
+                    }
+                    Ok(Command::Ping { message }) => {
+                        let frame = Frame::Array(vec![
+                            Frame::Bulk(Bytes::from_static(b"pong")),
+                            Frame::Bulk(message.unwrap_or_default()),
+                        ]);
+                        connection.write_frame(&frame).await?;
+                    }
+                    Ok(command) => {
+                        let frame = Frame::Error(format!(
+                            "ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context",
+                            command.name()
+                        ));
+                        connection.write_frame(&frame).await?;
+                    }
+                    Err(error) => connection.write_frame(&error.into()).await?,
+                }
+            }
+        }
+    }
+}
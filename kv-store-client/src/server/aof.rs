@@ -0,0 +1,487 @@
+use super::cmd::Command;
+use crate::db::{self, Journal};
+use crate::{frame, Db, Frame};
+use bytes::{Buf, BytesMut};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The file is rewritten once it has doubled in size since the last
+/// rewrite and is at least this big.
+const AUTO_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// When appended commands are synced to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fsync {
+    /// Before replying to the command, nothing acknowledged is lost.
+    Always,
+    /// Once per second, a crash loses at most the last second.
+    #[clap(name = "everysec")]
+    EverySec,
+    /// Whenever the operating system flushes its buffers.
+    No,
+}
+
+/// The append-only file: every write to the database is appended to it as
+/// a command, and replayed at startup.
+///
+/// A background rewrite replaces the file with the commands that create
+/// the current keys, so it does not grow forever with overwritten keys.
+#[derive(Clone)]
+pub struct Aof {
+    db: Db,
+    log: Arc<Log>,
+}
+
+/// The part the database writes to, see `Journal`.
+///
+/// The database records commands with a shard locked, so `state` is only
+/// ever held to touch buffers. Writing to the file and syncing it happens
+/// outside of it, with `writer` held to keep the writes in order.
+struct Log {
+    path: PathBuf,
+    fsync: Fsync,
+    state: Mutex<State>,
+    writer: Mutex<()>,
+    rewriting: AtomicBool,
+}
+
+struct State {
+    file: Arc<File>,
+    /// Recorded commands that are not in the file yet.
+    pending: BytesMut,
+    /// Number of commands recorded, and how many of them are as durable as
+    /// the fsync policy promises.
+    recorded: u64,
+    durable: u64,
+    size: u64,
+    /// Size after the last rewrite, or at startup.
+    base_size: u64,
+    /// Written since the last sync.
+    dirty: bool,
+    rewrite: Option<Rewrite>,
+    /// The last failed write, writes are refused until writing works
+    /// again.
+    error: Option<String>,
+}
+
+/// Commands recorded while a rewrite runs.
+struct Rewrite {
+    /// Shards the rewrite has copied. Only commands on those go to the
+    /// buffer, the copy of the others will include them.
+    copied: Vec<bool>,
+    /// Appended to the new file once all shards are written.
+    buffer: BytesMut,
+}
+
+#[derive(Debug)]
+pub enum AofError {
+    RewriteInProgress,
+    Write(String),
+}
+
+impl fmt::Display for AofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AofError::RewriteInProgress => {
+                "Background append only file rewriting already in progress".fmt(f)
+            }
+            AofError::Write(error) => write!(f, "Errors writing to the AOF file: {}", error),
+        }
+    }
+}
+
+impl Aof {
+    /// Replays the file at `path` into `db`, which should be empty, and
+    /// records every write to `db` from then on.
+    ///
+    /// A command cut short at the end of the file, as left by a crash
+    /// during a write, is dropped from the file. Anything else that is not
+    /// a valid write command fails.
+    pub fn open(db: Db, path: PathBuf, fsync: Fsync) -> crate::Result<(Aof, usize)> {
+        let commands = replay(&db, &path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let log = Arc::new(Log {
+            path,
+            fsync,
+            state: Mutex::new(State {
+                file: Arc::new(file),
+                pending: BytesMut::new(),
+                recorded: 0,
+                durable: 0,
+                size,
+                base_size: size,
+                dirty: false,
+                rewrite: None,
+                error: None,
+            }),
+            writer: Mutex::new(()),
+            rewriting: AtomicBool::new(false),
+        });
+        db.set_journal(log.clone());
+
+        let aof = Aof { db, log };
+        tokio::spawn(aof.clone().run());
+        Ok((aof, commands))
+    }
+
+    /// Waits until the writes so far are as durable as the fsync policy
+    /// promises, so the client can be told they succeeded.
+    pub async fn written(&self) -> Result<(), AofError> {
+        self.writable()?;
+        self.flush().await
+    }
+
+    /// Fails while the file cannot be written, like Redis the server then
+    /// refuses writes instead of losing them on a restart.
+    pub fn writable(&self) -> Result<(), AofError> {
+        match &self.log.state.lock().unwrap().error {
+            Some(error) => Err(AofError::Write(error.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the commands recorded so far, retrying after a failed write.
+    async fn flush(&self) -> Result<(), AofError> {
+        let log = self.log.clone();
+        let recorded = log.state.lock().unwrap().recorded;
+        tokio::task::spawn_blocking(move || log.flush(recorded))
+            .await
+            .unwrap()
+    }
+
+    /// Starts rewriting the file in the background.
+    pub fn bgrewrite(&self) -> Result<(), AofError> {
+        if self
+            .log
+            .rewriting
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(AofError::RewriteInProgress);
+        }
+        let aof = self.clone();
+        tokio::spawn(async move {
+            let log = aof.log.clone();
+            let result = tokio::task::spawn_blocking(move || aof.rewrite()).await;
+            log.rewriting.store(false, Ordering::Release);
+            match result.unwrap() {
+                Ok(size) => println!("rewrote {} ({} bytes)", log.path.display(), size),
+                Err(error) => println!("rewriting {} failed: {}", log.path.display(), error),
+            }
+        });
+        Ok(())
+    }
+
+    /// Syncs the file every second under the `everysec` policy, and starts
+    /// a rewrite when the file grew too much.
+    async fn run(self) {
+        let mut ticks = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticks.tick().await;
+            // commands nobody waits for, e.g. deletions of expired keys, and
+            // another attempt after a failed write
+            let _ = self.flush().await;
+            let (file, grown) = {
+                let mut state = self.log.state.lock().unwrap();
+                let dirty = std::mem::take(&mut state.dirty);
+                let file = (dirty && self.log.fsync == Fsync::EverySec).then(|| state.file.clone());
+                let grown =
+                    state.size >= AUTO_REWRITE_MIN_SIZE && state.size >= 2 * state.base_size;
+                (file, grown)
+            };
+            if let Some(file) = file {
+                let result = tokio::task::spawn_blocking(move || file.sync_data()).await;
+                if let Err(error) = result.unwrap() {
+                    println!("syncing {} failed: {}", self.log.path.display(), error);
+                }
+            }
+            if grown {
+                // nothing to do if a rewrite is already running
+                let _ = self.bgrewrite();
+            }
+        }
+    }
+
+    /// Writes the current keys to a new file, appends the commands recorded
+    /// in the meantime and moves it over the old one. Blocks.
+    fn rewrite(&self) -> io::Result<u64> {
+        self.log.state.lock().unwrap().rewrite = Some(Rewrite {
+            copied: vec![false; self.db.shard_count()],
+            buffer: BytesMut::new(),
+        });
+        let temporary = temporary_path(&self.log.path);
+        let result = self.write_keys(&temporary).and_then(|file| {
+            // nothing goes to the old file from here on, commands recorded
+            // in the meantime stay pending until the new one is in place
+            let _writer = self.log.writer.lock().unwrap();
+            let (tail, covered, recorded) = {
+                let mut state = self.log.state.lock().unwrap();
+                let rewrite = state.rewrite.take().unwrap();
+                (rewrite.buffer, state.pending.len(), state.recorded)
+            };
+            (&file).write_all(&tail)?;
+            file.sync_all()?;
+            fs::rename(&temporary, &self.log.path)?;
+            let size = file.metadata()?.len();
+
+            let mut state = self.log.state.lock().unwrap();
+            // the copy and its tail have these already
+            state.pending.advance(covered);
+            state.durable = state.durable.max(recorded);
+            state.file = Arc::new(file);
+            state.size = size;
+            state.base_size = size;
+            state.error = None;
+            Ok(size)
+        });
+        if result.is_err() {
+            self.log.state.lock().unwrap().rewrite = None;
+            let _ = fs::remove_file(&temporary);
+        }
+        result
+    }
+
+    fn write_keys(&self, path: &Path) -> io::Result<File> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut buffer = BytesMut::new();
+        for index in 0..self.db.shard_count() {
+            let copied = || {
+                if let Some(rewrite) = &mut self.log.state.lock().unwrap().rewrite {
+                    rewrite.copied[index] = true;
+                }
+            };
+            for (key, value, expires_at) in self.db.iter_shard_with(index, copied) {
+                buffer.clear();
+                for command in db::restore_commands(&key, &value, expires_at) {
+                    command.encode(&mut buffer);
+                }
+                writer.write_all(&buffer)?;
+            }
+        }
+        writer.into_inner().map_err(|error| error.into_error())
+    }
+}
+
+impl Log {
+    /// Writes the pending commands to the file, unless the first `target`
+    /// commands already are as durable as promised. Blocks.
+    fn flush(&self, target: u64) -> Result<(), AofError> {
+        let _writer = self.writer.lock().unwrap();
+        let (file, data, recorded, size, failed) = {
+            let mut state = self.state.lock().unwrap();
+            if state.durable >= target && state.error.is_none() {
+                return Ok(());
+            }
+            (
+                state.file.clone(),
+                state.pending.split(),
+                state.recorded,
+                state.size,
+                state.error.is_some(),
+            )
+        };
+
+        // a failed write may have left part of a command behind, truncating
+        // does not move the offset of files not opened for appending
+        let mut result = if failed {
+            file.set_len(size)
+                .and_then(|()| (&*file).seek(SeekFrom::Start(size)).map(drop))
+        } else {
+            Ok(())
+        };
+        if result.is_ok() {
+            result = (&*file).write_all(&data);
+        }
+        if result.is_ok() && self.fsync == Fsync::Always {
+            result = file.sync_data();
+        }
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(()) => {
+                state.size += data.len() as u64;
+                state.dirty |= !data.is_empty();
+                state.durable = recorded;
+                if state.error.take().is_some() {
+                    println!("writing to {} works again", self.path.display());
+                }
+                Ok(())
+            }
+            Err(error) => {
+                println!("writing to {} failed: {}", self.path.display(), error);
+                // kept for the next attempt, in front of newer commands
+                let newer = std::mem::replace(&mut state.pending, data);
+                state.pending.unsplit(newer);
+                state.error = Some(error.to_string());
+                Err(AofError::Write(error.to_string()))
+            }
+        }
+    }
+}
+
+impl Journal for Log {
+    fn record(&self, shard: usize, command: Frame) {
+        let mut encoded = BytesMut::new();
+        command.encode(&mut encoded);
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(rewrite) = &mut state.rewrite {
+            if rewrite.copied[shard] {
+                rewrite.buffer.extend_from_slice(&encoded);
+            }
+        }
+        state.pending.extend_from_slice(&encoded);
+        state.recorded += 1;
+    }
+}
+
+/// Applies the commands in the file to `db` and returns how many there
+/// were.
+fn replay(db: &Db, path: &Path) -> crate::Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut src = Cursor::new(&data[..]);
+    let mut commands = 0;
+    while (src.position() as usize) < data.len() {
+        let start = src.position();
+        let frame = match Frame::parse(&mut src) {
+            Ok(frame) => frame,
+            Err(frame::Error::Incomplete) => {
+                println!(
+                    "{} ends with a truncated command, dropping its last {} bytes",
+                    path.display(),
+                    data.len() as u64 - start
+                );
+                OpenOptions::new().write(true).open(path)?.set_len(start)?;
+                break;
+            }
+            Err(error) => {
+                return Err(
+                    format!("invalid {} at byte {}: {}", path.display(), start, error).into(),
+                )
+            }
+        };
+        apply(db, frame)
+            .map_err(|error| format!("invalid {} at byte {}: {}", path.display(), start, error))?;
+        commands += 1;
+    }
+    Ok(commands)
+}
+
+fn apply(db: &Db, frame: Frame) -> Result<(), String> {
+    let command = Command::from_frame(frame).map_err(|error| error.to_string())?;
+    if !command.is_write() {
+        return Err(format!("unexpected command '{}'", command.name()));
+    }
+    match command.apply(db) {
+        Frame::Error(error) => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".rewrite");
+    path.with_file_name(name)
+}
+
+#[tokio::test]
+async fn replays_rewritten_and_truncated_files() {
+    use bytes::Bytes;
+    use tokio::time::Instant;
+
+    let path = std::env::temp_dir().join(format!("kv-store-aof-{}.aof", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let db = Db::new();
+    let (aof, commands) = Aof::open(db.clone(), path.clone(), Fsync::Always).unwrap();
+    assert_eq!(commands, 0);
+
+    db.set("a".to_string(), Bytes::from("1"), None);
+    db.set("b".to_string(), Bytes::from("2"), None);
+    db.set("a".to_string(), Bytes::from("3"), None);
+    assert!(db.expire("b", Instant::now() + Duration::from_secs(100)));
+    assert!(db.del("b"));
+    db.set(
+        "c".to_string(),
+        Bytes::from("4"),
+        Some(Instant::now() + Duration::from_secs(100)),
+    );
+    aof.written().await.unwrap();
+    let appended = fs::metadata(&path).unwrap().len();
+
+    // one SET per key is left
+    aof.rewrite().unwrap();
+    assert!(fs::metadata(&path).unwrap().len() < appended);
+    let rewritten = fs::metadata(&path).unwrap().len();
+    db.set("d".to_string(), Bytes::from("5"), None);
+
+    // recording a command does not touch the file, waiting for it does
+    assert_eq!(fs::metadata(&path).unwrap().len(), rewritten);
+    aof.written().await.unwrap();
+    let complete = fs::metadata(&path).unwrap().len();
+    assert!(complete > rewritten);
+
+    // as if the server crashed in the middle of a write
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\ne").unwrap();
+
+    let restored = Db::new();
+    let (_, commands) = Aof::open(restored.clone(), path.clone(), Fsync::No).unwrap();
+    assert_eq!(commands, 3);
+    assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+    assert_eq!(restored.get("a"), Ok(Some(Bytes::from("3"))));
+    assert_eq!(restored.get("b"), Ok(None));
+    assert_eq!(restored.get("d"), Ok(Some(Bytes::from("5"))));
+    let ttl = restored.ttl("c").unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(98) && ttl <= Duration::from_secs(100));
+
+    // anything but a truncated tail is an error
+    fs::write(&path, b"+OK\r\n").unwrap();
+    assert!(Aof::open(Db::new(), path.clone(), Fsync::No).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn failed_writes_are_retried() {
+    use bytes::Bytes;
+
+    let path = std::env::temp_dir().join(format!("kv-store-aof-{}-full.aof", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let db = Db::new();
+    let (aof, _) = Aof::open(db.clone(), path.clone(), Fsync::Always).unwrap();
+    db.set("a".to_string(), Bytes::from("1"), None);
+    aof.written().await.unwrap();
+    // the rewritten file is not opened for appending
+    aof.rewrite().unwrap();
+    let size = fs::metadata(&path).unwrap().len();
+
+    // a disk without room left
+    let full = OpenOptions::new().write(true).open("/dev/full").unwrap();
+    let file = std::mem::replace(&mut aof.log.state.lock().unwrap().file, Arc::new(full));
+    db.set("b".to_string(), Bytes::from("2"), None);
+    assert!(matches!(aof.written().await, Err(AofError::Write(_))));
+    assert!(aof.writable().is_err());
+
+    // part of the failed write ended up in the file, the retry replaces it
+    (&*file).write_all(b"*3\r\n$3\r\nSET\r\n").unwrap();
+    aof.log.state.lock().unwrap().file = file;
+    aof.flush().await.unwrap();
+    assert!(aof.writable().is_ok());
+    assert!(fs::metadata(&path).unwrap().len() > size);
+
+    let restored = Db::new();
+    let (_, commands) = Aof::open(restored.clone(), path.clone(), Fsync::No).unwrap();
+    assert_eq!(commands, 2);
+    assert_eq!(restored.get("b"), Ok(Some(Bytes::from("2"))));
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,563 @@
+use crate::db::ListEnd;
+use crate::value::WrongType;
+use crate::{Db, Frame};
+use bytes::Bytes;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec;
+use tokio::time::Instant;
+
+/// A command sent by a client.
+///
+/// `mini_redis::Command` keeps the fields of `Publish` and `Subscribe`
+/// private and only knows a few commands, so they are parsed here instead.
+/// Commands on keys are run by `apply`, the others by the connection.
+#[derive(Debug)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Bytes,
+        expires_at: Option<Instant>,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    /// `EXPIRE`, `PEXPIRE` and their `AT` variants, a deadline that has
+    /// passed deletes the key.
+    Expire {
+        key: String,
+        expires_at: Instant,
+    },
+    /// `TTL` and `PTTL`.
+    Ttl {
+        key: String,
+        millis: bool,
+    },
+    Persist {
+        key: String,
+    },
+    /// `LPUSH` and `RPUSH`.
+    Push {
+        key: String,
+        values: Vec<Bytes>,
+        end: ListEnd,
+    },
+    /// `LPOP` and `RPOP`.
+    Pop {
+        key: String,
+        end: ListEnd,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: String,
+        fields: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: String,
+        field: Bytes,
+    },
+    HDel {
+        key: String,
+        fields: Vec<Bytes>,
+    },
+    HGetAll {
+        key: String,
+    },
+    SAdd {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    SMembers {
+        key: String,
+    },
+    ZAdd {
+        key: String,
+        members: Vec<(f64, Bytes)>,
+    },
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    ZScore {
+        key: String,
+        member: Bytes,
+    },
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+    /// Without channels, unsubscribes from all of them.
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    Ping {
+        message: Option<Bytes>,
+    },
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Unknown {
+        name: String,
+    },
+}
+
+/// Why a frame is not a valid command. It is reported to the client as an
+/// error frame, the connection stays usable.
+#[derive(Debug)]
+pub struct CommandError(String);
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ERR {}", self.0)
+    }
+}
+
+impl From<CommandError> for Frame {
+    fn from(error: CommandError) -> Frame {
+        Frame::Error(error.to_string())
+    }
+}
+
+/// Cursor over the arguments of a command.
+struct Parse {
+    name: String,
+    parts: vec::IntoIter<Frame>,
+}
+
+impl Parse {
+    fn arity_error(&self) -> CommandError {
+        CommandError(format!(
+            "wrong number of arguments for '{}' command",
+            self.name
+        ))
+    }
+
+    fn has_next(&self) -> bool {
+        self.parts.len() > 0
+    }
+
+    fn next_bytes(&mut self) -> Result<Bytes, CommandError> {
+        match self.parts.next() {
+            Some(Frame::Bulk(data)) => Ok(data),
+            Some(Frame::Simple(text)) => Ok(Bytes::from(text)),
+            Some(frame) => Err(CommandError(format!(
+                "protocol error; expected bulk string, got {:?}",
+                frame
+            ))),
+            None => Err(self.arity_error()),
+        }
+    }
+
+    fn next_string(&mut self) -> Result<String, CommandError> {
+        let data = self.next_bytes()?;
+        String::from_utf8(data.to_vec())
+            .map_err(|_| CommandError("protocol error; invalid string".to_string()))
+    }
+
+    fn next_int(&mut self) -> Result<i64, CommandError> {
+        if let Some(Frame::Integer(value)) = self.parts.as_slice().first() {
+            let value = *value;
+            self.parts.next();
+            return Ok(value);
+        }
+        self.next_string()?
+            .parse()
+            .map_err(|_| CommandError("value is not an integer or out of range".to_string()))
+    }
+
+    fn next_float(&mut self) -> Result<f64, CommandError> {
+        match self.next_string()?.parse::<f64>() {
+            Ok(value) if !value.is_nan() => Ok(value),
+            _ => Err(CommandError("value is not a valid float".to_string())),
+        }
+    }
+
+    /// One or more arguments up to the end.
+    fn remaining_bytes(&mut self) -> Result<Vec<Bytes>, CommandError> {
+        let mut parts = vec![self.next_bytes()?];
+        while self.has_next() {
+            parts.push(self.next_bytes()?);
+        }
+        Ok(parts)
+    }
+
+    fn finish(&mut self) -> Result<(), CommandError> {
+        if self.has_next() {
+            Err(self.arity_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Command {
+    pub fn from_frame(frame: Frame) -> Result<Command, CommandError> {
+        let parts = match frame {
+            Frame::Array(parts) if !parts.is_empty() => parts,
+            frame => {
+                return Err(CommandError(format!(
+                    "protocol error; expected a command array, got {:?}",
+                    frame
+                )))
+            }
+        };
+        let mut parse = Parse {
+            name: String::new(),
+            parts: parts.into_iter(),
+        };
+        parse.name = parse.next_string()?.to_lowercase();
+
+        let command = match parse.name.as_str() {
+            "get" => Command::Get {
+                key: parse.next_string()?,
+            },
+            "set" => {
+                let key = parse.next_string()?;
+                let value = parse.next_bytes()?;
+                let expires_at = if parse.has_next() {
+                    let unit = parse.next_string()?.to_uppercase();
+                    let amount = match parse.next_int()? {
+                        amount if amount > 0 => amount as u64,
+                        _ => {
+                            return Err(CommandError(
+                                "invalid expire time in 'set' command".to_string(),
+                            ))
+                        }
+                    };
+                    let expire = match unit.as_str() {
+                        "EX" => Duration::from_secs(amount),
+                        "PX" => Duration::from_millis(amount),
+                        "EXAT" => until(Duration::from_secs(amount)),
+                        "PXAT" => until(Duration::from_millis(amount)),
+                        _ => return Err(CommandError("syntax error".to_string())),
+                    };
+                    Some(deadline(expire, "set")?)
+                } else {
+                    None
+                };
+                Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                }
+            }
+            "del" => {
+                let mut keys = vec![parse.next_string()?];
+                while parse.has_next() {
+                    keys.push(parse.next_string()?);
+                }
+                Command::Del { keys }
+            }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                let key = parse.next_string()?;
+                // a time in the past deletes the key
+                let amount = parse.next_int()?.max(0) as u64;
+                let expire = match parse.name.as_str() {
+                    "expire" => Duration::from_secs(amount),
+                    "pexpire" => Duration::from_millis(amount),
+                    "expireat" => until(Duration::from_secs(amount)),
+                    _ => until(Duration::from_millis(amount)),
+                };
+                Command::Expire {
+                    key,
+                    expires_at: deadline(expire, &parse.name)?,
+                }
+            }
+            "ttl" | "pttl" => Command::Ttl {
+                key: parse.next_string()?,
+                millis: parse.name == "pttl",
+            },
+            "persist" => Command::Persist {
+                key: parse.next_string()?,
+            },
+            "lpush" | "rpush" => Command::Push {
+                key: parse.next_string()?,
+                values: parse.remaining_bytes()?,
+                end: if parse.name == "lpush" {
+                    ListEnd::Left
+                } else {
+                    ListEnd::Right
+                },
+            },
+            "lpop" | "rpop" => Command::Pop {
+                key: parse.next_string()?,
+                end: if parse.name == "lpop" {
+                    ListEnd::Left
+                } else {
+                    ListEnd::Right
+                },
+            },
+            "lrange" => Command::LRange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                stop: parse.next_int()?,
+            },
+            "hset" => {
+                let key = parse.next_string()?;
+                let mut fields = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+                while parse.has_next() {
+                    fields.push((parse.next_bytes()?, parse.next_bytes()?));
+                }
+                Command::HSet { key, fields }
+            }
+            "hget" => Command::HGet {
+                key: parse.next_string()?,
+                field: parse.next_bytes()?,
+            },
+            "hdel" => Command::HDel {
+                key: parse.next_string()?,
+                fields: parse.remaining_bytes()?,
+            },
+            "hgetall" => Command::HGetAll {
+                key: parse.next_string()?,
+            },
+            "sadd" => Command::SAdd {
+                key: parse.next_string()?,
+                members: parse.remaining_bytes()?,
+            },
+            "srem" => Command::SRem {
+                key: parse.next_string()?,
+                members: parse.remaining_bytes()?,
+            },
+            "smembers" => Command::SMembers {
+                key: parse.next_string()?,
+            },
+            "zadd" => {
+                let key = parse.next_string()?;
+                let mut members = vec![(parse.next_float()?, parse.next_bytes()?)];
+                while parse.has_next() {
+                    members.push((parse.next_float()?, parse.next_bytes()?));
+                }
+                Command::ZAdd { key, members }
+            }
+            "zrange" => Command::ZRange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                stop: parse.next_int()?,
+                with_scores: if parse.has_next() {
+                    if !parse.next_string()?.eq_ignore_ascii_case("withscores") {
+                        return Err(CommandError("syntax error".to_string()));
+                    }
+                    true
+                } else {
+                    false
+                },
+            },
+            "zscore" => Command::ZScore {
+                key: parse.next_string()?,
+                member: parse.next_bytes()?,
+            },
+            "publish" => Command::Publish {
+                channel: parse.next_string()?,
+                message: parse.next_bytes()?,
+            },
+            "subscribe" => {
+                let mut channels = vec![parse.next_string()?];
+                while parse.has_next() {
+                    channels.push(parse.next_string()?);
+                }
+                Command::Subscribe { channels }
+            }
+            "unsubscribe" => {
+                let mut channels = Vec::new();
+                while parse.has_next() {
+                    channels.push(parse.next_string()?);
+                }
+                Command::Unsubscribe { channels }
+            }
+            "ping" => Command::Ping {
+                message: if parse.has_next() {
+                    Some(parse.next_bytes()?)
+                } else {
+                    None
+                },
+            },
+            "save" => Command::Save,
+            "bgsave" => Command::BgSave,
+            "bgrewriteaof" => Command::BgRewriteAof,
+            // the remaining arguments are not checked
+            _ => return Ok(Command::Unknown { name: parse.name }),
+        };
+        parse.finish()?;
+        Ok(command)
+    }
+
+    /// Whether the command may change keys.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Del { .. }
+                | Command::Expire { .. }
+                | Command::Persist { .. }
+                | Command::Push { .. }
+                | Command::Pop { .. }
+                | Command::HSet { .. }
+                | Command::HDel { .. }
+                | Command::SAdd { .. }
+                | Command::SRem { .. }
+                | Command::ZAdd { .. }
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Del { .. } => "del",
+            Command::Expire { .. } => "expire",
+            Command::Ttl { millis: false, .. } => "ttl",
+            Command::Ttl { millis: true, .. } => "pttl",
+            Command::Persist { .. } => "persist",
+            Command::Push {
+                end: ListEnd::Left, ..
+            } => "lpush",
+            Command::Push {
+                end: ListEnd::Right,
+                ..
+            } => "rpush",
+            Command::Pop {
+                end: ListEnd::Left, ..
+            } => "lpop",
+            Command::Pop {
+                end: ListEnd::Right,
+                ..
+            } => "rpop",
+            Command::LRange { .. } => "lrange",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HDel { .. } => "hdel",
+            Command::HGetAll { .. } => "hgetall",
+            Command::SAdd { .. } => "sadd",
+            Command::SRem { .. } => "srem",
+            Command::SMembers { .. } => "smembers",
+            Command::ZAdd { .. } => "zadd",
+            Command::ZRange { .. } => "zrange",
+            Command::ZScore { .. } => "zscore",
+            Command::Publish { .. } => "publish",
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::Ping { .. } => "ping",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+            Command::Unknown { name } => name,
+        }
+    }
+}
+
+impl Command {
+    /// Runs a command on the keys and returns the reply. Commands that
+    /// need the connection are answered with an error.
+    pub fn apply(self, db: &Db) -> Frame {
+        let reply = match self {
+            Command::Get { key } => db.get(&key).map(bulk_or_null),
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => {
+                db.set(key, value, expires_at);
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            Command::Del { keys } => Ok(integer(keys.iter().filter(|key| db.del(key)).count())),
+            Command::Expire { key, expires_at } => {
+                Ok(integer(db.expire(&key, expires_at) as usize))
+            }
+            Command::Ttl { key, millis } => Ok(Frame::Integer(match db.ttl(&key) {
+                None => -2,
+                Some(None) => -1,
+                Some(Some(ttl)) if millis => ttl.as_millis() as i64,
+                // rounded like Redis does
+                Some(Some(ttl)) => ((ttl.as_millis() + 500) / 1000) as i64,
+            })),
+            Command::Persist { key } => Ok(integer(db.persist(&key) as usize)),
+            Command::Push { key, values, end } => db.push(&key, values, end).map(integer),
+            Command::Pop { key, end } => db.pop(&key, end).map(bulk_or_null),
+            Command::LRange { key, start, stop } => {
+                db.lrange(&key, start, stop).map(Frame::bulk_array)
+            }
+            Command::HSet { key, fields } => db.hset(&key, fields).map(integer),
+            Command::HGet { key, field } => db.hget(&key, &field).map(bulk_or_null),
+            Command::HDel { key, fields } => db.hdel(&key, fields).map(integer),
+            Command::HGetAll { key } => db.hgetall(&key).map(|fields| {
+                Frame::bulk_array(fields.into_iter().flat_map(|(field, value)| [field, value]))
+            }),
+            Command::SAdd { key, members } => db.sadd(&key, members).map(integer),
+            Command::SRem { key, members } => db.srem(&key, members).map(integer),
+            Command::SMembers { key } => db.smembers(&key).map(Frame::bulk_array),
+            Command::ZAdd { key, members } => db.zadd(&key, members).map(integer),
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => db.zrange(&key, start, stop).map(|members| {
+                Frame::bulk_array(members.into_iter().flat_map(|(member, score)| {
+                    let score = with_scores.then(|| Bytes::from(score.to_string()));
+                    std::iter::once(member).chain(score)
+                }))
+            }),
+            Command::ZScore { key, member } => db
+                .zscore(&key, &member)
+                .map(|score| bulk_or_null(score.map(|score| Bytes::from(score.to_string())))),
+            Command::Publish { channel, message } => Ok(integer(db.publish(&channel, message))),
+            Command::Ping { message: None } => Ok(Frame::Simple("PONG".to_string())),
+            Command::Ping {
+                message: Some(message),
+            } => Ok(Frame::Bulk(message)),
+            command => Ok(Frame::Error(format!(
+                "ERR unknown command '{}'",
+                command.name()
+            ))),
+        };
+        reply.unwrap_or_else(|error: WrongType| Frame::Error(error.to_string()))
+    }
+}
+
+fn bulk_or_null(value: Option<Bytes>) -> Frame {
+    value.map_or(Frame::Null, Frame::Bulk)
+}
+
+fn integer(value: usize) -> Frame {
+    Frame::Integer(value as i64)
+}
+
+/// When a key expiring after `expire` does so. Like in Redis the deadline
+/// has to fit in an `i64` of Unix milliseconds, which is how it is saved.
+fn deadline(expire: Duration, command: &str) -> Result<Instant, CommandError> {
+    let invalid = || CommandError(format!("invalid expire time in '{}' command", command));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    match now.checked_add(expire) {
+        Some(unix_time) if unix_time.as_millis() <= i64::MAX as u128 => {
+            Instant::now().checked_add(expire).ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Time left until a Unix time, zero if it has passed.
+fn until(unix_time: Duration) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    unix_time.saturating_sub(now)
+}
@@ -0,0 +1,127 @@
+use crate::{rdb, Db};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Saves snapshots of the database to one file, one save at a time.
+#[derive(Clone)]
+pub struct Snapshots {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db: Db,
+    path: PathBuf,
+    saving: AtomicBool,
+    /// `Db::changes` as of the last snapshot written or loaded.
+    saved_changes: AtomicU64,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    InProgress,
+    Io(io::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::InProgress => "Background save already in progress".fmt(f),
+            SaveError::Io(error) => write!(f, "snapshot failed: {}", error),
+        }
+    }
+}
+
+/// Marks a save as running until dropped.
+struct Saving(Arc<Inner>);
+
+impl Drop for Saving {
+    fn drop(&mut self) {
+        self.0.saving.store(false, Ordering::Release);
+    }
+}
+
+impl Snapshots {
+    pub fn new(db: Db, path: PathBuf) -> Snapshots {
+        Snapshots {
+            inner: Arc::new(Inner {
+                db,
+                path,
+                saving: AtomicBool::new(false),
+                saved_changes: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Restores the keys of the snapshot file, if there is one.
+    pub fn load(&self) -> crate::Result<usize> {
+        let keys = rdb::load(&self.inner.db, &self.inner.path)?;
+        self.inner
+            .saved_changes
+            .store(self.inner.db.changes(), Ordering::Relaxed);
+        Ok(keys)
+    }
+
+    /// Writes a snapshot and returns how many keys it holds.
+    pub async fn save(&self) -> Result<usize, SaveError> {
+        let saving = self.start()?;
+        run(saving).await
+    }
+
+    /// Starts a save in the background, its outcome is only logged.
+    pub fn bgsave(&self) -> Result<(), SaveError> {
+        let saving = self.start()?;
+        let snapshots = self.clone();
+        tokio::spawn(async move { log_saved(snapshots.path(), run(saving).await) });
+        Ok(())
+    }
+
+    /// Saves every `interval` as long as keys changed since the last
+    /// snapshot.
+    pub async fn save_periodically(self, interval: Duration) {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            if self.inner.db.changes() != self.inner.saved_changes.load(Ordering::Relaxed) {
+                self.save_logged().await;
+            }
+        }
+    }
+
+    pub async fn save_logged(&self) {
+        log_saved(self.path(), self.save().await)
+    }
+
+    fn start(&self) -> Result<Saving, SaveError> {
+        self.inner
+            .saving
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(|_| SaveError::InProgress)?;
+        Ok(Saving(self.inner.clone()))
+    }
+}
+
+async fn run(saving: Saving) -> Result<usize, SaveError> {
+    let inner = saving.0.clone();
+    let changes = inner.db.changes();
+    let keys = tokio::task::spawn_blocking(move || rdb::save(&inner.db, &inner.path))
+        .await
+        .unwrap()
+        .map_err(SaveError::Io)?;
+    saving.0.saved_changes.store(changes, Ordering::Relaxed);
+    Ok(keys)
+}
+
+fn log_saved(path: &Path, result: Result<usize, SaveError>) {
+    match result {
+        Ok(keys) => println!("saved {} keys to {}", keys, path.display()),
+        Err(error) => println!("saving {} failed: {}", path.display(), error),
+    }
+}
@@ -0,0 +1,152 @@
+//! The values a key can hold.
+
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+/// A command was used on a key holding another type of value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f)
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+impl Value {
+    /// Collections are removed from the database once they are empty.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(set) => set.is_empty(),
+        }
+    }
+}
+
+/// Members ordered by score, then by member.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+/// A score ordered with `f64::total_cmp`. NaN is never stored.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Adds the member or updates its score. Returns whether it is new.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// The members from the lowest score to the highest.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> + '_ {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+}
+
+/// The positions `start..=stop` of a sequence of `len` items, where
+/// negative indexes count from the end, clamped like `LRANGE` does.
+pub fn index_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+#[test]
+fn ranges_count_from_the_end() {
+    assert_eq!(index_range(5, 0, -1), 0..5);
+    assert_eq!(index_range(5, -2, -1), 3..5);
+    assert_eq!(index_range(5, 1, 100), 1..5);
+    assert_eq!(index_range(5, -100, 1), 0..2);
+    assert_eq!(index_range(5, 3, 2), 0..0);
+    assert_eq!(index_range(5, 5, 10), 0..0);
+    assert_eq!(index_range(0, 0, -1), 0..0);
+}
+
+#[test]
+fn sorted_sets_order_by_score_then_member() {
+    let mut set = SortedSet::default();
+    assert!(set.insert(Bytes::from("b"), 2.0));
+    assert!(set.insert(Bytes::from("a"), 2.0));
+    assert!(set.insert(Bytes::from("c"), -1.5));
+    assert!(!set.insert(Bytes::from("c"), 3.0));
+    let members: Vec<_> = set
+        .iter()
+        .map(|(member, score)| (member.clone(), score))
+        .collect();
+    assert_eq!(
+        members,
+        [
+            (Bytes::from("a"), 2.0),
+            (Bytes::from("b"), 2.0),
+            (Bytes::from("c"), 3.0)
+        ]
+    );
+    assert_eq!(set.score(b"c"), Some(3.0));
+    assert_eq!(set.len(), 3);
+}
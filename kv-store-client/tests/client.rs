@@ -0,0 +1,219 @@
+use bytes::Bytes;
+use kv_store_client::client::{Client, Message, Pipeline, Pool, ReplyError};
+use kv_store_client::server::{self, Snapshots};
+use kv_store_client::{Db, Frame};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::StreamExt;
+
+/// Starts a server on a free port, it runs until the test ends.
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let db = Db::new();
+    let path = std::env::temp_dir().join(format!("kv-store-client-{}.rdb", addr.port()));
+    let snapshots = Snapshots::new(db.clone(), path);
+    tokio::spawn(server::run(
+        listener,
+        db,
+        snapshots,
+        None,
+        std::future::pending::<()>(),
+    ));
+    addr
+}
+
+#[tokio::test]
+async fn strings_expire() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    assert_eq!(client.get("a").await.unwrap(), None);
+    client.set("a", "1").await.unwrap();
+    assert_eq!(client.get("a").await.unwrap(), Some(Bytes::from("1")));
+    assert_eq!(client.ttl("a").await.unwrap(), Some(None));
+
+    client
+        .set_expires("b", "2", Duration::from_secs(100))
+        .await
+        .unwrap();
+    let ttl = client.ttl("b").await.unwrap().unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(98) && ttl <= Duration::from_secs(100));
+    assert!(client.persist("b").await.unwrap());
+    assert_eq!(client.ttl("b").await.unwrap(), Some(None));
+
+    assert!(client.expire("a", Duration::from_secs(10)).await.unwrap());
+    assert!(!client.expire("c", Duration::from_secs(10)).await.unwrap());
+    assert_eq!(client.del(&["a", "b", "c"]).await.unwrap(), 2);
+    assert_eq!(client.ttl("a").await.unwrap(), None);
+    assert_eq!(client.ping(None).await.unwrap(), Bytes::from("PONG"));
+
+    // deadlines too far in the future are refused, the key is left alone
+    let mut pipeline = Pipeline::new();
+    pipeline
+        .set("a", "1")
+        .command(["EXPIRE", "a", "9223372036854775807"])
+        .command(["SET", "a", "2", "EX", "9223372036854775807"])
+        .command(["EXPIREAT", "a", "9223372036854775807"])
+        .get("a");
+    let replies = client.execute(&pipeline).await.unwrap();
+    let invalid =
+        |command: &str| Frame::Error(format!("ERR invalid expire time in '{}' command", command));
+    assert_eq!(replies[1], invalid("expire"));
+    assert_eq!(replies[2], invalid("set"));
+    assert_eq!(replies[3], invalid("expireat"));
+    assert_eq!(replies[4], "1");
+    assert_eq!(client.ttl("a").await.unwrap(), Some(None));
+}
+
+#[tokio::test]
+async fn collections_and_error_replies() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    assert_eq!(client.rpush("list", ["b", "c"]).await.unwrap(), 2);
+    assert_eq!(client.lpush("list", ["a"]).await.unwrap(), 3);
+    assert_eq!(client.lrange("list", 0, -1).await.unwrap(), ["a", "b", "c"]);
+    assert_eq!(client.rpop("list").await.unwrap(), Some(Bytes::from("c")));
+
+    assert_eq!(client.hset("hash", [("f", "v")]).await.unwrap(), 1);
+    assert_eq!(
+        client.hget("hash", "f").await.unwrap(),
+        Some(Bytes::from("v"))
+    );
+    assert_eq!(
+        client.hgetall("hash").await.unwrap(),
+        [(Bytes::from("f"), Bytes::from("v"))]
+    );
+    assert_eq!(client.hdel("hash", ["f", "g"]).await.unwrap(), 1);
+
+    assert_eq!(client.sadd("set", ["x", "y"]).await.unwrap(), 2);
+    assert_eq!(client.srem("set", ["x"]).await.unwrap(), 1);
+    assert_eq!(client.smembers("set").await.unwrap(), ["y"]);
+
+    let members = [(2.0, "b"), (-1.5, "a")];
+    assert_eq!(client.zadd("zset", members).await.unwrap(), 2);
+    assert_eq!(client.zrange("zset", 0, -1).await.unwrap(), ["a", "b"]);
+    assert_eq!(
+        client.zrange_withscores("zset", 0, 0).await.unwrap(),
+        [(Bytes::from("a"), -1.5)]
+    );
+    assert_eq!(client.zscore("zset", "b").await.unwrap(), Some(2.0));
+    assert_eq!(client.zscore("zset", "c").await.unwrap(), None);
+
+    // an error reply leaves the connection usable
+    let error = client.get("list").await.unwrap_err();
+    let error = error.downcast::<ReplyError>().unwrap();
+    assert!(error.message().starts_with("WRONGTYPE"), "{}", error);
+    assert!(!client.is_broken());
+    assert_eq!(client.lpop("list").await.unwrap(), Some(Bytes::from("a")));
+}
+
+#[tokio::test]
+async fn pipelines_return_every_reply() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    let mut pipeline = Pipeline::new();
+    pipeline
+        .set("a", "1")
+        .lpush("a", ["x"])
+        .rpush("list", ["x", "y"])
+        .get("a")
+        .command(["LRANGE", "list", "0", "-1"]);
+    assert_eq!(pipeline.len(), 5);
+    let replies = client.execute(&pipeline).await.unwrap();
+    assert_eq!(replies.len(), 5);
+    assert_eq!(replies[0], "OK");
+    assert!(matches!(&replies[1], Frame::Error(message) if message.starts_with("WRONGTYPE")));
+    assert_eq!(replies[2], Frame::Integer(2));
+    assert_eq!(replies[3], "1");
+    assert_eq!(replies[4], Frame::bulk_array(["x", "y"]));
+
+    assert!(client.execute(&Pipeline::new()).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn pools_reuse_connections() {
+    let pool = Pool::new(start_server().await.to_string(), 2);
+
+    let tasks: Vec<_> = (0..10)
+        .map(|i| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let mut client = pool.get().await.unwrap();
+                client.rpush("list", [i.to_string()]).await.unwrap();
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(pool.idle(), 2);
+
+    let mut client = pool.get().await.unwrap();
+    assert_eq!(client.lrange("list", 0, -1).await.unwrap().len(), 10);
+    assert_eq!(pool.idle(), 1);
+
+    // a call cancelled before its reply arrived breaks the connection, it
+    // is not handed out again
+    let call = client.lrange("list", 0, -1);
+    drop(call);
+    assert!(!client.is_broken());
+    tokio::select! {
+        biased;
+        _ = client.lrange("list", 0, -1) => panic!("the reply cannot have arrived yet"),
+        _ = std::future::ready(()) => {}
+    }
+    assert!(client.is_broken());
+    assert!(client.get("a").await.is_err());
+    drop(client);
+    assert_eq!(pool.idle(), 1);
+}
+
+#[tokio::test]
+async fn subscribers_receive_messages() {
+    let addr = start_server().await;
+    let mut publisher = Client::connect(addr).await.unwrap();
+    let mut subscriber = Client::connect(addr)
+        .await
+        .unwrap()
+        .subscribe(&["news"])
+        .await
+        .unwrap();
+
+    assert_eq!(publisher.publish("news", "first").await.unwrap(), 1);
+    subscriber.subscribe(&["sports"]).await.unwrap();
+    assert_eq!(subscriber.channels(), ["news", "sports"]);
+    assert_eq!(publisher.publish("sports", "second").await.unwrap(), 1);
+    subscriber.unsubscribe(&["news"]).await.unwrap();
+    assert_eq!(publisher.publish("news", "dropped").await.unwrap(), 0);
+    assert_eq!(publisher.publish("sports", "third").await.unwrap(), 1);
+
+    let message = |channel: &str, content: &'static str| Message {
+        channel: channel.to_string(),
+        content: Bytes::from(content),
+    };
+    assert_eq!(
+        subscriber.next_message().await.unwrap(),
+        Some(message("news", "first"))
+    );
+    let mut messages = Box::pin(subscriber.into_stream());
+    assert_eq!(
+        messages.next().await.unwrap().unwrap(),
+        message("sports", "second")
+    );
+    assert_eq!(
+        messages.next().await.unwrap().unwrap(),
+        message("sports", "third")
+    );
+
+    // unsubscribing from every channel ends the messages
+    let mut subscriber = Client::connect(addr)
+        .await
+        .unwrap()
+        .subscribe(&["a", "b"])
+        .await
+        .unwrap();
+    subscriber.unsubscribe(&[]).await.unwrap();
+    assert!(subscriber.channels().is_empty());
+    assert_eq!(subscriber.next_message().await.unwrap(), None);
+}
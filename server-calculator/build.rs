@@ -1,4 +1,9 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/calculator_service.proto")?;
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("calculator_service_descriptor.bin"))
+        .compile(&["proto/calculator_service.proto"], &["proto"])?;
     Ok(())
 }
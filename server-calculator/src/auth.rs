@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Metadata key carrying `Bearer <token>`.
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+pub fn bearer_token(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get(AUTHORIZATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of a token was guessed right.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (l, r)| difference | (l ^ r))
+            == 0
+}
+
+/// Rejects requests whose bearer token is not in the configured list. An
+/// empty list disables authentication.
+#[derive(Debug, Clone, Default)]
+pub struct AuthInterceptor {
+    tokens: Arc<Vec<String>>,
+}
+
+impl AuthInterceptor {
+    pub fn new(tokens: Vec<String>) -> Self {
+        AuthInterceptor {
+            tokens: Arc::new(tokens),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub(crate) fn accepts(&self, token: &str) -> bool {
+        self.tokens.iter().fold(false, |accepted, expected| {
+            constant_time_eq(expected.as_bytes(), token.as_bytes()) | accepted
+        })
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if !self.is_enabled() {
+            return Ok(request);
+        }
+        match bearer_token(request.metadata()) {
+            Some(token) if self.accepts(token) => Ok(request),
+            Some(_) => Err(Status::unauthenticated("invalid bearer token")),
+            None => Err(Status::unauthenticated("missing bearer token")),
+        }
+    }
+}
+
+#[test]
+fn bearer_tokens_are_checked() {
+    let mut interceptor = AuthInterceptor::new(vec!["first".into(), "second".into()]);
+    let request = |authorization: Option<&str>| {
+        let mut request = Request::new(());
+        if let Some(value) = authorization {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, value.parse().unwrap());
+        }
+        request
+    };
+
+    assert!(interceptor.call(request(Some("Bearer second"))).is_ok());
+    for rejected in [None, Some("Bearer third"), Some("Bearer "), Some("second")] {
+        let status = interceptor.call(request(rejected)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+    assert!(AuthInterceptor::default().call(request(None)).is_ok());
+}
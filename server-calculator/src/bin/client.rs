@@ -1,98 +1,799 @@
-pub mod calculator_service {
-    tonic::include_proto!("calculator_service");
-}
-
-use calculator_service::calculator_service_client::CalculatorServiceClient;
-use calculator_service::CalcInput;
+use clap::Parser;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use server_calculator::calculator_service::{
+    calc_request, calc_response, evaluation_result, CalcInput, CalcRequest, ErrorCode,
+    ExpressionRequest, MemoryOperation, MemoryRequest, UnaryInput,
+};
+use server_calculator::client::CalculatorClient;
+use server_calculator::config::ClientArgs;
+use server_calculator::expression::{self, Statement};
+use server_calculator::number::{Mode, DEFAULT_DECIMAL_SCALE};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::stdin;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 #[derive(Debug)]
 struct ResultError {
     operation: Operation,
+    code: ErrorCode,
+    reason: String,
 }
 
 impl Display for ResultError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self.operation {
-            Operation::Add(a, b) => write!(f, "Failed to compute: a={}, b={}, op=+", a, b),
-            Operation::Sub(a, b) => write!(f, "Failed to compute: a={}, b={}, op=-", a, b),
-            Operation::Mul(a, b) => write!(f, "Failed to compute: a={}, b={}, op=*", a, b),
-            Operation::Div(a, b) => write!(f, "Failed to compute: a={}, b={}, op=/", a, b),
+        match &self.operation {
+            Operation::Add(a, b) => write!(f, "Failed to compute: a={}, b={}, op=+", a, b)?,
+            Operation::Sub(a, b) => write!(f, "Failed to compute: a={}, b={}, op=-", a, b)?,
+            Operation::Mul(a, b) => write!(f, "Failed to compute: a={}, b={}, op=*", a, b)?,
+            Operation::Div(a, b) => write!(f, "Failed to compute: a={}, b={}, op=/", a, b)?,
+            Operation::Pow(a, b) => write!(f, "Failed to compute: a={}, b={}, op=^", a, b)?,
+            Operation::Mod(a, b) => write!(f, "Failed to compute: a={}, b={}, op=%", a, b)?,
+            Operation::Gcd(a, b) => write!(f, "Failed to compute: gcd {} {}", a, b)?,
+            Operation::Lcm(a, b) => write!(f, "Failed to compute: lcm {} {}", a, b)?,
+            Operation::Sqrt(a) => write!(f, "Failed to compute: sqrt {}", a)?,
+            Operation::Factorial(a) => write!(f, "Failed to compute: factorial {}", a)?,
+            Operation::Abs(a) => write!(f, "Failed to compute: abs {}", a)?,
+            Operation::Evaluate(expression) => write!(f, "Failed to compute: {}", expression)?,
         }
+        write!(f, " ({:?}: {})", self.code, self.reason)
     }
 }
 impl Error for ResultError {}
 
 #[derive(Debug)]
 enum Operation {
-    Add(i64, i64),
-    Sub(i64, i64),
-    Mul(i64, i64),
-    Div(i64, i64),
+    Add(String, String),
+    Sub(String, String),
+    Mul(String, String),
+    Div(String, String),
+    Pow(String, String),
+    Mod(String, String),
+    Gcd(String, String),
+    Lcm(String, String),
+    Sqrt(String),
+    Factorial(String),
+    Abs(String),
+    Evaluate(String),
 }
 
-fn parse_input(input: String) -> Result<Operation, Box<dyn Error>> {
-    let mut tokens = input.split(' ');
-    let left = tokens.next().unwrap_or("0").trim().parse::<i64>()?;
-    let op = tokens.next().unwrap_or("+").trim();
-    let right = tokens.next().unwrap_or("0").trim().parse::<i64>()?;
+fn is_number(token: &str) -> bool {
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+fn parse_binary(input: &str) -> Option<Operation> {
+    let tokens = input.split_whitespace().collect::<Vec<_>>();
+    let (left, op, right) = match tokens[..] {
+        [left, op, right] if is_number(left) && is_number(right) => {
+            (left.to_string(), op, right.to_string())
+        }
+        _ => return None,
+    };
 
     let operation = match op {
         "+" => Operation::Add(left, right),
         "-" => Operation::Sub(left, right),
         "*" => Operation::Mul(left, right),
         "/" => Operation::Div(left, right),
-        _ => panic!("Error while parsing input."),
+        "^" => Operation::Pow(left, right),
+        "%" => Operation::Mod(left, right),
+        _ => return None,
     };
-    Ok(operation)
+    Some(operation)
+}
+
+/// Parses function syntax without parentheses, e.g. `sqrt 16` or
+/// `gcd 12 18`.
+fn parse_function(input: &str) -> Option<Operation> {
+    let tokens = input.split_whitespace().collect::<Vec<_>>();
+    if !tokens.iter().skip(1).all(|token| is_number(token)) {
+        return None;
+    }
+
+    let operation = match tokens[..] {
+        ["sqrt", a] => Operation::Sqrt(a.to_string()),
+        ["factorial", a] => Operation::Factorial(a.to_string()),
+        ["abs", a] => Operation::Abs(a.to_string()),
+        ["gcd", a, b] => Operation::Gcd(a.to_string(), b.to_string()),
+        ["lcm", a, b] => Operation::Lcm(a.to_string(), b.to_string()),
+        _ => return None,
+    };
+    Some(operation)
+}
+
+/// Simple `a op b` and `function a` input maps onto the dedicated RPCs,
+/// anything else is sent to the server as a whole expression.
+fn parse_input(input: String) -> Operation {
+    parse_binary(&input)
+        .or_else(|| parse_function(&input))
+        .unwrap_or(Operation::Evaluate(input))
+}
+
+/// Parses the arguments of the `:mode` command, e.g. `decimal 4`.
+fn parse_mode(args: &[&str]) -> Result<Mode, String> {
+    let mode = match args {
+        ["integer"] | ["int"] => Mode::Integer,
+        ["big"] | ["bigint"] => Mode::BigInteger,
+        ["rational"] => Mode::Rational,
+        ["decimal"] => Mode::Decimal {
+            scale: DEFAULT_DECIMAL_SCALE,
+        },
+        ["decimal", scale] => Mode::Decimal {
+            scale: scale
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid scale: {}", scale))?,
+        },
+        ["float"] => Mode::Float,
+        _ => return Err("Usage: :mode integer|big|rational|decimal [scale]|float".to_string()),
+    };
+    Ok(mode)
+}
+
+fn calc_input(a: &str, b: &str, mode: Mode) -> CalcInput {
+    let (mode, scale) = mode.to_proto();
+    CalcInput {
+        a: a.to_string(),
+        b: b.to_string(),
+        mode: mode as i32,
+        scale,
+    }
+}
+
+fn unary_input(a: &str, mode: Mode) -> UnaryInput {
+    let (mode, scale) = mode.to_proto();
+    UnaryInput {
+        a: a.to_string(),
+        mode: mode as i32,
+        scale,
+    }
+}
+
+fn expression_request(expression: &str, mode: Mode) -> ExpressionRequest {
+    let (mode, scale) = mode.to_proto();
+    ExpressionRequest {
+        expression: expression.to_string(),
+        mode: mode as i32,
+        scale,
+    }
+}
+
+impl Operation {
+    fn to_batch(&self, mode: Mode) -> calc_request::Operation {
+        match self {
+            Operation::Add(a, b) => calc_request::Operation::Add(calc_input(a, b, mode)),
+            Operation::Sub(a, b) => calc_request::Operation::Sub(calc_input(a, b, mode)),
+            Operation::Mul(a, b) => calc_request::Operation::Mul(calc_input(a, b, mode)),
+            Operation::Div(a, b) => calc_request::Operation::Div(calc_input(a, b, mode)),
+            Operation::Pow(a, b) => calc_request::Operation::Pow(calc_input(a, b, mode)),
+            Operation::Mod(a, b) => calc_request::Operation::Mod(calc_input(a, b, mode)),
+            Operation::Gcd(a, b) => calc_request::Operation::Gcd(calc_input(a, b, mode)),
+            Operation::Lcm(a, b) => calc_request::Operation::Lcm(calc_input(a, b, mode)),
+            Operation::Sqrt(a) => calc_request::Operation::Sqrt(unary_input(a, mode)),
+            Operation::Factorial(a) => calc_request::Operation::Factorial(unary_input(a, mode)),
+            Operation::Abs(a) => calc_request::Operation::Abs(unary_input(a, mode)),
+            Operation::Evaluate(expression) => {
+                calc_request::Operation::Evaluate(expression_request(expression, mode))
+            }
+        }
+    }
 }
 
 async fn get_result(
-    client: &mut CalculatorServiceClient<tonic::transport::Channel>,
+    client: &mut CalculatorClient,
     operation: Operation,
-) -> Result<i64, Box<dyn Error>> {
+    mode: Mode,
+) -> Result<String, Box<dyn Error>> {
     let response = match operation {
-        Operation::Add(a, b) => client.add(tonic::Request::new(CalcInput { a, b })).await?,
-        Operation::Sub(a, b) => client.sub(tonic::Request::new(CalcInput { a, b })).await?,
-        Operation::Mul(a, b) => client.mul(tonic::Request::new(CalcInput { a, b })).await?,
-        Operation::Div(a, b) => client.div(tonic::Request::new(CalcInput { a, b })).await?,
+        Operation::Add(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.add(request).await
+                })
+                .await?
+        }
+        Operation::Sub(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.sub(request).await
+                })
+                .await?
+        }
+        Operation::Mul(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.mul(request).await
+                })
+                .await?
+        }
+        Operation::Div(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.div(request).await
+                })
+                .await?
+        }
+        Operation::Pow(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.pow(request).await
+                })
+                .await?
+        }
+        Operation::Mod(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.r#mod(request).await
+                })
+                .await?
+        }
+        Operation::Gcd(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.gcd(request).await
+                })
+                .await?
+        }
+        Operation::Lcm(ref a, ref b) => {
+            let input = calc_input(a, b, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.lcm(request).await
+                })
+                .await?
+        }
+        Operation::Sqrt(ref a) => {
+            let input = unary_input(a, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.sqrt(request).await
+                })
+                .await?
+        }
+        Operation::Factorial(ref a) => {
+            let input = unary_input(a, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.factorial(request).await
+                })
+                .await?
+        }
+        Operation::Abs(ref a) => {
+            let input = unary_input(a, mode);
+            client
+                .call(true, input, |mut client, request| async move {
+                    client.abs(request).await
+                })
+                .await?
+        }
+        Operation::Evaluate(ref expression) => {
+            let request = expression_request(expression, mode);
+            // assignments change the session, repeating them is not safe
+            let idempotent = !matches!(
+                expression::parse(expression),
+                Ok(Statement::Assignment { .. })
+            );
+            let response = client
+                .call(idempotent, request, |mut client, request| async move {
+                    client.evaluate(request).await
+                })
+                .await?;
+            return match response.into_inner().outcome {
+                Some(evaluation_result::Outcome::Value(value)) => Ok(value),
+                Some(evaluation_result::Outcome::Error(error)) => Err(Box::new(ResultError {
+                    code: ErrorCode::from_i32(error.code).unwrap_or(ErrorCode::InvalidInput),
+                    reason: format!("{} at position {}", error.message, error.position),
+                    operation,
+                })),
+                None => Err("Server returned an empty evaluation result".into()),
+            };
+        }
+    };
+
+    let data = response.into_inner();
+    match ErrorCode::from_i32(data.error) {
+        Some(ErrorCode::NoError) => Ok(data.result),
+        code => Err(Box::new(ResultError {
+            operation,
+            code: code.unwrap_or(ErrorCode::InvalidInput),
+            reason: data.message,
+        })),
+    }
+}
+
+/// Sends every non-empty line of the file through a single `BatchCompute`
+/// stream and prints the results in the order they arrive. Lines starting
+/// with `#` are comments.
+async fn run_batch(
+    client: &CalculatorClient,
+    path: &Path,
+    mode: Mode,
+) -> Result<usize, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let lines = contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index as u64 + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    let requests = lines
+        .iter()
+        .map(|&(id, line)| CalcRequest {
+            id,
+            operation: Some(parse_input(line.to_string()).to_batch(mode)),
+        })
+        .collect::<Vec<_>>();
+
+    let mut responses = client
+        .client()
+        .batch_compute(tokio_stream::iter(requests))
+        .await?
+        .into_inner();
+
+    let lines = lines.into_iter().collect::<HashMap<_, _>>();
+    let mut failures = 0;
+    while let Some(response) = responses.message().await? {
+        let line = lines.get(&response.id).copied().unwrap_or_default();
+        match response.outcome {
+            Some(calc_response::Outcome::Value(value)) => {
+                println!("{}: {} = {}", response.id, line, value)
+            }
+            Some(calc_response::Outcome::Error(error)) => {
+                failures += 1;
+                println!(
+                    "{}: {} failed ({:?}: {})",
+                    response.id,
+                    line,
+                    ErrorCode::from_i32(error.code).unwrap_or(ErrorCode::InvalidInput),
+                    error.message
+                );
+            }
+            None => failures += 1,
+        }
+    }
+    Ok(failures)
+}
+
+fn parse_memory(input: &str) -> Option<MemoryOperation> {
+    match input.to_uppercase().as_str() {
+        "M+" => Some(MemoryOperation::MemoryAdd),
+        "M-" => Some(MemoryOperation::MemorySubtract),
+        "MR" => Some(MemoryOperation::MemoryRecall),
+        "MC" => Some(MemoryOperation::MemoryClear),
+        _ => None,
+    }
+}
+
+async fn use_memory(
+    client: &mut CalculatorClient,
+    operation: MemoryOperation,
+    mode: Mode,
+) -> Result<String, Box<dyn Error>> {
+    let (number_mode, scale) = mode.to_proto();
+    let request = MemoryRequest {
+        operation: operation as i32,
+        mode: number_mode as i32,
+        scale,
     };
+    let idempotent = matches!(
+        operation,
+        MemoryOperation::MemoryRecall | MemoryOperation::MemoryClear
+    );
+    let response = client
+        .call(idempotent, request, |mut client, request| async move {
+            client.memory(request).await
+        })
+        .await?;
+    match response.into_inner().outcome {
+        Some(evaluation_result::Outcome::Value(value)) => Ok(value),
+        Some(evaluation_result::Outcome::Error(error)) => {
+            Err(format!("Memory error: {}", error.message).into())
+        }
+        None => Err("Server returned an empty memory result".into()),
+    }
+}
+
+const HELP: &str = "\
+Enter an expression to compute it on the server, e.g.
+  1 + 2 * 3        x = 7 / 2        2 ^ 10 % 7
+  sqrt 16          factorial 5      gcd 12 18
+  sqrt(x) + abs(-3)
+
+Commands:
+  :mode [integer|big|rational|decimal [scale]|float]   show or change the number mode
+  M+, M-, MR, MC                                       use the memory register
+  :help                                                show this help
+  :quit, q                                             exit";
+
+const COMMANDS: &[&str] = &[":help", ":mode", ":quit"];
+const MODES: &[&str] = &["integer", "big", "rational", "decimal", "float"];
+const WORDS: &[&str] = &[
+    "sqrt",
+    "factorial",
+    "abs",
+    "gcd",
+    "lcm",
+    "M+",
+    "M-",
+    "MR",
+    "MC",
+];
+
+/// Completes commands at the start of the line, modes after `:mode` and
+/// function names and memory operations anywhere else.
+#[derive(Default)]
+struct ReplHelper;
+
+fn complete_word(line: &str, pos: usize) -> (usize, Vec<String>) {
+    let line = &line[..pos];
+    let start = line
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| c.is_whitespace() || c == '(' || c == ',')
+        .map_or(0, |(index, c)| index + c.len_utf8());
+    let (before, word) = line.split_at(start);
+
+    let candidates: &[&[&str]] = match before.trim() {
+        "" => &[COMMANDS, WORDS],
+        ":mode" => &[MODES],
+        _ => &[WORDS],
+    };
+    let word = word.to_lowercase();
+    let matches = candidates
+        .iter()
+        .flat_map(|candidates| candidates.iter())
+        .filter(|candidate| candidate.to_lowercase().starts_with(&word))
+        .map(|candidate| candidate.to_string())
+        .collect();
+    (start, matches)
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
 
-    let data = response.get_ref();
-    if data.error {
-        Err(Box::new(ResultError { operation }))
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete_word(line, pos))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// What a line of input produced.
+enum Reply {
+    Value(String),
+    Message(String),
+    Quit,
+}
+
+/// Runs a single line of input, either a command or a calculation.
+async fn execute(
+    client: &mut CalculatorClient,
+    mode: &mut Mode,
+    input: &str,
+) -> Result<Reply, Box<dyn Error>> {
+    match input {
+        "q" | "Q" | ":q" | ":quit" => return Ok(Reply::Quit),
+        ":help" | ":h" | "?" => return Ok(Reply::Message(HELP.to_string())),
+        _ => {}
+    }
+
+    if let Some(args) = input.strip_prefix(":mode") {
+        let args = args.split_whitespace().collect::<Vec<_>>();
+        if !args.is_empty() {
+            *mode = parse_mode(&args)?;
+        }
+        return Ok(Reply::Message(format!("Mode = {:?}", mode)));
+    }
+    if input.starts_with(':') {
+        return Err(format!("Unknown command {}, try :help", input).into());
+    }
+
+    if let Some(operation) = parse_memory(input) {
+        let value = use_memory(client, operation, *mode).await?;
+        return Ok(Reply::Message(format!("Memory = {}", value)));
+    }
+
+    let value = get_result(client, parse_input(input.to_string()), *mode).await?;
+    Ok(Reply::Value(value))
+}
+
+/// Input history is kept across runs in this file.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".calculator_history"))
+}
+
+/// Tells how often the last call had to be retried, in verbose mode.
+fn report_retries(client: &CalculatorClient, verbose: bool) {
+    if verbose && client.retries() > 0 {
+        eprintln!("(retried {} times)", client.retries());
+    }
+}
+
+async fn run_repl(client: &mut CalculatorClient, mut mode: Mode, verbose: bool) {
+    let mut editor = Editor::<ReplHelper>::new();
+    editor.set_helper(Some(ReplHelper));
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history on the first run
+        let _ = editor.load_history(path);
+    }
+    println!("Enter an expression, :help for help or :quit to exit");
+
+    loop {
+        let input = match editor.readline("> ") {
+            Ok(input) => input,
+            // Ctrl-C only abandons the current line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Failed to read input: {}", error);
+                break;
+            }
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input);
+
+        let reply = execute(client, &mut mode, input).await;
+        report_retries(client, verbose);
+        match reply {
+            Ok(Reply::Value(value)) => println!("Result = {}", value),
+            Ok(Reply::Message(message)) => println!("{}", message),
+            Ok(Reply::Quit) => break,
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(error) = editor.save_history(path) {
+            eprintln!("Failed to save history to {}: {}", path.display(), error);
+        }
+    }
+}
+
+/// Runs each line without prompting, printing results to stdout and
+/// errors to stderr. Blank lines and lines starting with `#` are skipped.
+/// Returns the number of lines that failed.
+async fn run_script(
+    client: &mut CalculatorClient,
+    mut mode: Mode,
+    lines: &[String],
+    verbose: bool,
+) -> usize {
+    let mut failures = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let reply = execute(client, &mut mode, line).await;
+        report_retries(client, verbose);
+        match reply {
+            Ok(Reply::Value(value)) => println!("{}", value),
+            Ok(Reply::Message(message)) => println!("{}", message),
+            Ok(Reply::Quit) => break,
+            Err(error) => {
+                failures += 1;
+                eprintln!("line {}: {}", index + 1, error);
+            }
+        }
+    }
+    failures
+}
+
+/// Scripts and batches exit unsuccessfully if any line failed.
+fn exit_status(failures: usize) -> i32 {
+    if failures == 0 {
+        0
     } else {
-        Ok(data.result)
+        1
     }
 }
 
-fn get_input() -> String {
-    let mut input_string = String::new();
-    let _ = match stdin().read_line(&mut input_string) {
-        Ok(_) => {}
-        Err(_) => return "".to_string(),
+/// Reads a script from a file, or from stdin for `-`.
+fn read_script(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let contents = if path == Path::new("-") {
+        std::io::read_to_string(stdin())?
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|error| format!("Failed to read {}: {}", path.display(), error))?
     };
-    input_string.trim().to_string()
+    Ok(contents.lines().map(str::to_string).collect())
+}
+
+#[derive(Parser)]
+#[clap(author, version, about = "Calculator gRPC client", long_about = None)]
+struct Args {
+    #[clap(flatten)]
+    connection: ClientArgs,
+
+    /// Send every line of the file through a single batch stream and exit
+    #[clap(long, value_parser, conflicts_with_all = &["eval", "file"])]
+    batch: Option<PathBuf>,
+
+    /// Compute an expression or run a command and exit, can be repeated
+    #[clap(short, long = "eval", value_parser)]
+    eval: Vec<String>,
+
+    /// Run every line of the file, `-` for stdin, and exit
+    #[clap(short, long, value_parser, conflicts_with = "eval")]
+    file: Option<PathBuf>,
+
+    /// Report retried calls on stderr
+    #[clap(short, long)]
+    verbose: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let mut client = CalculatorServiceClient::connect("http://localhost:1234").await?;
+    let args = Args::parse();
+    let config = args.connection.resolve()?;
 
-    loop {
-        println!("Enter simple expression (q to exit):");
-        let input = get_input();
-        if input.eq("q") || input.eq("Q") {
-            exit(0)
+    let mut client = CalculatorClient::connect(&config).await?;
+    report_retries(&client, args.verbose);
+    let mode = Mode::default();
+
+    if let Some(path) = args.batch {
+        let failures = run_batch(&client, &path, mode).await?;
+        exit(exit_status(failures))
+    }
+
+    let script = match args.file {
+        Some(path) => Some(read_script(&path)?),
+        None if !args.eval.is_empty() => Some(args.eval),
+        None => None,
+    };
+    match script {
+        Some(lines) => {
+            let failures = run_script(&mut client, mode, &lines, args.verbose).await;
+            exit(exit_status(failures))
         }
+        None => run_repl(&mut client, mode, args.verbose).await,
+    }
+    Ok(())
+}
+
+#[test]
+fn completion_candidates() {
+    assert_eq!(complete_word(":m", 2), (0, vec![":mode".to_string()]));
+    assert_eq!(
+        complete_word(":mode d", 7),
+        (6, vec!["decimal".to_string()])
+    );
+    assert_eq!(
+        complete_word("1 + sqrt(fa", 11),
+        (9, vec!["factorial".to_string()])
+    );
+    assert_eq!(
+        complete_word("m", 1),
+        (0, ["M+", "M-", "MR", "MC"].map(String::from).to_vec())
+    );
+    assert_eq!(complete_word("2 + x", 5), (4, Vec::new()));
+    // a separator longer than one byte
+    assert_eq!(
+        complete_word("1 +\u{a0}sq", 7),
+        (5, vec!["sqrt".to_string()])
+    );
+}
+
+#[test]
+fn inputs_map_onto_operations() {
+    let parse = |input: &str| parse_input(input.to_string());
+    assert!(matches!(parse("1 + 2"), Operation::Add(a, b) if a == "1" && b == "2"));
+    assert!(matches!(parse("-1.5 - -2"), Operation::Sub(a, b) if a == "-1.5" && b == "-2"));
+    assert!(matches!(parse("2 ^ 10"), Operation::Pow(..)));
+    assert!(matches!(parse("sqrt 16"), Operation::Sqrt(a) if a == "16"));
+    assert!(matches!(parse("gcd 12 18"), Operation::Gcd(a, b) if a == "12" && b == "18"));
+    for expression in ["1 + 2 * 3", "x + 1", "sqrt(16)", "gcd 12", "x = 2", "1 $ 2"] {
+        assert!(
+            matches!(parse(expression), Operation::Evaluate(ref e) if e == expression),
+            "{}",
+            expression
+        );
+    }
 
-        let parsed = parse_input(input).expect("Failed to parse input.");
+    assert_eq!(parse_mode(&["big"]), Ok(Mode::BigInteger));
+    assert_eq!(
+        parse_mode(&["decimal", "4"]),
+        Ok(Mode::Decimal { scale: 4 })
+    );
+    assert_eq!(
+        parse_mode(&["decimal", "x"]),
+        Err("Invalid scale: x".to_string())
+    );
+    assert!(parse_mode(&["octal"]).is_err());
+}
 
-        let result = get_result(&mut client, parsed).await?;
+#[tokio::test]
+async fn commands_and_errors_do_not_end_the_session() {
+    let server = server_calculator::testing::TestServer::start().await;
+    let mut client = CalculatorClient::connect(&server.client_config())
+        .await
+        .unwrap();
+    let mut mode = Mode::default();
 
-        println!("Result = {}", result);
+    for quit in ["q", ":q", ":quit"] {
+        let reply = execute(&mut client, &mut mode, quit).await.unwrap();
+        assert!(matches!(reply, Reply::Quit));
     }
+    let reply = execute(&mut client, &mut mode, ":help").await.unwrap();
+    assert!(matches!(reply, Reply::Message(message) if message == HELP));
+    let error = execute(&mut client, &mut mode, ":bogus")
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.to_string(), "Unknown command :bogus, try :help");
+    assert!(execute(&mut client, &mut mode, ":mode octal")
+        .await
+        .is_err());
+    assert_eq!(mode, Mode::Integer);
+
+    let reply = execute(&mut client, &mut mode, ":mode rational")
+        .await
+        .unwrap();
+    assert!(matches!(reply, Reply::Message(message) if message == "Mode = Rational"));
+    let error = execute(&mut client, &mut mode, "1 / 0")
+        .await
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("DivisionByZero"), "{}", error);
+    let reply = execute(&mut client, &mut mode, "x = 7 / 2").await.unwrap();
+    assert!(matches!(reply, Reply::Value(value) if value == "7/2"));
+    let reply = execute(&mut client, &mut mode, "x * 2").await.unwrap();
+    assert!(matches!(reply, Reply::Value(value) if value == "7"));
+}
+
+#[tokio::test]
+async fn scripts_count_failed_lines() {
+    let server = server_calculator::testing::TestServer::start().await;
+    let mut client = CalculatorClient::connect(&server.client_config())
+        .await
+        .unwrap();
+    let script = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let lines = script(&["1 + 1", "", "# a comment", ":mode float", "1 / 4"]);
+    let failures = run_script(&mut client, Mode::default(), &lines, false).await;
+    assert_eq!(failures, 0);
+    assert_eq!(exit_status(failures), 0);
+
+    // failing lines do not stop the script, quitting does
+    let lines = script(&["1 / 0", ":bogus", "2 * 3", "1 +", ":quit", "1 / 0"]);
+    let failures = run_script(&mut client, Mode::default(), &lines, false).await;
+    assert_eq!(failures, 3);
+    assert_eq!(exit_status(failures), 1);
 }
@@ -1,107 +1,173 @@
-use std::task::{Context, Poll};
-use tonic::codegen::Service;
-use tonic::{transport::Server, Request, Response, Status};
-use tower::layer::Layer;
+use clap::Parser;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
+
+use server_calculator::auth::AuthInterceptor;
+use server_calculator::cache::ResultCache;
+use server_calculator::calculator_service::calculator_service_server::CalculatorServiceServer;
+use server_calculator::calculator_service::FILE_DESCRIPTOR_SET;
+use server_calculator::config::ServerArgs;
+use server_calculator::gateway::{self, Gateway};
+use server_calculator::limit::LimitLayer;
+use server_calculator::logging::{self, LogLayer};
+use server_calculator::metrics::{self, MetricsLayer};
+use server_calculator::service::{self, Calculator};
+use server_calculator::session::SessionStore;
+
+/// How often idle sessions are purged in the background.
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long in-flight requests get to finish once shutdown has started.
+/// Health `Watch` streams never end on their own, so it can't be forever.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Methods of the health and reflection services served next to the
+/// calculator.
+const STANDARD_METHODS: &[&str] = &[
+    "/grpc.health.v1.Health/Check",
+    "/grpc.health.v1.Health/Watch",
+    "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+];
+/// How often rate limit state of idle clients is dropped.
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Resolves on SIGINT or SIGTERM, after the health status has been switched
+/// to NOT_SERVING for the grace period. The server then stops accepting
+/// connections and waits for in-flight requests to finish.
+async fn shutdown_signal(mut health: HealthReporter, grace: Duration) {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 
-pub mod calculator_service {
-    // name of the grpc package
-    tonic::include_proto!("calculator_service");
+    // give health checkers a chance to notice before connections are refused
+    health
+        .set_not_serving::<CalculatorServiceServer<Calculator>>()
+        .await;
+    tracing::info!(
+        grace_secs = grace.as_secs(),
+        "shutting down, reported NOT_SERVING"
+    );
+    tokio::time::sleep(grace).await;
+    tracing::info!("draining in-flight requests");
 }
 
-use calculator_service::calculator_service_server::{CalculatorService, CalculatorServiceServer};
-use calculator_service::{CalcInput, CalcOutput};
-
-#[derive(Default, Clone)]
-struct Calculator;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ServerArgs::parse().resolve()?;
+    logging::init(config.log_format);
 
-#[tonic::async_trait]
-impl CalculatorService for Calculator {
-    async fn add(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
-        let result = request.get_ref().a + request.get_ref().b;
-        Ok(Response::new(CalcOutput {
-            result,
-            error: false,
-        }))
+    let sessions = SessionStore::default().with_limits(&config.sessions);
+    let mut calculator = Calculator::new(sessions);
+    if let Some(size) = config.cache.size {
+        calculator = calculator.with_cache(ResultCache::new(size, config.cache.ttl()));
     }
+    let expiry = calculator.sessions().spawn_expiry(SESSION_EXPIRY_INTERVAL);
 
-    async fn sub(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
-        let result = request.get_ref().a - request.get_ref().b;
-        Ok(Response::new(CalcOutput {
-            result,
-            error: false,
-        }))
-    }
+    let metrics_addr = config.metrics_address();
+    tokio::spawn(async move {
+        if let Err(error) = metrics::serve(metrics_addr).await {
+            tracing::error!(%error, "metrics server failed");
+        }
+    });
 
-    async fn mul(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
-        let result = request.get_ref().a * request.get_ref().b;
-        Ok(Response::new(CalcOutput {
-            result,
-            error: false,
-        }))
+    let mut builder = Server::builder();
+    if let Some(tls) = &config.tls {
+        builder = builder.tls_config(tls.load()?)?;
     }
-
-    async fn div(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
-        let result = request.get_ref().a / request.get_ref().b;
-        Ok(Response::new(CalcOutput {
-            result,
-            error: false,
-        }))
+    if let Some(timeout) = config.request_timeout() {
+        builder = builder.timeout(timeout);
     }
-}
-
-#[derive(Clone)]
-struct LogService<S> {
-    target: &'static str,
-    service: S,
-}
-
-impl<S, Request> Service<Request> for LogService<S>
-where
-    S: Service<Request>,
-    Request: std::fmt::Debug,
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = S::Future;
-
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+    if let Some(limit) = config.concurrency_limit {
+        builder = builder.concurrency_limit_per_connection(limit);
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
-        println!("Request: {:?}, Target: {:?}", request, self.target);
-        self.service.call(request)
-    }
-}
-
-#[derive(Clone)]
-struct LogLayer {
-    target: &'static str,
-}
-
-impl<S> Layer<S> for LogLayer {
-    type Service = LogService<S>;
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health
+        .set_serving::<CalculatorServiceServer<Calculator>>()
+        .await;
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()?;
+
+    let auth = AuthInterceptor::new(config.tokens.clone());
+    let limits = LimitLayer::new(&config.limits, auth.clone());
+    let cleanup = limits.spawn_cleanup(RATE_LIMIT_CLEANUP_INTERVAL);
+
+    let (stop_gateway, gateway_stopped) = oneshot::channel::<()>();
+    let gateway_addr = config.gateway_address();
+    let gateway = gateway_addr.map(|gateway_addr| {
+        let gateway = Gateway::new(calculator.clone(), auth.clone()).with_limits(limits.clone());
+        tokio::spawn(async move {
+            let shutdown = async {
+                let _ = gateway_stopped.await;
+            };
+            if let Err(error) = gateway::serve(gateway_addr, gateway, shutdown).await {
+                tracing::error!(%error, "gateway failed");
+            }
+        })
+    });
+
+    let addr = config.address();
+    tracing::info!(
+        %addr,
+        gateway = ?gateway_addr,
+        tls = config.tls.is_some(),
+        mutual_tls = config.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some()),
+        auth = auth.is_enabled(),
+        limits = config.limits.is_enabled(),
+        cache = config.cache.is_enabled(),
+        "serving calculator"
+    );
+    let (stopping, stopped) = oneshot::channel();
+    let grace = config.shutdown_grace();
+    let server = builder
+        .layer(LogLayer)
+        .layer(MetricsLayer::new(
+            service::METHODS.iter().chain(STANDARD_METHODS).copied(),
+        ))
+        .layer(limits)
+        // health and reflection stay reachable without a bearer token
+        .add_service(health_service)
+        .add_service(reflection)
+        .add_service(CalculatorServiceServer::with_interceptor(
+            calculator.clone(),
+            auth,
+        ))
+        .serve_with_shutdown(addr, async move {
+            shutdown_signal(health, grace).await;
+            let _ = stopping.send(());
+            let _ = stop_gateway.send(());
+        });
+    let drain_deadline = async {
+        match stopped.await {
+            Ok(()) => tokio::time::sleep(DRAIN_TIMEOUT).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
 
-    fn layer(&self, service: S) -> Self::Service {
-        Self::Service {
-            target: self.target,
-            service,
+    tokio::select! {
+        result = server => result?,
+        _ = drain_deadline => tracing::warn!("in-flight requests did not finish in time"),
+    }
+    if let Some(gateway) = gateway {
+        if tokio::time::timeout(DRAIN_TIMEOUT, gateway).await.is_err() {
+            tracing::warn!("in-flight gateway requests did not finish in time");
         }
     }
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr: std::net::SocketAddr = "127.0.0.1:1234".parse().unwrap();
-    let calculator = Calculator::default();
-
-    let layer = LogLayer { target: "abcd" };
-
-    Server::builder()
-        .layer(layer)
-        .add_service(CalculatorServiceServer::new(calculator))
-        .serve(addr)
-        .await?;
 
+    if let Some(cache) = calculator.cache() {
+        tracing::info!(hits = cache.hits(), misses = cache.misses(), "result cache");
+    }
+    expiry.abort();
+    if let Some(cleanup) = cleanup {
+        cleanup.abort();
+    }
+    tracing::info!("server stopped");
     Ok(())
 }
@@ -0,0 +1,283 @@
+use crate::calculator_service::{evaluation_result, CalcOutput};
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::metadata::MetadataMap;
+
+/// Requests with `cache-control: no-cache` are computed from scratch and
+/// their results are not stored.
+pub const CACHE_CONTROL_HEADER: &str = "cache-control";
+const NO_CACHE: &str = "no-cache";
+
+lazy_static! {
+    static ref CACHE_LOOKUPS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "calculator_cache_lookups_total",
+        "the number of result cache lookups, by operation and result",
+        &["operation", "result"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Results kept at most, the least recently used are dropped first.
+    /// Unset disables the cache.
+    pub size: Option<usize>,
+    /// How long a result is served from the cache.
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            size: None,
+            ttl_secs: 60,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.size.is_some()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+pub fn bypasses_cache(metadata: &MetadataMap) -> bool {
+    metadata
+        .get_all(CACHE_CONTROL_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case(NO_CACHE))
+}
+
+/// A computation: the operation, its operands and the number mode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    operation: String,
+    operands: Vec<String>,
+    mode: i32,
+    scale: u32,
+}
+
+impl CacheKey {
+    pub fn new(operation: impl ToString, operands: &[&str], mode: i32, scale: u32) -> Self {
+        CacheKey {
+            operation: operation.to_string(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+            mode,
+            scale,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Output(CalcOutput),
+    Outcome(evaluation_result::Outcome),
+}
+
+struct Entry {
+    value: Value,
+    expires: Instant,
+}
+
+/// Memoizes results of pure computations. Failed calculations are cached
+/// too, they are just as deterministic.
+///
+/// The lock is not held while computing, two requests missing the same key
+/// at once both compute it.
+#[derive(Clone)]
+pub struct ResultCache {
+    entries: Arc<Mutex<LruCache<CacheKey, Entry>>>,
+    ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl ResultCache {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        ResultCache {
+            entries: Arc::new(Mutex::new(LruCache::new(size))),
+            ttl,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        drop(entries);
+
+        let result = if value.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            "hit"
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            "miss"
+        };
+        CACHE_LOOKUPS_TOTAL
+            .with_label_values(&[&key.operation, result])
+            .inc();
+        tracing::trace!(operation = %key.operation, result, "result cache lookup");
+        value
+    }
+
+    fn put(&self, key: CacheKey, value: Value) {
+        let entry = Entry {
+            value,
+            expires: Instant::now() + self.ttl,
+        };
+        self.entries.lock().unwrap().put(key, entry);
+    }
+
+    pub fn output(&self, key: CacheKey, compute: impl FnOnce() -> CalcOutput) -> CalcOutput {
+        if let Some(Value::Output(output)) = self.get(&key) {
+            return output;
+        }
+        let output = compute();
+        self.put(key, Value::Output(output.clone()));
+        output
+    }
+
+    pub fn outcome(
+        &self,
+        key: CacheKey,
+        compute: impl FnOnce() -> evaluation_result::Outcome,
+    ) -> evaluation_result::Outcome {
+        if let Some(Value::Outcome(outcome)) = self.get(&key) {
+            return outcome;
+        }
+        let outcome = compute();
+        self.put(key, Value::Outcome(outcome.clone()));
+        outcome
+    }
+}
+
+#[cfg(test)]
+fn add(cache: &ResultCache, a: &str, computed: &mut u32) -> String {
+    let key = CacheKey::new("+", &[a, "1"], 0, 0);
+    cache
+        .output(key, || {
+            *computed += 1;
+            CalcOutput {
+                result: format!("{}+1", a),
+                ..CalcOutput::default()
+            }
+        })
+        .result
+}
+
+#[tokio::test(start_paused = true)]
+async fn results_expire_and_least_recently_used_are_evicted() {
+    let cache = ResultCache::new(2, Duration::from_secs(10));
+    let mut computed = 0;
+
+    assert_eq!(add(&cache, "1", &mut computed), "1+1");
+    assert_eq!(add(&cache, "1", &mut computed), "1+1");
+    assert_eq!((computed, cache.hits(), cache.misses()), (1, 1, 1));
+
+    // "1" was used more recently than "2", so "2" makes room for "3"
+    add(&cache, "2", &mut computed);
+    add(&cache, "1", &mut computed);
+    add(&cache, "3", &mut computed);
+    assert_eq!(cache.len(), 2);
+    add(&cache, "1", &mut computed);
+    assert_eq!(computed, 3);
+    add(&cache, "2", &mut computed);
+    assert_eq!(computed, 4);
+
+    tokio::time::advance(Duration::from_secs(11)).await;
+    add(&cache, "2", &mut computed);
+    assert_eq!(computed, 5);
+}
+
+#[test]
+fn no_cache_directive() {
+    let mut metadata = MetadataMap::new();
+    assert!(!bypasses_cache(&metadata));
+    metadata.insert(CACHE_CONTROL_HEADER, "max-age=0, No-Cache".parse().unwrap());
+    assert!(bypasses_cache(&metadata));
+}
+
+#[tokio::test]
+async fn calculator_uses_the_cache_unless_told_not_to() {
+    use crate::calculator_service::calculator_service_server::CalculatorService;
+    use crate::calculator_service::{CalcInput, CreateSessionRequest, ExpressionRequest};
+    use crate::service::Calculator;
+    use crate::session::SESSION_TOKEN_HEADER;
+    use tonic::Request;
+
+    let cache = ResultCache::new(16, Duration::from_secs(60));
+    let calculator = Calculator::default().with_cache(cache.clone());
+    let input = CalcInput {
+        a: "6".to_string(),
+        b: "7".to_string(),
+        ..CalcInput::default()
+    };
+    for _ in 0..3 {
+        let output = calculator.mul(Request::new(input.clone())).await.unwrap();
+        assert_eq!(output.into_inner().result, "42");
+    }
+    assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+    let mut request = Request::new(input);
+    request
+        .metadata_mut()
+        .insert(CACHE_CONTROL_HEADER, "no-cache".parse().unwrap());
+    calculator.mul(request).await.unwrap();
+    assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+    // expressions in a session may use its variables
+    let token = calculator
+        .create_session(Request::new(CreateSessionRequest {}))
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+    let mut request = Request::new(ExpressionRequest {
+        expression: "1 + 1".to_string(),
+        ..ExpressionRequest::default()
+    });
+    request
+        .metadata_mut()
+        .insert(SESSION_TOKEN_HEADER, token.parse().unwrap());
+    CalculatorService::evaluate(&calculator, request)
+        .await
+        .unwrap();
+    assert_eq!(cache.len(), 1);
+}
@@ -0,0 +1,228 @@
+use crate::auth::{AUTHORIZATION_HEADER, BEARER_PREFIX};
+use crate::calculator_service::calculator_service_client::CalculatorServiceClient;
+use crate::calculator_service::CreateSessionRequest;
+use crate::config::{ClientConfig, RetryConfig};
+use crate::error::ERROR_CODE_HEADER;
+use crate::session::SESSION_TOKEN_HEADER;
+use rand::Rng;
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status};
+
+/// Attaches the bearer token and the session token to every request.
+#[derive(Clone, Default)]
+pub struct CredentialsInterceptor {
+    authorization: Option<MetadataValue<Ascii>>,
+    session: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for CredentialsInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, authorization.clone());
+        }
+        if let Some(session) = &self.session {
+            request
+                .metadata_mut()
+                .insert(SESSION_TOKEN_HEADER, session.clone());
+        }
+        Ok(request)
+    }
+}
+
+pub type Client = CalculatorServiceClient<InterceptedService<Channel, CredentialsInterceptor>>;
+
+/// Exponential backoff with jitter: the n-th retry waits a random time
+/// between half and all of `initial * 2^n`, capped at the maximum.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig) -> Self {
+        RetryPolicy {
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+        }
+    }
+
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let cap = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        cap / 2 + rand::thread_rng().gen_range(Duration::ZERO..=cap / 2)
+    }
+}
+
+fn is_unknown_session(status: &Status) -> bool {
+    status.code() == Code::NotFound
+        && status
+            .metadata()
+            .get(ERROR_CODE_HEADER)
+            .and_then(|code| code.to_str().ok())
+            == Some("UnknownSession")
+}
+
+/// A calculator client that keeps a session, gives every call a deadline
+/// and retries calls the server was unavailable for.
+///
+/// The channel reconnects by itself once the server is back, a session the
+/// server no longer knows, e.g. after a restart, is replaced by a new one.
+/// Session variables are lost when that happens.
+#[derive(Clone)]
+pub struct CalculatorClient {
+    channel: Channel,
+    interceptor: CredentialsInterceptor,
+    deadline: Option<Duration>,
+    retry: RetryPolicy,
+    retries: u32,
+}
+
+impl CalculatorClient {
+    pub async fn connect(config: &ClientConfig) -> Result<Self, Box<dyn Error>> {
+        let mut endpoint = Endpoint::from_shared(config.uri())?;
+        if let Some(tls) = config.tls_config()? {
+            endpoint = endpoint.tls_config(tls)?;
+        }
+        if let Some(timeout) = config.request_timeout() {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some(limit) = config.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+
+        let mut interceptor = CredentialsInterceptor::default();
+        if let Some(token) = &config.token {
+            interceptor.authorization = Some(format!("{}{}", BEARER_PREFIX, token).parse()?);
+        }
+        let mut client = CalculatorClient {
+            channel: endpoint.connect_lazy(),
+            interceptor,
+            deadline: config.deadline(),
+            retry: RetryPolicy::new(&config.retry),
+            retries: 0,
+        };
+        client.start_session().await?;
+        Ok(client)
+    }
+
+    /// Starts a new session, later calls are made in it.
+    pub async fn start_session(&mut self) -> Result<(), Status> {
+        self.retries = 0;
+        self.replace_session().await
+    }
+
+    async fn replace_session(&mut self) -> Result<(), Status> {
+        self.interceptor.session = None;
+        let session = self
+            .attempt(
+                true,
+                CreateSessionRequest {},
+                |mut client, request| async move { client.create_session(request).await },
+            )
+            .await?
+            .into_inner();
+        let token = session
+            .token
+            .parse()
+            .map_err(|_| Status::internal("server sent an invalid session token"))?;
+        self.interceptor.session = Some(token);
+        Ok(())
+    }
+
+    /// The underlying generated client, for calls that are not retried such
+    /// as streams.
+    pub fn client(&self) -> Client {
+        CalculatorServiceClient::with_interceptor(self.channel.clone(), self.interceptor.clone())
+    }
+
+    /// How often the last call was retried.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Makes a call with the configured deadline. `Unavailable` errors are
+    /// retried with backoff if `idempotent`, calls that may have changed
+    /// state on the server are never repeated.
+    pub async fn call<T, R, F, Fut>(
+        &mut self,
+        idempotent: bool,
+        message: T,
+        call: F,
+    ) -> Result<Response<R>, Status>
+    where
+        T: Clone,
+        F: Fn(Client, Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<R>, Status>>,
+    {
+        self.retries = 0;
+        match self.attempt(idempotent, message.clone(), &call).await {
+            // the call was rejected before doing anything, so it is safe
+            // to repeat in the new session
+            Err(status) if is_unknown_session(&status) => {
+                self.replace_session().await?;
+                self.attempt(idempotent, message, &call).await
+            }
+            result => result,
+        }
+    }
+
+    async fn attempt<T, R, F, Fut>(
+        &mut self,
+        idempotent: bool,
+        message: T,
+        call: F,
+    ) -> Result<Response<R>, Status>
+    where
+        T: Clone,
+        F: Fn(Client, Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<R>, Status>>,
+    {
+        loop {
+            let mut request = Request::new(message.clone());
+            if let Some(deadline) = self.deadline {
+                request.set_timeout(deadline);
+            }
+            match call(self.client(), request).await {
+                Err(status)
+                    if status.code() == Code::Unavailable
+                        && idempotent
+                        && self.retries < self.retry.max_retries =>
+                {
+                    tokio::time::sleep(self.retry.backoff(self.retries)).await;
+                    self.retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[test]
+fn backoff_grows_exponentially_with_jitter() {
+    let policy = RetryPolicy::new(&RetryConfig {
+        max_retries: 10,
+        initial_backoff_ms: 100,
+        max_backoff_ms: 1000,
+    });
+    for _ in 0..100 {
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = policy.backoff(2);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+        let capped = policy.backoff(20);
+        assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,588 @@
+use crate::cache::CacheConfig;
+use crate::limit::{LimitConfig, LimitKey};
+use crate::logging::LogFormat;
+use crate::session::SessionConfig;
+use clap::Parser;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+pub const DEFAULT_PORT: u16 = 1234;
+pub const DEFAULT_METRICS_PORT: u16 = 9898;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse(path, error) => {
+                write!(f, "invalid config file {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn read(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|error| ConfigError::Read(path.to_path_buf(), error))
+}
+
+/// Contents of the TOML config file. Both binaries read the same file,
+/// each from its own section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        toml::from_str(&read(path)?).map_err(|error| ConfigError::Parse(path.to_path_buf(), error))
+    }
+
+    fn load_optional(path: Option<&Path>) -> Result<Self, ConfigError> {
+        path.map_or_else(|| Ok(Config::default()), Config::load)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// PEM encoded private key of the certificate.
+    pub key: PathBuf,
+    /// CA that client certificates must be signed by. Enables mutual TLS.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<ServerTlsConfig, ConfigError> {
+        let identity = Identity::from_pem(read(&self.cert)?, read(&self.key)?);
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = &self.client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(read(client_ca)?));
+        }
+        Ok(tls)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    pub metrics_port: u16,
+    /// Port of the HTTP/JSON gateway, which is disabled when unset.
+    pub gateway_port: Option<u16>,
+    pub tls: Option<TlsConfig>,
+    pub request_timeout_secs: Option<u64>,
+    /// Requests handled at the same time on a single connection.
+    pub concurrency_limit: Option<usize>,
+    pub log_format: LogFormat,
+    /// Bearer tokens clients may authenticate with. Empty disables
+    /// authentication.
+    pub tokens: Vec<String>,
+    /// How long the server reports NOT_SERVING before it stops accepting
+    /// connections on shutdown.
+    pub shutdown_grace_secs: u64,
+    pub limits: LimitConfig,
+    pub cache: CacheConfig,
+    pub sessions: SessionConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            metrics_port: DEFAULT_METRICS_PORT,
+            gateway_port: None,
+            tls: None,
+            request_timeout_secs: None,
+            concurrency_limit: None,
+            log_format: LogFormat::default(),
+            tokens: Vec::new(),
+            shutdown_grace_secs: 0,
+            limits: LimitConfig::default(),
+            cache: CacheConfig::default(),
+            sessions: SessionConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    pub fn metrics_address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.metrics_port)
+    }
+
+    pub fn gateway_address(&self) -> Option<SocketAddr> {
+        self.gateway_port
+            .map(|port| SocketAddr::new(self.host, port))
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    /// CA certificate to verify the server with. Connects over TLS when set.
+    pub tls_ca: Option<PathBuf>,
+    /// Client certificate and key, for servers that require mutual TLS.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub token: Option<String>,
+    pub request_timeout_secs: Option<u64>,
+    /// Deadline of every call attempt, also enforced by the server.
+    pub deadline_ms: Option<u64>,
+    /// Requests in flight at the same time on the channel.
+    pub concurrency_limit: Option<usize>,
+    pub retry: RetryConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retries of an idempotent call the server was unavailable for.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 5000,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            host: "localhost".to_string(),
+            port: DEFAULT_PORT,
+            tls_ca: None,
+            tls_cert: None,
+            tls_key: None,
+            token: None,
+            request_timeout_secs: None,
+            deadline_ms: None,
+            concurrency_limit: None,
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn uri(&self) -> String {
+        let scheme = if self.tls_ca.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline_ms.map(Duration::from_millis)
+    }
+
+    pub fn tls_config(&self) -> Result<Option<ClientTlsConfig>, ConfigError> {
+        let ca = match &self.tls_ca {
+            Some(ca) => Certificate::from_pem(read(ca)?),
+            None => return Ok(None),
+        };
+        let mut tls = ClientTlsConfig::new()
+            .ca_certificate(ca)
+            .domain_name(self.host.clone());
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+        Ok(Some(tls))
+    }
+}
+
+/// Command line flags of the server. Flags win over environment variables,
+/// which win over the config file.
+#[derive(Debug, Default, Parser)]
+#[clap(author, version, about = "Calculator gRPC server", long_about = None)]
+pub struct ServerArgs {
+    /// TOML config file, settings are read from its `[server]` section
+    #[clap(long, value_parser, env = "CALCULATOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to bind to
+    #[clap(long, value_parser, env = "CALCULATOR_HOST")]
+    pub host: Option<IpAddr>,
+
+    #[clap(long, value_parser, env = "CALCULATOR_PORT")]
+    pub port: Option<u16>,
+
+    /// Port of the Prometheus `/metrics` endpoint
+    #[clap(long, value_parser, env = "CALCULATOR_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Port to serve the calculator as JSON over HTTP on, e.g. `POST /v1/add`
+    #[clap(long, value_parser, env = "CALCULATOR_GATEWAY_PORT")]
+    pub gateway_port: Option<u16>,
+
+    /// PEM certificate chain, enables TLS together with `--tls-key`
+    #[clap(long, value_parser, requires = "tls-key", env = "CALCULATOR_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(long, value_parser, requires = "tls-cert", env = "CALCULATOR_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA certificate, requires clients to present a certificate it signed
+    #[clap(
+        long,
+        value_parser,
+        requires = "tls-cert",
+        env = "CALCULATOR_TLS_CLIENT_CA"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Bearer token clients may authenticate with, can be repeated
+    #[clap(
+        long = "token",
+        value_parser,
+        env = "CALCULATOR_TOKENS",
+        value_delimiter = ','
+    )]
+    pub tokens: Vec<String>,
+
+    #[clap(long, value_parser, env = "CALCULATOR_REQUEST_TIMEOUT_SECS")]
+    pub request_timeout_secs: Option<u64>,
+
+    /// Requests handled at the same time on a single connection
+    #[clap(long, value_parser, env = "CALCULATOR_CONCURRENCY_LIMIT")]
+    pub concurrency_limit: Option<usize>,
+
+    /// `pretty` or `json`
+    #[clap(long, value_parser, env = "CALCULATOR_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Seconds to report NOT_SERVING to health checks before shutting down
+    #[clap(long, value_parser, env = "CALCULATOR_SHUTDOWN_GRACE_SECS")]
+    pub shutdown_grace_secs: Option<u64>,
+
+    /// Sustained requests per second allowed for each client
+    #[clap(long, value_parser, env = "CALCULATOR_RATE_LIMIT")]
+    pub rate_limit: Option<u32>,
+
+    /// Requests a client may make at once after being idle
+    #[clap(long, value_parser, env = "CALCULATOR_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// What clients are told apart by, `peer` or `token` (unknown tokens
+    /// count as their peer)
+    #[clap(long, value_parser, env = "CALCULATOR_RATE_LIMIT_KEY")]
+    pub rate_limit_key: Option<LimitKey>,
+
+    /// Requests handled at the same time across all clients, more are shed
+    #[clap(long, value_parser, env = "CALCULATOR_MAX_CONCURRENT_REQUESTS")]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Results of repeated computations kept in memory, unset disables caching
+    #[clap(long, value_parser, env = "CALCULATOR_CACHE_SIZE")]
+    pub cache_size: Option<usize>,
+
+    /// Seconds a cached result is served for
+    #[clap(long, value_parser, env = "CALCULATOR_CACHE_TTL_SECS")]
+    pub cache_ttl_secs: Option<u64>,
+
+    /// Sessions alive at the same time, creating more fails
+    #[clap(long, value_parser, env = "CALCULATOR_MAX_SESSIONS")]
+    pub max_sessions: Option<usize>,
+
+    /// Variables a session may hold
+    #[clap(long, value_parser, env = "CALCULATOR_MAX_SESSION_VARIABLES")]
+    pub max_session_variables: Option<usize>,
+}
+
+impl ServerArgs {
+    pub fn resolve(self) -> Result<ServerConfig, ConfigError> {
+        let mut config = Config::load_optional(self.config.as_deref())?.server;
+        if let Some(host) = self.host {
+            config.host = host;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(metrics_port) = self.metrics_port {
+            config.metrics_port = metrics_port;
+        }
+        if let Some(gateway_port) = self.gateway_port {
+            config.gateway_port = Some(gateway_port);
+        }
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert,
+                key,
+                client_ca: self.tls_client_ca,
+            });
+        }
+        if !self.tokens.is_empty() {
+            config.tokens = self.tokens;
+        }
+        if let Some(shutdown_grace_secs) = self.shutdown_grace_secs {
+            config.shutdown_grace_secs = shutdown_grace_secs;
+        }
+        if self.rate_limit.is_some() {
+            config.limits.requests_per_second = self.rate_limit;
+        }
+        if self.rate_limit_burst.is_some() {
+            config.limits.burst = self.rate_limit_burst;
+        }
+        if let Some(key) = self.rate_limit_key {
+            config.limits.key = key;
+        }
+        if self.max_concurrent_requests.is_some() {
+            config.limits.max_concurrent_requests = self.max_concurrent_requests;
+        }
+        if self.cache_size.is_some() {
+            config.cache.size = self.cache_size;
+        }
+        if let Some(cache_ttl_secs) = self.cache_ttl_secs {
+            config.cache.ttl_secs = cache_ttl_secs;
+        }
+        if let Some(max_sessions) = self.max_sessions {
+            config.sessions.max_sessions = max_sessions;
+        }
+        if let Some(max_variables) = self.max_session_variables {
+            config.sessions.max_variables = max_variables;
+        }
+        if self.request_timeout_secs.is_some() {
+            config.request_timeout_secs = self.request_timeout_secs;
+        }
+        if self.concurrency_limit.is_some() {
+            config.concurrency_limit = self.concurrency_limit;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        Ok(config)
+    }
+}
+
+/// Connection flags of the client, resolved the same way as [`ServerArgs`].
+#[derive(Debug, Default, clap::Args)]
+pub struct ClientArgs {
+    /// TOML config file, settings are read from its `[client]` section
+    #[clap(long, value_parser, env = "CALCULATOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Server host to connect to
+    #[clap(long, value_parser, env = "CALCULATOR_CLIENT_HOST")]
+    pub host: Option<String>,
+
+    #[clap(long, value_parser, env = "CALCULATOR_CLIENT_PORT")]
+    pub port: Option<u16>,
+
+    /// PEM CA certificate of the server, enables TLS
+    #[clap(long, value_parser, env = "CALCULATOR_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate for mutual TLS, together with `--tls-key`
+    #[clap(
+        long,
+        value_parser,
+        requires_all = &["tls-key", "tls-ca"],
+        env = "CALCULATOR_CLIENT_TLS_CERT"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_parser,
+        requires = "tls-cert",
+        env = "CALCULATOR_CLIENT_TLS_KEY"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    /// Bearer token sent with every request
+    #[clap(long, value_parser, env = "CALCULATOR_TOKEN")]
+    pub token: Option<String>,
+
+    #[clap(long, value_parser, env = "CALCULATOR_REQUEST_TIMEOUT_SECS")]
+    pub request_timeout_secs: Option<u64>,
+
+    /// Deadline of every call attempt in milliseconds
+    #[clap(long, value_parser, env = "CALCULATOR_DEADLINE_MS")]
+    pub deadline_ms: Option<u64>,
+
+    /// Requests in flight at the same time
+    #[clap(long, value_parser, env = "CALCULATOR_CONCURRENCY_LIMIT")]
+    pub concurrency_limit: Option<usize>,
+
+    /// Retries of calls the server was unavailable for, 0 disables them
+    #[clap(long, value_parser, env = "CALCULATOR_RETRIES")]
+    pub retries: Option<u32>,
+
+    /// Wait before the first retry in milliseconds, doubled for each retry
+    #[clap(long, value_parser, env = "CALCULATOR_RETRY_BACKOFF_MS")]
+    pub retry_backoff_ms: Option<u64>,
+
+    #[clap(long, value_parser, env = "CALCULATOR_MAX_RETRY_BACKOFF_MS")]
+    pub max_retry_backoff_ms: Option<u64>,
+}
+
+impl ClientArgs {
+    pub fn resolve(self) -> Result<ClientConfig, ConfigError> {
+        let mut config = Config::load_optional(self.config.as_deref())?.client;
+        if let Some(host) = self.host {
+            config.host = host;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if self.tls_ca.is_some() {
+            config.tls_ca = self.tls_ca;
+        }
+        if self.tls_cert.is_some() {
+            config.tls_cert = self.tls_cert;
+            config.tls_key = self.tls_key;
+        }
+        if self.token.is_some() {
+            config.token = self.token;
+        }
+        if self.request_timeout_secs.is_some() {
+            config.request_timeout_secs = self.request_timeout_secs;
+        }
+        if self.deadline_ms.is_some() {
+            config.deadline_ms = self.deadline_ms;
+        }
+        if self.concurrency_limit.is_some() {
+            config.concurrency_limit = self.concurrency_limit;
+        }
+        if let Some(retries) = self.retries {
+            config.retry.max_retries = retries;
+        }
+        if let Some(backoff) = self.retry_backoff_ms {
+            config.retry.initial_backoff_ms = backoff;
+        }
+        if let Some(backoff) = self.max_retry_backoff_ms {
+            config.retry.max_backoff_ms = backoff;
+        }
+        Ok(config)
+    }
+}
+
+#[test]
+fn config_file_sections() {
+    let config: Config = toml::from_str(
+        r#"
+        [server]
+        host = "0.0.0.0"
+        port = 4000
+        gateway_port = 8080
+        request_timeout_secs = 5
+        log_format = "json"
+
+        [server.limits]
+        requests_per_second = 50
+        key = "token"
+
+        [server.cache]
+        size = 1000
+
+        [server.sessions]
+        max_sessions = 50
+
+        [server.tls]
+        cert = "server.pem"
+        key = "server.key"
+
+        [client]
+        host = "calculator.internal"
+        tls_ca = "ca.pem"
+        deadline_ms = 250
+
+        [client.retry]
+        max_retries = 2
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.server.address(), "0.0.0.0:4000".parse().unwrap());
+    assert_eq!(config.server.metrics_port, DEFAULT_METRICS_PORT);
+    assert_eq!(
+        config.server.gateway_address(),
+        Some("0.0.0.0:8080".parse().unwrap())
+    );
+    assert_eq!(
+        config.server.request_timeout(),
+        Some(Duration::from_secs(5))
+    );
+    assert_eq!(config.server.log_format, LogFormat::Json);
+    assert_eq!(config.server.limits.requests_per_second, Some(50));
+    assert_eq!(config.server.limits.key, LimitKey::Token);
+    assert_eq!(config.server.cache.size, Some(1000));
+    assert_eq!(config.server.cache.ttl(), Duration::from_secs(60));
+    assert_eq!(config.server.sessions.max_sessions, 50);
+    assert_eq!(config.server.sessions.max_variables, 100);
+    assert_eq!(config.server.tls.unwrap().key, PathBuf::from("server.key"));
+    assert_eq!(config.client.uri(), "https://calculator.internal:1234");
+    assert_eq!(config.client.deadline(), Some(Duration::from_millis(250)));
+    assert_eq!(config.client.retry.max_retries, 2);
+    assert_eq!(config.client.retry.initial_backoff_ms, 100);
+    assert!(toml::from_str::<Config>("[server]\nbind = 1").is_err());
+}
+
+#[test]
+fn flags_override_the_config_file() {
+    let path = std::env::temp_dir().join(format!("calculator-{}.toml", std::process::id()));
+    std::fs::write(&path, "[server]\nport = 4000\nconcurrency_limit = 8\n").unwrap();
+
+    let args = ServerArgs::try_parse_from([
+        "server",
+        "--port",
+        "5000",
+        "--config",
+        path.to_str().unwrap(),
+    ])
+    .unwrap();
+    let config = args.resolve().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(config.port, 5000);
+    assert_eq!(config.concurrency_limit, Some(8));
+    assert!(ServerArgs::try_parse_from(["server", "--tls-cert", "cert.pem"]).is_err());
+
+    let args = ServerArgs::try_parse_from(["server", "--token", "a,b", "--token", "c"]).unwrap();
+    assert_eq!(args.resolve().unwrap().tokens, ["a", "b", "c"]);
+}
@@ -0,0 +1,67 @@
+use crate::calculator_service::ErrorCode;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use tonic::Status;
+
+/// Response metadata naming the calculator error code of a failed
+/// calculation, so it can be logged without decoding the message.
+pub const ERROR_CODE_HEADER: &str = "calc-error-code";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalcError {
+    DivisionByZero,
+    Overflow,
+    InvalidInput(String),
+    UnknownSession,
+    SessionRequired,
+    /// The operation is not defined for its input.
+    Domain(String),
+}
+
+impl CalcError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CalcError::DivisionByZero => ErrorCode::DivisionByZero,
+            CalcError::Overflow => ErrorCode::Overflow,
+            CalcError::InvalidInput(_) => ErrorCode::InvalidInput,
+            CalcError::UnknownSession => ErrorCode::UnknownSession,
+            CalcError::SessionRequired => ErrorCode::SessionRequired,
+            CalcError::Domain(_) => ErrorCode::DomainError,
+        }
+    }
+}
+
+impl Display for CalcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalcError::DivisionByZero => write!(f, "division by zero"),
+            CalcError::Overflow => write!(f, "overflow"),
+            CalcError::InvalidInput(message) => write!(f, "{}", message),
+            CalcError::UnknownSession => write!(f, "unknown or expired session"),
+            CalcError::SessionRequired => write!(f, "this operation requires a session"),
+            CalcError::Domain(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for CalcError {}
+
+/// Used for requests the server refuses to process at all, as opposed to
+/// calculations that fail and are reported inside the response message.
+impl From<CalcError> for Status {
+    fn from(error: CalcError) -> Self {
+        let code = error.code();
+        let mut status = match error {
+            CalcError::DivisionByZero => Status::invalid_argument(error.to_string()),
+            CalcError::Overflow => Status::out_of_range(error.to_string()),
+            CalcError::InvalidInput(_) => Status::invalid_argument(error.to_string()),
+            CalcError::UnknownSession => Status::not_found(error.to_string()),
+            CalcError::SessionRequired => Status::failed_precondition(error.to_string()),
+            CalcError::Domain(_) => Status::invalid_argument(error.to_string()),
+        };
+        if let Ok(value) = format!("{:?}", code).parse() {
+            status.metadata_mut().insert(ERROR_CODE_HEADER, value);
+        }
+        status
+    }
+}
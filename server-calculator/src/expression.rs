@@ -0,0 +1,706 @@
+use crate::error::CalcError;
+use crate::number::{Mode, Number};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Named values an expression can refer to, kept as literals so that they
+/// can be reinterpreted in whatever number mode the expression uses.
+pub type Variables = HashMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    /// Remainder of truncating division, it has the sign of the dividend.
+    Mod,
+    Gcd,
+    Lcm,
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Operator::Add => "+",
+                Operator::Sub => "-",
+                Operator::Mul => "*",
+                Operator::Div => "/",
+                Operator::Pow => "^",
+                Operator::Mod => "%",
+                Operator::Gcd => "gcd",
+                Operator::Lcm => "lcm",
+            }
+        )
+    }
+}
+
+/// Functions of a single number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    /// Rounds down in the integer modes.
+    Sqrt,
+    Factorial,
+    Abs,
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Function::Sqrt => "sqrt",
+                Function::Factorial => "factorial",
+                Function::Abs => "abs",
+            }
+        )
+    }
+}
+
+/// What a name followed by `(` calls.
+enum Callee {
+    Unary(Function),
+    Binary(Operator),
+}
+
+impl Callee {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sqrt" => Callee::Unary(Function::Sqrt),
+            "factorial" => Callee::Unary(Function::Factorial),
+            "abs" => Callee::Unary(Function::Abs),
+            "gcd" => Callee::Binary(Operator::Gcd),
+            "lcm" => Callee::Binary(Operator::Lcm),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(String),
+    Identifier(String),
+    Operator(Operator),
+    Assign,
+    Comma,
+    LeftParen,
+    RightParen,
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionError {
+    pub kind: CalcError,
+    pub position: usize,
+}
+
+impl ExpressionError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        ExpressionError {
+            kind: CalcError::InvalidInput(message.into()),
+            position,
+        }
+    }
+
+    fn at(kind: CalcError, position: usize) -> Self {
+        ExpressionError { kind, position }
+    }
+}
+
+impl Display for ExpressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.kind, self.position)
+    }
+}
+
+impl Error for ExpressionError {}
+
+/// Splits the input into tokens, remembering the byte offset of each one
+/// so that errors can point back into the original string.
+fn tokenize(input: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '0'..='9' | '.' | 'a'..='z' | 'A'..='Z' | '_' => {
+                let is_number = c.is_ascii_digit() || c == '.';
+                let mut end = position;
+                while let Some(&(index, next)) = chars.peek() {
+                    let continues = if is_number {
+                        next.is_ascii_digit() || next == '.'
+                    } else {
+                        next.is_ascii_alphanumeric() || next == '_'
+                    };
+                    if !continues {
+                        break;
+                    }
+                    end = index + next.len_utf8();
+                    chars.next();
+                }
+                let text = input[position..end].to_string();
+                tokens.push(Token {
+                    kind: if is_number {
+                        TokenKind::Number(text)
+                    } else {
+                        TokenKind::Identifier(text)
+                    },
+                    position,
+                });
+                continue;
+            }
+            '+' => TokenKind::Operator(Operator::Add),
+            '-' => TokenKind::Operator(Operator::Sub),
+            '*' => TokenKind::Operator(Operator::Mul),
+            '/' => TokenKind::Operator(Operator::Div),
+            '^' => TokenKind::Operator(Operator::Pow),
+            '%' => TokenKind::Operator(Operator::Mod),
+            '=' => TokenKind::Assign,
+            ',' => TokenKind::Comma,
+            '(' => TokenKind::LeftParen,
+            ')' => TokenKind::RightParen,
+            other => {
+                return Err(ExpressionError::new(
+                    format!("unexpected character '{}'", other),
+                    position,
+                ))
+            }
+        };
+        tokens.push(Token { kind, position });
+        chars.next();
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::End,
+        position: input.len(),
+    });
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number {
+        literal: String,
+        position: usize,
+    },
+    Variable {
+        name: String,
+        position: usize,
+    },
+    Negate {
+        operand: Box<Expr>,
+        position: usize,
+    },
+    Binary {
+        operator: Operator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        position: usize,
+    },
+    Call {
+        function: Function,
+        argument: Box<Expr>,
+        position: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Expression(Expr),
+    Assignment { name: String, value: Expr },
+}
+
+/// Recursive descent parser over the token stream:
+///
+/// ```text
+/// statement  := identifier "=" expression | expression
+/// expression := term (("+" | "-") term)*
+/// term       := unary (("*" | "/" | "%") unary)*
+/// unary      := "-" unary | power
+/// power      := primary ("^" unary)?
+/// primary    := number | call | identifier | "(" expression ")"
+/// call       := identifier "(" expression ("," expression)* ")"
+/// ```
+///
+/// `^` is right associative and binds tighter than a leading minus, so
+/// `-2^2` is -4 and `2^3^2` is 512.
+struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    /// Height of the tree being built, evaluating it recurses that deep.
+    depth: usize,
+}
+
+/// Deeper nesting of parentheses, minus signs, calls and chained operators
+/// is rejected, parsing and evaluating it could overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.current].clone();
+        if token.kind != TokenKind::End {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn statement(&mut self) -> Result<Statement, ExpressionError> {
+        if let [Token {
+            kind: TokenKind::Identifier(name),
+            ..
+        }, Token {
+            kind: TokenKind::Assign,
+            ..
+        }, ..] = &self.tokens[self.current..]
+        {
+            let name = name.clone();
+            self.current += 2;
+            let value = self.expression()?;
+            return Ok(Statement::Assignment { name, value });
+        }
+        Ok(Statement::Expression(self.expression()?))
+    }
+
+    fn nest(&mut self) -> Result<(), ExpressionError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ExpressionError::new(
+                "expression is nested too deeply",
+                self.peek().position,
+            ));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<Expr, ExpressionError> {
+        let depth = self.depth;
+        let mut left = self.term()?;
+        while let TokenKind::Operator(operator @ (Operator::Add | Operator::Sub)) = self.peek().kind
+        {
+            // every operator puts the expression so far one level deeper
+            self.nest()?;
+            let position = self.advance().position;
+            let right = self.term()?;
+            left = Expr::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+                position,
+            };
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, ExpressionError> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        while let TokenKind::Operator(operator @ (Operator::Mul | Operator::Div | Operator::Mod)) =
+            self.peek().kind
+        {
+            self.nest()?;
+            let position = self.advance().position;
+            let right = self.unary()?;
+            left = Expr::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+                position,
+            };
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExpressionError> {
+        self.nest()?;
+        let result = if self.peek().kind == TokenKind::Operator(Operator::Sub) {
+            let position = self.advance().position;
+            self.unary().map(|operand| Expr::Negate {
+                operand: Box::new(operand),
+                position,
+            })
+        } else {
+            self.power()
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn power(&mut self) -> Result<Expr, ExpressionError> {
+        let base = self.primary()?;
+        if self.peek().kind != TokenKind::Operator(Operator::Pow) {
+            return Ok(base);
+        }
+        let position = self.advance().position;
+        let exponent = self.unary()?;
+        Ok(Expr::Binary {
+            operator: Operator::Pow,
+            left: Box::new(base),
+            right: Box::new(exponent),
+            position,
+        })
+    }
+
+    fn call(&mut self, name: &str, position: usize) -> Result<Expr, ExpressionError> {
+        let callee = Callee::from_name(name).ok_or_else(|| {
+            ExpressionError::new(format!("unknown function '{}'", name), position)
+        })?;
+        let opening = self.advance();
+
+        let mut arguments = vec![self.expression()?];
+        while self.peek().kind == TokenKind::Comma {
+            self.advance();
+            arguments.push(self.expression()?);
+        }
+        let closing = self.advance();
+        if closing.kind != TokenKind::RightParen {
+            return Err(ExpressionError::new(
+                format!("expected ')' to close '(' at position {}", opening.position),
+                closing.position,
+            ));
+        }
+
+        match (callee, arguments.len()) {
+            (Callee::Unary(function), 1) => Ok(Expr::Call {
+                function,
+                argument: Box::new(arguments.remove(0)),
+                position,
+            }),
+            (Callee::Binary(operator), 2) => {
+                let right = arguments.remove(1);
+                Ok(Expr::Binary {
+                    operator,
+                    left: Box::new(arguments.remove(0)),
+                    right: Box::new(right),
+                    position,
+                })
+            }
+            (Callee::Unary(_), _) => Err(ExpressionError::new(
+                format!("{} takes one argument", name),
+                position,
+            )),
+            (Callee::Binary(_), _) => Err(ExpressionError::new(
+                format!("{} takes two arguments", name),
+                position,
+            )),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExpressionError> {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::Number(literal) => Ok(Expr::Number {
+                literal,
+                position: token.position,
+            }),
+            TokenKind::Identifier(name) if self.peek().kind == TokenKind::LeftParen => {
+                self.call(&name, token.position)
+            }
+            TokenKind::Identifier(name) => Ok(Expr::Variable {
+                name,
+                position: token.position,
+            }),
+            TokenKind::LeftParen => {
+                let inner = self.expression()?;
+                let closing = self.advance();
+                if closing.kind != TokenKind::RightParen {
+                    return Err(ExpressionError::new(
+                        format!("expected ')' to close '(' at position {}", token.position),
+                        closing.position,
+                    ));
+                }
+                Ok(inner)
+            }
+            TokenKind::End => Err(ExpressionError::new(
+                "unexpected end of expression",
+                token.position,
+            )),
+            TokenKind::RightParen => Err(ExpressionError::new("unexpected ')'", token.position)),
+            TokenKind::Assign => Err(ExpressionError::new("unexpected '='", token.position)),
+            TokenKind::Comma => Err(ExpressionError::new("unexpected ','", token.position)),
+            TokenKind::Operator(operator) => Err(ExpressionError::new(
+                format!("unexpected operator '{}'", operator),
+                token.position,
+            )),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Statement, ExpressionError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        current: 0,
+        depth: 0,
+    };
+    let statement = parser.statement()?;
+
+    let trailing = parser.peek();
+    match trailing.kind {
+        TokenKind::End => Ok(statement),
+        TokenKind::RightParen => Err(ExpressionError::new("unmatched ')'", trailing.position)),
+        _ => Err(ExpressionError::new(
+            "expected an operator",
+            trailing.position,
+        )),
+    }
+}
+
+impl Expr {
+    pub fn evaluate<N: Number>(&self, variables: &Variables) -> Result<N, ExpressionError> {
+        match self {
+            Expr::Number { literal, position } => {
+                N::parse(literal).map_err(|kind| ExpressionError::at(kind, *position))
+            }
+            Expr::Variable { name, position } => match variables.get(name) {
+                Some(literal) => {
+                    N::parse(literal).map_err(|kind| ExpressionError::at(kind, *position))
+                }
+                None => Err(ExpressionError::new(
+                    format!("unknown variable '{}'", name),
+                    *position,
+                )),
+            },
+            Expr::Negate { operand, position } => operand
+                .evaluate::<N>(variables)?
+                .negate()
+                .map_err(|kind| ExpressionError::at(kind, *position)),
+            Expr::Binary {
+                operator,
+                left,
+                right,
+                position,
+            } => {
+                let left = left.evaluate::<N>(variables)?;
+                let right = right.evaluate::<N>(variables)?;
+                N::apply(*operator, left, right)
+                    .map_err(|kind| ExpressionError::at(kind, *position))
+            }
+            Expr::Call {
+                function,
+                argument,
+                position,
+            } => N::call(*function, argument.evaluate::<N>(variables)?)
+                .map_err(|kind| ExpressionError::at(kind, *position)),
+        }
+    }
+}
+
+/// Evaluates an expression or assignment, storing assigned values back
+/// into `variables`.
+pub fn evaluate(
+    input: &str,
+    mode: Mode,
+    variables: &mut Variables,
+) -> Result<String, ExpressionError> {
+    match parse(input)? {
+        Statement::Expression(expr) => mode.evaluate(&expr, variables),
+        Statement::Assignment { name, value } => {
+            let value = mode.evaluate(&value, variables)?;
+            variables.insert(name, value.clone());
+            Ok(value)
+        }
+    }
+}
+
+#[test]
+fn precedence_and_parentheses() {
+    let evaluate = |input| evaluate(input, Mode::Integer, &mut Variables::new());
+    assert_eq!(evaluate("1 + 2 * 3"), Ok("7".into()));
+    assert_eq!(evaluate("(1 + 2) * 3"), Ok("9".into()));
+    assert_eq!(evaluate("10 - 4 - 3"), Ok("3".into()));
+    assert_eq!(evaluate("(3 + 4) * -2 / 7"), Ok("-2".into()));
+    assert_eq!(evaluate("--5"), Ok("5".into()));
+}
+
+#[test]
+fn powers_remainders_and_functions() {
+    let evaluate = |input| evaluate(input, Mode::Integer, &mut Variables::new());
+    assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok("512".into()));
+    assert_eq!(evaluate("-2 ^ 2"), Ok("-4".into()));
+    assert_eq!(evaluate("2 * 3 ^ 2 % 5"), Ok("3".into()));
+    assert_eq!(evaluate("-7 % 3"), Ok("-1".into()));
+    assert_eq!(evaluate("sqrt(17) + abs(-3)"), Ok("7".into()));
+    assert_eq!(
+        evaluate("factorial(gcd(12, 18)) / lcm(4, 6)"),
+        Ok("60".into())
+    );
+    assert_eq!(
+        evaluate("2 ^ -1"),
+        Err(ExpressionError::at(
+            CalcError::Domain("negative exponents need the rational, decimal or float mode".into()),
+            2
+        ))
+    );
+    assert_eq!(
+        evaluate("1 + sqrt(-4)"),
+        Err(ExpressionError::at(
+            CalcError::Domain("square root of a negative number".into()),
+            4
+        ))
+    );
+}
+
+#[test]
+fn function_call_errors() {
+    let error = |input| {
+        evaluate(input, Mode::Integer, &mut Variables::new())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error("sine(1)"), "unknown function 'sine' at position 0");
+    assert_eq!(
+        error("1 + sqrt(1, 2)"),
+        "sqrt takes one argument at position 4"
+    );
+    assert_eq!(error("gcd(1)"), "gcd takes two arguments at position 0");
+    assert_eq!(
+        error("abs(1"),
+        "expected ')' to close '(' at position 3 at position 5"
+    );
+    assert_eq!(error("1, 2"), "expected an operator at position 1");
+}
+
+#[test]
+fn parse_errors_carry_position() {
+    let position = |input| {
+        evaluate(input, Mode::Integer, &mut Variables::new())
+            .unwrap_err()
+            .position
+    };
+    assert_eq!(position("1 + * 2"), 4);
+    assert_eq!(position("(1 + 2"), 6);
+    assert_eq!(position("1 + 2)"), 5);
+    assert_eq!(position("2 x 3"), 2);
+    assert_eq!(position(""), 0);
+    assert_eq!(position("1 + 2.5"), 4);
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    // the stack size of a tokio worker
+    std::thread::Builder::new()
+        .stack_size(2 * 1024 * 1024)
+        .spawn(|| {
+            let evaluate = |input: &str| evaluate(input, Mode::Integer, &mut Variables::new());
+            let nested = |depth: usize| "(".repeat(depth) + "1" + &")".repeat(depth);
+            assert_eq!(evaluate(&nested(MAX_DEPTH - 1)), Ok("1".into()));
+            assert_eq!(
+                evaluate(&nested(2047)),
+                Err(ExpressionError::new(
+                    "expression is nested too deeply",
+                    MAX_DEPTH
+                ))
+            );
+            assert_eq!(
+                evaluate(&("-".repeat(4095) + "1")).unwrap_err().to_string(),
+                format!("expression is nested too deeply at position {}", MAX_DEPTH)
+            );
+            assert_eq!(
+                evaluate(&("2^".repeat(2000) + "1")).unwrap_err().kind,
+                CalcError::InvalidInput("expression is nested too deeply".into())
+            );
+            assert_eq!(evaluate(&("1+".repeat(200) + "1")), Ok("201".into()));
+            assert_eq!(
+                evaluate(&("1*".repeat(2047) + "1")).unwrap_err().kind,
+                CalcError::InvalidInput("expression is nested too deeply".into())
+            );
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn evaluation_errors() {
+    assert_eq!(
+        evaluate("1 / (2 - 2)", Mode::Integer, &mut Variables::new()),
+        Err(ExpressionError::at(CalcError::DivisionByZero, 2))
+    );
+    assert_eq!(
+        evaluate(
+            "9223372036854775807 + 1",
+            Mode::Integer,
+            &mut Variables::new()
+        ),
+        Err(ExpressionError::at(CalcError::Overflow, 20))
+    );
+}
+
+#[test]
+fn expressions_in_other_modes() {
+    let mut variables = Variables::new();
+    assert_eq!(
+        evaluate("7 / 2", Mode::Rational, &mut variables),
+        Ok("7/2".into())
+    );
+    assert_eq!(
+        evaluate(
+            "1.5 * (2 - 0.25)",
+            Mode::Decimal { scale: 3 },
+            &mut variables
+        ),
+        Ok("2.625".into())
+    );
+    assert_eq!(
+        evaluate("-7 / 2", Mode::Float, &mut variables),
+        Ok("-3.5".into())
+    );
+    assert_eq!(
+        evaluate("2 ^ -2 % (1/3)", Mode::Rational, &mut variables),
+        Ok("1/4".into())
+    );
+}
+
+#[test]
+fn variables_and_assignment() {
+    let mut variables = Variables::new();
+    assert_eq!(
+        evaluate("x = 5", Mode::Integer, &mut variables),
+        Ok("5".into())
+    );
+    assert_eq!(
+        evaluate("x + 1", Mode::Integer, &mut variables),
+        Ok("6".into())
+    );
+    assert_eq!(
+        evaluate("half = x / 2", Mode::Rational, &mut variables),
+        Ok("5/2".into())
+    );
+    assert_eq!(
+        evaluate("half * 2", Mode::Integer, &mut variables)
+            .unwrap_err()
+            .position,
+        0
+    );
+    assert_eq!(
+        evaluate("y + 1", Mode::Integer, &mut variables),
+        Err(ExpressionError::new("unknown variable 'y'", 0))
+    );
+    assert_eq!(
+        evaluate("x = = 1", Mode::Integer, &mut variables)
+            .unwrap_err()
+            .position,
+        4
+    );
+}
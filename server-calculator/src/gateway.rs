@@ -0,0 +1,709 @@
+use crate::auth::AuthInterceptor;
+use crate::calculator_service::calculator_service_server::CalculatorService;
+use crate::calculator_service::{
+    evaluation_result, CalcInput, CalcOutput, CreateSessionRequest, ErrorCode, EvaluationResult,
+    ExpressionRequest, MemoryOperation, MemoryRequest, NumberMode, UnaryInput,
+};
+use crate::error::ERROR_CODE_HEADER;
+use crate::limit::{LimitLayer, Rejection, RETRY_AFTER_HEADER};
+use crate::metrics::MetricsLayer;
+use crate::service::Calculator;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tonic::codegen::Service;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::{Code, Request, Response, Status};
+use tower::layer::Layer;
+
+/// Request bodies larger than this are rejected without being parsed.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Operations served under `/v1/`.
+const OPERATIONS: &[&str] = &[
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "pow",
+    "mod",
+    "gcd",
+    "lcm",
+    "sqrt",
+    "factorial",
+    "abs",
+    "evaluate",
+    "memory",
+    "sessions",
+];
+
+/// Number modes by the name used in JSON bodies, e.g. `"big_integer"`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ModeName {
+    #[default]
+    Integer,
+    BigInteger,
+    Rational,
+    Decimal,
+    Float,
+}
+
+impl From<ModeName> for NumberMode {
+    fn from(mode: ModeName) -> Self {
+        match mode {
+            ModeName::Integer => NumberMode::Integer,
+            ModeName::BigInteger => NumberMode::BigInteger,
+            ModeName::Rational => NumberMode::Rational,
+            ModeName::Decimal => NumberMode::Decimal,
+            ModeName::Float => NumberMode::Float,
+        }
+    }
+}
+
+/// JSON numbers are accepted for convenience, numbers that do not fit a
+/// double, fractions and exact decimals have to be sent as strings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Operand {
+    Number(serde_json::Number),
+    Text(String),
+}
+
+impl Operand {
+    fn into_literal(self) -> String {
+        match self {
+            Operand::Number(number) => number.to_string(),
+            Operand::Text(text) => text,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BinaryBody {
+    a: Operand,
+    b: Operand,
+    #[serde(default)]
+    mode: ModeName,
+    #[serde(default)]
+    scale: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnaryBody {
+    a: Operand,
+    #[serde(default)]
+    mode: ModeName,
+    #[serde(default)]
+    scale: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpressionBody {
+    expression: String,
+    #[serde(default)]
+    mode: ModeName,
+    #[serde(default)]
+    scale: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MemoryName {
+    Recall,
+    Add,
+    Subtract,
+    Clear,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemoryBody {
+    operation: MemoryName,
+    #[serde(default)]
+    mode: ModeName,
+    #[serde(default)]
+    scale: u32,
+}
+
+/// Status of a calculation that failed. Malformed input is the client's
+/// fault, other failures are well-formed requests that cannot be computed.
+fn calculation_status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::NoError => StatusCode::OK,
+        ErrorCode::InvalidInput | ErrorCode::SessionRequired => StatusCode::BAD_REQUEST,
+        ErrorCode::UnknownSession => StatusCode::NOT_FOUND,
+        ErrorCode::DivisionByZero | ErrorCode::Overflow | ErrorCode::DomainError => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+    }
+}
+
+/// The usual mapping of gRPC status codes onto HTTP.
+fn grpc_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Error bodies look like `{"error": {"code": "DivisionByZero", ...}}`,
+/// the code is also sent as the `calc-error-code` header like the gRPC
+/// metadata, which is what the metrics count errors by.
+fn error_json(status: StatusCode, code: &str, error: Value) -> hyper::Response<Body> {
+    let mut response = json_response(status, json!({ "error": error }));
+    if let Ok(value) = HeaderValue::from_str(code) {
+        response.headers_mut().insert(ERROR_CODE_HEADER, value);
+    }
+    response
+}
+
+fn error_response(status: StatusCode, code: &str, message: &str) -> hyper::Response<Body> {
+    error_json(status, code, json!({ "code": code, "message": message }))
+}
+
+fn status_response(status: Status) -> hyper::Response<Body> {
+    let grpc_code = format!("{:?}", status.code());
+    let code = status
+        .metadata()
+        .get(ERROR_CODE_HEADER)
+        .and_then(|code| code.to_str().ok())
+        .unwrap_or(&grpc_code);
+    error_response(grpc_status(status.code()), code, status.message())
+}
+
+fn output_response(output: CalcOutput) -> hyper::Response<Body> {
+    match ErrorCode::from_i32(output.error) {
+        Some(ErrorCode::NoError) => {
+            json_response(StatusCode::OK, json!({ "result": output.result }))
+        }
+        code => {
+            let code = code.unwrap_or(ErrorCode::InvalidInput);
+            error_response(
+                calculation_status(code),
+                &format!("{:?}", code),
+                &output.message,
+            )
+        }
+    }
+}
+
+fn evaluation_response(result: EvaluationResult) -> hyper::Response<Body> {
+    match result.outcome {
+        Some(evaluation_result::Outcome::Value(value)) => {
+            json_response(StatusCode::OK, json!({ "result": value }))
+        }
+        Some(evaluation_result::Outcome::Error(error)) => {
+            let code = ErrorCode::from_i32(error.code).unwrap_or(ErrorCode::InvalidInput);
+            let name = format!("{:?}", code);
+            error_json(
+                calculation_status(code),
+                &name,
+                json!({
+                    "code": name,
+                    "message": error.message,
+                    "position": error.position,
+                }),
+            )
+        }
+        None => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal",
+            "empty evaluation result",
+        ),
+    }
+}
+
+/// Limited clients get `429 Too Many Requests` with the usual `Retry-After`
+/// header in seconds, and the exact hint in milliseconds.
+impl Rejection for Body {
+    fn rejection(message: &str, retry_after: Duration) -> hyper::Response<Self> {
+        let mut response = error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &format!("{:?}", Code::ResourceExhausted),
+            message,
+        );
+        let millis = retry_after.as_millis().max(1);
+        let headers = response.headers_mut();
+        headers.insert(RETRY_AFTER_HEADER, HeaderValue::from(millis as u64));
+        headers.insert(RETRY_AFTER, HeaderValue::from(millis.div_ceil(1000) as u64));
+        response
+    }
+}
+
+/// Serves the calculator as JSON over HTTP, e.g. `POST /v1/add` with
+/// `{"a": 1, "b": 2}`. Requests go through the same `Calculator`,
+/// authentication, limits and metrics as gRPC ones, headers are passed on
+/// as metadata so the `authorization` and `session-token` headers work the
+/// same way.
+#[derive(Clone)]
+pub struct Gateway {
+    calculator: Calculator,
+    auth: AuthInterceptor,
+    limits: LimitLayer,
+    metrics: MetricsLayer,
+}
+
+impl Gateway {
+    pub fn new(calculator: Calculator, auth: AuthInterceptor) -> Self {
+        Gateway {
+            calculator,
+            auth,
+            limits: LimitLayer::default(),
+            metrics: MetricsLayer::new(
+                OPERATIONS
+                    .iter()
+                    .map(|operation| format!("/v1/{}", operation)),
+            ),
+        }
+    }
+
+    /// Limits requests together with the gRPC server these limits are
+    /// shared with, clients get one budget across both.
+    pub fn with_limits(mut self, limits: LimitLayer) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The gateway behind the same layers as the gRPC services, for the
+    /// connection from `peer`.
+    fn service(
+        &self,
+        peer: Option<TcpConnectInfo>,
+    ) -> impl Service<
+        hyper::Request<Body>,
+        Response = hyper::Response<Body>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<hyper::Response<Body>, Infallible>> + Send,
+    > {
+        let gateway = self.clone();
+        let handler = service_fn(move |mut request: hyper::Request<Body>| {
+            // limits tell clients apart by it like for gRPC connections
+            if let Some(peer) = &peer {
+                request.extensions_mut().insert(peer.clone());
+            }
+            gateway.clone().handle(request)
+        });
+        self.metrics.layer(self.limits.layer(handler))
+    }
+
+    pub async fn handle(
+        self,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Infallible> {
+        let path = request.uri().path().to_string();
+        let operation = match path.strip_prefix("/v1/") {
+            Some(operation) => operation,
+            None => {
+                return Ok(error_response(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    "not found",
+                ))
+            }
+        };
+        if request.method() != Method::POST {
+            let mut response = error_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                "only POST is supported",
+            );
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("POST"));
+            return Ok(response);
+        }
+
+        let (parts, body) = request.into_parts();
+        if declared_length(&parts.headers, &body) > MAX_BODY_SIZE as u64 {
+            return Ok(body_too_large());
+        }
+        let metadata = MetadataMap::from_headers(parts.headers);
+        let mut probe = Request::new(());
+        *probe.metadata_mut() = metadata.clone();
+        if let Err(status) = self.auth.clone().call(probe) {
+            return Ok(status_response(status));
+        }
+
+        let body = match read_body(body).await {
+            Ok(body) => body,
+            Err(response) => return Ok(response),
+        };
+
+        let response = self.dispatch(operation, &body, metadata).await;
+        tracing::debug!(%path, status = response.status().as_u16(), "gateway request");
+        Ok(response)
+    }
+
+    async fn dispatch(
+        &self,
+        operation: &str,
+        body: &[u8],
+        metadata: MetadataMap,
+    ) -> hyper::Response<Body> {
+        let calculator = &self.calculator;
+        let result = match operation {
+            "add" | "sub" | "mul" | "div" | "pow" | "mod" | "gcd" | "lcm" => {
+                let body: BinaryBody = match parse(body) {
+                    Ok(body) => body,
+                    Err(error) => return invalid_body(error),
+                };
+                let input = request(
+                    &metadata,
+                    CalcInput {
+                        a: body.a.into_literal(),
+                        b: body.b.into_literal(),
+                        mode: NumberMode::from(body.mode) as i32,
+                        scale: body.scale,
+                    },
+                );
+                let output = match operation {
+                    "add" => calculator.add(input).await,
+                    "sub" => calculator.sub(input).await,
+                    "mul" => calculator.mul(input).await,
+                    "div" => calculator.div(input).await,
+                    "pow" => calculator.pow(input).await,
+                    "mod" => calculator.r#mod(input).await,
+                    "gcd" => calculator.gcd(input).await,
+                    _ => calculator.lcm(input).await,
+                };
+                output.map(|output| output_response(output.into_inner()))
+            }
+            "sqrt" | "factorial" | "abs" => {
+                let body: UnaryBody = match parse(body) {
+                    Ok(body) => body,
+                    Err(error) => return invalid_body(error),
+                };
+                let input = request(
+                    &metadata,
+                    UnaryInput {
+                        a: body.a.into_literal(),
+                        mode: NumberMode::from(body.mode) as i32,
+                        scale: body.scale,
+                    },
+                );
+                let output = match operation {
+                    "sqrt" => calculator.sqrt(input).await,
+                    "factorial" => calculator.factorial(input).await,
+                    _ => calculator.abs(input).await,
+                };
+                output.map(|output| output_response(output.into_inner()))
+            }
+            "evaluate" => {
+                let body: ExpressionBody = match parse(body) {
+                    Ok(body) => body,
+                    Err(error) => return invalid_body(error),
+                };
+                let input = request(
+                    &metadata,
+                    ExpressionRequest {
+                        expression: body.expression,
+                        mode: NumberMode::from(body.mode) as i32,
+                        scale: body.scale,
+                    },
+                );
+                CalculatorService::evaluate(calculator, input)
+                    .await
+                    .map(|result| evaluation_response(result.into_inner()))
+            }
+            "memory" => {
+                let body: MemoryBody = match parse(body) {
+                    Ok(body) => body,
+                    Err(error) => return invalid_body(error),
+                };
+                let operation = match body.operation {
+                    MemoryName::Recall => MemoryOperation::MemoryRecall,
+                    MemoryName::Add => MemoryOperation::MemoryAdd,
+                    MemoryName::Subtract => MemoryOperation::MemorySubtract,
+                    MemoryName::Clear => MemoryOperation::MemoryClear,
+                };
+                let input = request(
+                    &metadata,
+                    MemoryRequest {
+                        operation: operation as i32,
+                        mode: NumberMode::from(body.mode) as i32,
+                        scale: body.scale,
+                    },
+                );
+                CalculatorService::memory(calculator, input)
+                    .await
+                    .map(|result| evaluation_response(result.into_inner()))
+            }
+            "sessions" => calculator
+                .create_session(request(&metadata, CreateSessionRequest {}))
+                .await
+                .map(Response::into_inner)
+                .map(|session| {
+                    json_response(
+                        StatusCode::CREATED,
+                        json!({
+                            "token": session.token,
+                            "idle_timeout_seconds": session.idle_timeout_seconds,
+                        }),
+                    )
+                }),
+            _ => Ok(error_response(
+                StatusCode::NOT_FOUND,
+                "NotFound",
+                &format!("unknown operation '{}'", operation),
+            )),
+        };
+        result.unwrap_or_else(status_response)
+    }
+}
+
+/// The size the client announced, bodies claiming to be too large are
+/// rejected before any of them is read.
+fn declared_length(headers: &HeaderMap, body: &Body) -> u64 {
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    body.size_hint().lower().max(content_length)
+}
+
+fn body_too_large() -> hyper::Response<Body> {
+    error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "InvalidInput",
+        "request body is too large",
+    )
+}
+
+/// Reads the body chunk by chunk, giving up as soon as it gets larger than
+/// `MAX_BODY_SIZE` instead of buffering all of it first.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, hyper::Response<Body>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|error| {
+            error_response(StatusCode::BAD_REQUEST, "InvalidInput", &error.to_string())
+        })?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(body_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn request<T>(metadata: &MetadataMap, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    *request.metadata_mut() = metadata.clone();
+    request
+}
+
+/// Bodies are optional for operations without arguments.
+fn parse<T: for<'de> Deserialize<'de>>(body: &[u8]) -> serde_json::Result<T> {
+    let body = if body.is_empty() { &b"{}"[..] } else { body };
+    serde_json::from_slice(body)
+}
+
+fn invalid_body(error: serde_json::Error) -> hyper::Response<Body> {
+    error_response(StatusCode::BAD_REQUEST, "InvalidInput", &error.to_string())
+}
+
+/// Serves the gateway until `shutdown` resolves, then lets in-flight
+/// requests finish.
+pub async fn serve(
+    addr: SocketAddr,
+    gateway: Gateway,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let service = gateway.service(Some(conn.connect_info()));
+        async move { Ok::<_, Infallible>(service) }
+    });
+    hyper::Server::try_bind(&addr)?
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+async fn post(gateway: &Gateway, path: &str, body: &str) -> (StatusCode, Value) {
+    let request = hyper::Request::post(path)
+        .header("authorization", "Bearer s3cret")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = gateway.clone().handle(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn json_requests_map_onto_the_calculator() {
+    let gateway = Gateway::new(Calculator::default(), AuthInterceptor::default());
+
+    let (status, body) = post(&gateway, "/v1/add", r#"{"a": 1, "b": 2}"#).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "result": "3" }));
+
+    let (_, body) = post(
+        &gateway,
+        "/v1/div",
+        r#"{"a": "7", "b": "2", "mode": "rational"}"#,
+    )
+    .await;
+    assert_eq!(body["result"], "7/2");
+
+    let (status, body) = post(&gateway, "/v1/sqrt", r#"{"a": 17}"#).await;
+    assert_eq!(
+        (status, body["result"].as_str()),
+        (StatusCode::OK, Some("4"))
+    );
+
+    let (status, body) = post(&gateway, "/v1/evaluate", r#"{"expression": "2 * (3 + 4)"}"#).await;
+    assert_eq!(
+        (status, body["result"].as_str()),
+        (StatusCode::OK, Some("14"))
+    );
+
+    let (status, body) = post(&gateway, "/v1/sessions", "").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(body["token"].is_string());
+}
+
+#[tokio::test]
+async fn errors_map_onto_http_statuses() {
+    let gateway = Gateway::new(Calculator::default(), AuthInterceptor::default());
+
+    let (status, body) = post(&gateway, "/v1/div", r#"{"a": 1, "b": 0}"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error"]["code"], "DivisionByZero");
+
+    let (status, body) = post(&gateway, "/v1/evaluate", r#"{"expression": "1 +"}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["position"], 3);
+
+    let (status, body) = post(&gateway, "/v1/add", r#"{"a": 1}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["code"], "InvalidInput");
+
+    let (status, body) = post(&gateway, "/v1/memory", r#"{"operation": "recall"}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["code"], "SessionRequired");
+
+    let (status, _) = post(&gateway, "/v1/cosine", "{}").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let guarded = Gateway::new(
+        Calculator::default(),
+        AuthInterceptor::new(vec!["other".to_string()]),
+    );
+    let (status, body) = post(&guarded, "/v1/add", r#"{"a": 1, "b": 2}"#).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["code"], "Unauthenticated");
+}
+
+#[tokio::test]
+async fn large_bodies_are_rejected_before_being_read() {
+    let gateway = Gateway::new(Calculator::default(), AuthInterceptor::default());
+    let handle = |request| gateway.clone().handle(request);
+
+    let padding = " ".repeat(MAX_BODY_SIZE);
+    let (status, _) = post(
+        &gateway,
+        "/v1/add",
+        &format!(r#"{{"a": 1, "b": 2}}{}"#, padding),
+    )
+    .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // an announced length is enough, the body is never waited for
+    let (_sender, body) = Body::channel();
+    let request = hyper::Request::post("/v1/add")
+        .header(CONTENT_LENGTH, MAX_BODY_SIZE + 1)
+        .body(body)
+        .unwrap();
+    let response = handle(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // without one the body is read until it gets too large
+    let (mut sender, body) = Body::channel();
+    let chunks = tokio::spawn(async move {
+        let mut sent = 0;
+        while sender.send_data(vec![b' '; 1024].into()).await.is_ok() {
+            sent += 1;
+        }
+        sent
+    });
+    let request = hyper::Request::post("/v1/add").body(body).unwrap();
+    let response = handle(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(chunks.await.unwrap() <= MAX_BODY_SIZE / 1024 + 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_are_limited_and_counted() {
+    use crate::limit::LimitConfig;
+
+    let limits = LimitLayer::new(
+        &LimitConfig {
+            requests_per_second: Some(1),
+            ..LimitConfig::default()
+        },
+        AuthInterceptor::default(),
+    );
+    let gateway =
+        Gateway::new(Calculator::default(), AuthInterceptor::default()).with_limits(limits);
+    let mut service = gateway.service(None);
+    let request = || {
+        hyper::Request::post("/v1/gcd")
+            .body(Body::from(r#"{"a": 12, "b": 18}"#))
+            .unwrap()
+    };
+
+    let response = service.call(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = service.call(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[RETRY_AFTER_HEADER], "1000");
+    assert_eq!(response.headers()[RETRY_AFTER], "1");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "ResourceExhausted");
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    let response = service.call(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let metrics = String::from_utf8(crate::metrics::gather()).unwrap();
+    assert!(metrics.contains(r#"calculator_grpc_requests_total{method="/v1/gcd"} 3"#));
+    assert!(metrics
+        .contains(r#"calculator_grpc_errors_total{code="ResourceExhausted",method="/v1/gcd"} 1"#));
+}
@@ -0,0 +1,23 @@
+pub mod calculator_service {
+    // name of the grpc package
+    tonic::include_proto!("calculator_service");
+
+    /// Encoded descriptors of the proto file, served through reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("calculator_service_descriptor");
+}
+
+pub mod auth;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod error;
+pub mod expression;
+pub mod gateway;
+pub mod limit;
+pub mod logging;
+pub mod metrics;
+pub mod number;
+pub mod service;
+pub mod session;
+pub mod testing;
@@ -0,0 +1,428 @@
+use crate::auth::{AuthInterceptor, AUTHORIZATION_HEADER, BEARER_PREFIX};
+use dashmap::DashMap;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::Service;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::Status;
+use tower::layer::Layer;
+
+/// Metadata key telling a rejected client how many milliseconds to wait
+/// before trying again.
+pub const RETRY_AFTER_HEADER: &str = "retry-after-ms";
+/// Retry hint given when the server is at its concurrency cap, there is no
+/// way to tell when a slot frees up.
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_millis(100);
+/// Health checks are never limited, an overloaded server failing them would
+/// make things worse.
+const HEALTH_SERVICE_PREFIX: &str = "/grpc.health.v1.Health/";
+
+/// What requests are grouped by for rate limiting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitKey {
+    #[default]
+    Peer,
+    /// The bearer token if it is one of the configured tokens, the peer
+    /// address otherwise. Made up tokens do not get a bucket of their own.
+    Token,
+}
+
+impl FromStr for LimitKey {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        match key.to_lowercase().as_str() {
+            "peer" => Ok(LimitKey::Peer),
+            "token" => Ok(LimitKey::Token),
+            other => Err(format!("unknown rate limit key '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Sustained requests per second allowed for each key. Unset disables
+    /// rate limiting.
+    pub requests_per_second: Option<u32>,
+    /// Requests a key may make at once after being idle, defaults to
+    /// `requests_per_second`.
+    pub burst: Option<u32>,
+    pub key: LimitKey,
+    /// Requests handled at the same time across all clients. Requests over
+    /// the cap are shed instead of queued.
+    pub max_concurrent_requests: Option<usize>,
+}
+
+impl LimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.requests_per_second.is_some() || self.max_concurrent_requests.is_some()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the key's bucket, or returns how long it takes
+    /// until one is available.
+    fn acquire(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drops buckets that have refilled completely, they are no different
+    /// from a new one.
+    fn purge_full(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct LimitLayer {
+    key: LimitKey,
+    /// Tells which bearer tokens are known when limiting by token.
+    auth: AuthInterceptor,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl LimitLayer {
+    pub fn new(config: &LimitConfig, auth: AuthInterceptor) -> Self {
+        let rate_limiter = config.requests_per_second.map(|rate| {
+            Arc::new(RateLimiter {
+                rate: f64::from(rate.max(1)),
+                burst: f64::from(config.burst.unwrap_or(rate).max(1)),
+                buckets: DashMap::new(),
+            })
+        });
+        LimitLayer {
+            key: config.key,
+            auth,
+            rate_limiter,
+            concurrency: config
+                .max_concurrent_requests
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+        }
+    }
+
+    /// Periodically forgets clients that have not been limited recently.
+    pub fn spawn_cleanup(&self, interval: Duration) -> Option<JoinHandle<()>> {
+        let rate_limiter = self.rate_limiter.clone()?;
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                rate_limiter.purge_full();
+            }
+        }))
+    }
+}
+
+impl<S> Layer<S> for LimitLayer {
+    type Service = LimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LimitService {
+            limits: self.clone(),
+            service,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LimitService<S> {
+    limits: LimitLayer,
+    service: S,
+}
+
+fn limit_key<B>(request: &Request<B>, limits: &LimitLayer) -> String {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .filter(|token| limits.auth.accepts(token));
+    match (limits.key, token) {
+        (LimitKey::Token, Some(token)) => {
+            // keeps tokens out of memory dumps and the log
+            let mut hasher = DefaultHasher::new();
+            token.hash(&mut hasher);
+            format!("token:{:016x}", hasher.finish())
+        }
+        _ => {
+            let peer = request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr());
+            match peer {
+                Some(peer) => format!("peer:{}", peer.ip()),
+                None => "peer:unknown".to_string(),
+            }
+        }
+    }
+}
+
+/// Response bodies of services that can be limited, a rejection is told
+/// to the client in the protocol of the service.
+pub trait Rejection: Sized {
+    fn rejection(message: &str, retry_after: Duration) -> Response<Self>;
+}
+
+/// gRPC clients get `RESOURCE_EXHAUSTED` with the retry hint as metadata.
+impl Rejection for BoxBody {
+    fn rejection(message: &str, retry_after: Duration) -> Response<Self> {
+        let mut status = Status::resource_exhausted(message);
+        let millis = retry_after.as_millis().max(1).to_string();
+        if let Ok(value) = millis.parse() {
+            status.metadata_mut().insert(RETRY_AFTER_HEADER, value);
+        }
+        status.to_http()
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Rejection,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LimitFuture<S::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        if request.uri().path().starts_with(HEALTH_SERVICE_PREFIX) {
+            return LimitFuture::Admitted {
+                future: self.service.call(request),
+                permit: None,
+            };
+        }
+
+        if let Some(rate_limiter) = &self.limits.rate_limiter {
+            let key = limit_key(&request, &self.limits);
+            if let Err(retry_after) = rate_limiter.acquire(&key) {
+                tracing::warn!(
+                    key = %key,
+                    retry_after_ms = retry_after.as_millis() as u64,
+                    "rate limited"
+                );
+                return LimitFuture::Rejected {
+                    response: Some(ResBody::rejection("rate limit exceeded", retry_after)),
+                };
+            }
+        }
+
+        let permit = match &self.limits.concurrency {
+            Some(concurrency) => match concurrency.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    tracing::warn!("load shed, too many concurrent requests");
+                    return LimitFuture::Rejected {
+                        response: Some(ResBody::rejection(
+                            "server overloaded",
+                            OVERLOADED_RETRY_AFTER,
+                        )),
+                    };
+                }
+            },
+            None => None,
+        };
+
+        LimitFuture::Admitted {
+            future: self.service.call(request),
+            permit,
+        }
+    }
+}
+
+pin_project! {
+    /// Holds the concurrency permit until the response headers have been
+    /// produced. Streamed response bodies do not count against the cap.
+    #[project = LimitFutureProj]
+    pub enum LimitFuture<F, B> {
+        Admitted {
+            #[pin]
+            future: F,
+            permit: Option<OwnedSemaphorePermit>,
+        },
+        Rejected {
+            response: Option<Response<B>>,
+        },
+    }
+}
+
+impl<F, B, E> Future for LimitFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            LimitFutureProj::Admitted { future, permit } => {
+                let result = match future.poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                };
+                permit.take();
+                Poll::Ready(result)
+            }
+            LimitFutureProj::Rejected { response } => Poll::Ready(Ok(response
+                .take()
+                .expect("rejected future polled after completion"))),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_service(
+    config: LimitConfig,
+    delay: Duration,
+) -> LimitService<
+    impl Service<Request<()>, Response = Response<BoxBody>, Error = std::convert::Infallible>,
+> {
+    struct Slow(Duration);
+
+    impl Service<Request<()>> for Slow {
+        type Response = Response<BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<()>) -> Self::Future {
+            let delay = self.0;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(Response::new(tonic::body::empty_body()))
+            })
+        }
+    }
+
+    let auth = AuthInterceptor::new(vec!["first".into(), "second".into()]);
+    LimitLayer::new(&config, auth).layer(Slow(delay))
+}
+
+#[cfg(test)]
+fn retry_after(response: &Response<BoxBody>) -> Option<u64> {
+    assert_eq!(response.headers()["grpc-status"], "8");
+    response.headers()[RETRY_AFTER_HEADER]
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+#[tokio::test(start_paused = true)]
+async fn token_buckets_refill() {
+    let mut service = test_service(
+        LimitConfig {
+            requests_per_second: Some(2),
+            burst: Some(3),
+            key: LimitKey::Token,
+            ..LimitConfig::default()
+        },
+        Duration::ZERO,
+    );
+    let request = |token: &str| {
+        Request::builder()
+            .header(AUTHORIZATION_HEADER, format!("{}{}", BEARER_PREFIX, token))
+            .body(())
+            .unwrap()
+    };
+
+    for _ in 0..3 {
+        let response = service.call(request("first")).await.unwrap();
+        assert!(response.headers().get("grpc-status").is_none());
+    }
+    let rejected = service.call(request("first")).await.unwrap();
+    assert_eq!(retry_after(&rejected), Some(500));
+    assert!(service.call(request("second")).await.is_ok());
+
+    tokio::time::advance(Duration::from_millis(500)).await;
+    let response = service.call(request("first")).await.unwrap();
+    assert!(response.headers().get("grpc-status").is_none());
+
+    // unknown tokens share the bucket of the peer, so a client cannot
+    // dodge the limit by making up a new token for every request
+    for i in 0..3 {
+        let response = service
+            .call(request(&format!("random{}", i)))
+            .await
+            .unwrap();
+        assert!(response.headers().get("grpc-status").is_none());
+    }
+    let rejected = service.call(request("random3")).await.unwrap();
+    assert_eq!(retry_after(&rejected), Some(500));
+    let rejected = service.call(Request::new(())).await.unwrap();
+    assert_eq!(retry_after(&rejected), Some(500));
+}
+
+#[tokio::test(start_paused = true)]
+async fn excess_concurrent_requests_are_shed() {
+    let mut service = test_service(
+        LimitConfig {
+            max_concurrent_requests: Some(1),
+            ..LimitConfig::default()
+        },
+        Duration::from_secs(1),
+    );
+
+    let first = service.call(Request::new(()));
+    let shed = service.call(Request::new(())).await.unwrap();
+    assert_eq!(
+        retry_after(&shed),
+        Some(OVERLOADED_RETRY_AFTER.as_millis() as u64)
+    );
+
+    first.await.unwrap();
+    let response = service.call(Request::new(())).await.unwrap();
+    assert!(response.headers().get("grpc-status").is_none());
+}
@@ -0,0 +1,215 @@
+use crate::error::ERROR_CODE_HEADER;
+use crate::limit::RETRY_AFTER_HEADER;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http::{HeaderValue, Request, Response};
+use tonic::codegen::Service;
+use tonic::transport::server::TcpConnectInfo;
+use tower::layer::Layer;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+/// Header used to correlate log lines with a request. Generated when the
+/// client does not send one and always echoed back on the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Environment variable holding the log filter, e.g. `info` or
+/// `server_calculator=debug`.
+pub const LOG_FILTER_ENV: &str = "CALCULATOR_LOG";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}'", other)),
+        }
+    }
+}
+
+/// Installs the global subscriber. The filter is read from
+/// [`LOG_FILTER_ENV`] and defaults to `info`.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_env(LOG_FILTER_ENV).unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+fn generate_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+#[derive(Clone)]
+pub struct LogService<S> {
+    service: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LogService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Display,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LogFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(generate_request_id);
+        let peer = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+            .map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+
+        let span = tracing::info_span!(
+            "grpc",
+            method = %request.uri().path(),
+            peer = %peer,
+            request_id = %request_id,
+        );
+        let future = span.in_scope(|| {
+            tracing::debug!("request started");
+            self.service.call(request)
+        });
+
+        LogFuture {
+            future,
+            span,
+            request_id: HeaderValue::from_str(&request_id).ok(),
+            start: Instant::now(),
+        }
+    }
+}
+
+pin_project! {
+    /// Logs the outcome and latency of the request once the inner service
+    /// produces a response.
+    pub struct LogFuture<F> {
+        #[pin]
+        future: F,
+        span: Span,
+        request_id: Option<HeaderValue>,
+        start: Instant,
+    }
+}
+
+impl<F, ResBody, E> Future for LogFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    E: Display,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _entered = this.span.enter();
+        let mut result = match this.future.poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let latency_ms = this.start.elapsed().as_secs_f64() * 1000.0;
+        match &mut result {
+            Ok(response) => {
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value: &HeaderValue| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                // an error status is sent in the headers of a trailers-only
+                // response, successful calls carry it in the trailers
+                let grpc_status = header("grpc-status").unwrap_or_else(|| "0".to_string());
+                let error_code = header(ERROR_CODE_HEADER);
+                let retry_after_ms = header(RETRY_AFTER_HEADER);
+                tracing::info!(
+                    latency_ms,
+                    http_status = response.status().as_u16(),
+                    grpc_status = %grpc_status,
+                    error_code = error_code.as_deref().unwrap_or("none"),
+                    retry_after_ms = retry_after_ms.as_deref().unwrap_or("none"),
+                    "request completed"
+                );
+                if let Some(request_id) = this.request_id.take() {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+                }
+            }
+            Err(error) => tracing::error!(latency_ms, %error, "request failed"),
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct LogLayer;
+
+impl<S> Layer<S> for LogLayer {
+    type Service = LogService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LogService { service }
+    }
+}
+
+#[tokio::test]
+async fn request_id_is_echoed() {
+    use std::convert::Infallible;
+    use std::future::Ready;
+
+    struct Empty;
+
+    impl Service<Request<()>> for Empty {
+        type Response = Response<()>;
+        type Error = Infallible;
+        type Future = Ready<Result<Response<()>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<()>) -> Self::Future {
+            std::future::ready(Ok(Response::new(())))
+        }
+    }
+
+    let mut service = LogLayer.layer(Empty);
+    let request = Request::builder()
+        .header(REQUEST_ID_HEADER, "abc123")
+        .body(())
+        .unwrap();
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.headers()[REQUEST_ID_HEADER], "abc123");
+
+    let response = service.call(Request::new(())).await.unwrap();
+    assert_eq!(response.headers()[REQUEST_ID_HEADER].len(), 16);
+    assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+}
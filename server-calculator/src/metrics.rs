@@ -0,0 +1,243 @@
+use crate::error::ERROR_CODE_HEADER;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
+use lazy_static::lazy_static;
+use pin_project_lite::pin_project;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::Service;
+use tower::layer::Layer;
+
+/// The label of requests for paths none of the services has.
+const UNKNOWN_METHOD: &str = "unknown";
+
+lazy_static! {
+    static ref GRPC_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "calculator_grpc_requests_total",
+        "the total number of gRPC requests handled, by method",
+        &["method"]
+    )
+    .unwrap();
+    static ref GRPC_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "calculator_grpc_errors_total",
+        "the number of failed gRPC requests, by method and error code",
+        &["method", "code"]
+    )
+    .unwrap();
+    static ref GRPC_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "calculator_grpc_request_duration_seconds",
+        "the time taken to produce a gRPC response, by method",
+        &["method"]
+    )
+    .unwrap();
+}
+
+/// The error a response carries: the calculator error code if the server
+/// tagged one, otherwise the gRPC status if it is not `Ok`.
+fn error_code(headers: &HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(code) = header(ERROR_CODE_HEADER) {
+        return Some(code.to_string());
+    }
+    match header("grpc-status").and_then(|status| status.parse::<i32>().ok()) {
+        None | Some(0) => None,
+        Some(status) => Some(format!("{:?}", tonic::Code::from_i32(status))),
+    }
+}
+
+pub fn gather() -> Vec<u8> {
+    let metrics = prometheus::gather();
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder.encode(&metrics, &mut buf).unwrap();
+    buf
+}
+
+async fn handle(request: hyper::Request<Body>) -> Result<hyper::Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => hyper::Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                hyper::header::CONTENT_TYPE,
+                TextEncoder::new().format_type(),
+            )
+            .body(Body::from(gather())),
+        _ => hyper::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+/// Serves the Prometheus text format on `/metrics` until the server fails.
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+    hyper::Server::try_bind(&addr)?.serve(make_svc).await
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    service: S,
+    methods: Arc<HashSet<String>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = MetricsFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let method = match request.uri().path() {
+            path if self.methods.contains(path) => path.to_string(),
+            _ => UNKNOWN_METHOD.to_string(),
+        };
+        GRPC_REQUESTS_TOTAL.with_label_values(&[&method]).inc();
+        MetricsFuture {
+            future: self.service.call(request),
+            method,
+            start: Instant::now(),
+        }
+    }
+}
+
+pin_project! {
+    pub struct MetricsFuture<F> {
+        #[pin]
+        future: F,
+        method: String,
+        start: Instant,
+    }
+}
+
+impl<F, ResBody, E> Future for MetricsFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = match this.future.poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        GRPC_REQUEST_DURATION_SECONDS
+            .with_label_values(&[this.method])
+            .observe(this.start.elapsed().as_secs_f64());
+        let code = match &result {
+            Ok(response) => error_code(response.headers()),
+            Err(_) => Some("Transport".to_string()),
+        };
+        if let Some(code) = code {
+            GRPC_ERRORS_TOTAL
+                .with_label_values(&[this.method, &code])
+                .inc();
+        }
+        Poll::Ready(result)
+    }
+}
+
+/// Records request counts, error counts and latencies for every RPC and
+/// gateway request.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    methods: Arc<HashSet<String>>,
+}
+
+impl MetricsLayer {
+    /// Labels requests with their path if it is one of `methods`, and all
+    /// others as `unknown`: the series of every path a client makes up would
+    /// be kept forever.
+    pub fn new<M: Into<String>>(methods: impl IntoIterator<Item = M>) -> Self {
+        MetricsLayer {
+            methods: Arc::new(methods.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MetricsService {
+            service,
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn requests_and_errors_are_counted() {
+    use crate::calculator_service::ErrorCode;
+    use std::future::Ready;
+    use tonic::codegen::http::HeaderValue;
+
+    struct Failing;
+
+    impl Service<Request<()>> for Failing {
+        type Response = Response<()>;
+        type Error = Infallible;
+        type Future = Ready<Result<Response<()>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<()>) -> Self::Future {
+            let mut response = Response::new(());
+            if request.uri().path().ends_with("Div") {
+                let code = format!("{:?}", ErrorCode::DivisionByZero);
+                response
+                    .headers_mut()
+                    .insert(ERROR_CODE_HEADER, HeaderValue::from_str(&code).unwrap());
+            }
+            std::future::ready(Ok(response))
+        }
+    }
+
+    let mut service = MetricsLayer::new(["/test.Metrics/Add", "/test.Metrics/Div"]).layer(Failing);
+    for path in [
+        "/test.Metrics/Add",
+        "/test.Metrics/Div",
+        "/test.Metrics/Div",
+        "/test.Metrics/Made/Up",
+        "/test.Metrics/Div/",
+    ] {
+        let request = Request::builder().uri(path).body(()).unwrap();
+        service.call(request).await.unwrap();
+    }
+
+    let text = String::from_utf8(gather()).unwrap();
+    assert!(text.contains(r#"calculator_grpc_requests_total{method="/test.Metrics/Div"} 2"#));
+    assert!(text.contains(
+        r#"calculator_grpc_errors_total{code="DivisionByZero",method="/test.Metrics/Div"} 2"#
+    ));
+    assert!(!text.contains(
+        r#"calculator_grpc_errors_total{code="DivisionByZero",method="/test.Metrics/Add"}"#
+    ));
+    assert!(text.contains(
+        r#"calculator_grpc_request_duration_seconds_count{method="/test.Metrics/Add"} 1"#
+    ));
+    // unknown paths share one series
+    assert!(text.contains(r#"calculator_grpc_requests_total{method="unknown"} 2"#));
+    assert!(!text.contains("/test.Metrics/Made/Up"));
+}
@@ -0,0 +1,763 @@
+use crate::calculator_service::NumberMode;
+use crate::error::CalcError;
+use crate::expression::{Expr, ExpressionError, Function, Operator, Variables};
+use num_bigint::BigInt;
+use num_integer::{Integer, Roots};
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Pow, Signed, ToPrimitive, Zero};
+use rust_decimal::{Decimal, MathematicalOps};
+use std::fmt::Display;
+use std::num::IntErrorKind;
+use std::str::FromStr;
+
+/// Largest scale `rust_decimal` can represent.
+pub const MAX_DECIMAL_SCALE: u32 = 28;
+pub const DEFAULT_DECIMAL_SCALE: u32 = 10;
+
+/// Results of products, powers and factorials in the arbitrary precision
+/// modes are capped, computing them would otherwise take unbounded time and
+/// memory.
+const MAX_RESULT_BITS: u64 = 1 << 20;
+const MAX_FACTORIAL: u64 = 10_000;
+
+/// A number domain the calculator can compute in.
+pub trait Number: Sized {
+    fn parse(literal: &str) -> Result<Self, CalcError>;
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError>;
+    fn negate(self) -> Result<Self, CalcError>;
+    fn call(function: Function, value: Self) -> Result<Self, CalcError>;
+}
+
+fn negative_exponent() -> CalcError {
+    CalcError::Domain("negative exponents need the rational, decimal or float mode".to_string())
+}
+
+fn fractional_exponent() -> CalcError {
+    CalcError::Domain("fractional exponents need the float mode".to_string())
+}
+
+fn negative_square_root() -> CalcError {
+    CalcError::Domain("square root of a negative number".to_string())
+}
+
+/// Gcd, lcm and factorials are computed on integers in every mode.
+fn require_integer(name: impl Display, integer: Option<BigInt>) -> Result<BigInt, CalcError> {
+    integer.ok_or_else(|| CalcError::Domain(format!("{} is only defined for integers", name)))
+}
+
+fn integer_pow(base: &BigInt, exponent: &BigInt) -> Result<BigInt, CalcError> {
+    if exponent.is_negative() {
+        return Err(negative_exponent());
+    }
+    // 0, 1 and -1 only depend on whether the exponent is zero or odd
+    let exponent = if base.bits() <= 1 && exponent > &BigInt::from(2) {
+        2 - u32::from(exponent.is_odd())
+    } else {
+        exponent.to_u32().ok_or(CalcError::Overflow)?
+    };
+    if base.bits().saturating_mul(u64::from(exponent)) > MAX_RESULT_BITS {
+        return Err(CalcError::Overflow);
+    }
+    Ok(base.pow(exponent))
+}
+
+/// Sums, products and quotients of rationals multiply numerators and
+/// denominators, the result has at most this many bits of both together.
+fn rational_bits(value: &BigRational) -> u64 {
+    value.numer().bits() + value.denom().bits()
+}
+
+/// Powers by squaring, `checked_powi` takes time linear in the exponent.
+fn decimal_pow(base: Decimal, exponent: Decimal) -> Option<Decimal> {
+    // 0, 1 and -1 only depend on whether the exponent is zero or odd
+    if base.abs() <= Decimal::ONE && base.fract().is_zero() && exponent > Decimal::TWO {
+        let odd = !(exponent % Decimal::TWO).is_zero();
+        return Some(if odd { base } else { base * base });
+    }
+    let negative = exponent.is_sign_negative();
+    let mut exponent = exponent.to_i64()?.unsigned_abs();
+    let (mut base, mut power) = (base, Decimal::ONE);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            power = power.checked_mul(base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    if negative {
+        Decimal::ONE.checked_div(power)
+    } else {
+        Some(power)
+    }
+}
+
+fn integer_factorial(value: &BigInt) -> Result<BigInt, CalcError> {
+    if value.is_negative() {
+        return Err(CalcError::Domain(
+            "factorial of a negative number".to_string(),
+        ));
+    }
+    match value.to_u64() {
+        Some(n) if n <= MAX_FACTORIAL => Ok((2..=n).product()),
+        _ => Err(CalcError::Overflow),
+    }
+}
+
+impl Number for i64 {
+    fn parse(literal: &str) -> Result<Self, CalcError> {
+        literal.parse::<i64>().map_err(|error| match error.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => CalcError::Overflow,
+            _ => CalcError::InvalidInput(format!("'{}' is not an integer", literal)),
+        })
+    }
+
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError> {
+        match operator {
+            Operator::Add => left.checked_add(right),
+            Operator::Sub => left.checked_sub(right),
+            Operator::Mul => left.checked_mul(right),
+            Operator::Div if right == 0 => return Err(CalcError::DivisionByZero),
+            Operator::Div => left.checked_div(right),
+            Operator::Pow if right < 0 => return Err(negative_exponent()),
+            Operator::Pow => match u32::try_from(right) {
+                Ok(exponent) => left.checked_pow(exponent),
+                Err(_) => match left {
+                    0 | 1 => Some(left),
+                    -1 if right % 2 == 0 => Some(1),
+                    -1 => Some(-1),
+                    _ => None,
+                },
+            },
+            Operator::Mod if right == 0 => return Err(CalcError::DivisionByZero),
+            Operator::Mod => left.checked_rem(right),
+            // on magnitudes, the gcd of i64::MIN and 0 does not fit an i64
+            Operator::Gcd => i64::try_from(left.unsigned_abs().gcd(&right.unsigned_abs())).ok(),
+            Operator::Lcm => {
+                let (left, right) = (left.unsigned_abs(), right.unsigned_abs());
+                match left.gcd(&right) {
+                    0 => Some(0),
+                    gcd => (left / gcd)
+                        .checked_mul(right)
+                        .and_then(|lcm| i64::try_from(lcm).ok()),
+                }
+            }
+        }
+        .ok_or(CalcError::Overflow)
+    }
+
+    fn negate(self) -> Result<Self, CalcError> {
+        self.checked_neg().ok_or(CalcError::Overflow)
+    }
+
+    fn call(function: Function, value: Self) -> Result<Self, CalcError> {
+        match function {
+            Function::Sqrt if value < 0 => Err(negative_square_root()),
+            Function::Sqrt => Ok(Roots::sqrt(&value)),
+            Function::Factorial if value < 0 => Err(CalcError::Domain(
+                "factorial of a negative number".to_string(),
+            )),
+            Function::Factorial => (2..=value)
+                .try_fold(1i64, |product, factor| product.checked_mul(factor))
+                .ok_or(CalcError::Overflow),
+            Function::Abs => value.checked_abs().ok_or(CalcError::Overflow),
+        }
+    }
+}
+
+impl Number for BigInt {
+    fn parse(literal: &str) -> Result<Self, CalcError> {
+        BigInt::from_str(literal)
+            .map_err(|_| CalcError::InvalidInput(format!("'{}' is not an integer", literal)))
+    }
+
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError> {
+        Ok(match operator {
+            Operator::Add => left + right,
+            Operator::Sub => left - right,
+            Operator::Mul | Operator::Lcm if left.bits() + right.bits() > MAX_RESULT_BITS => {
+                return Err(CalcError::Overflow)
+            }
+            Operator::Mul => left * right,
+            Operator::Div if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Div => left / right,
+            Operator::Pow => integer_pow(&left, &right)?,
+            Operator::Mod if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Mod => left % right,
+            Operator::Gcd => left.gcd(&right),
+            Operator::Lcm => left.lcm(&right),
+        })
+    }
+
+    fn negate(self) -> Result<Self, CalcError> {
+        Ok(-self)
+    }
+
+    fn call(function: Function, value: Self) -> Result<Self, CalcError> {
+        match function {
+            Function::Sqrt if value.is_negative() => Err(negative_square_root()),
+            Function::Sqrt => Ok(value.sqrt()),
+            Function::Factorial => integer_factorial(&value),
+            Function::Abs => Ok(value.abs()),
+        }
+    }
+}
+
+/// Accepts integers, fractions (`7/2`) and decimal literals (`3.25`).
+impl Number for BigRational {
+    fn parse(literal: &str) -> Result<Self, CalcError> {
+        let invalid = || CalcError::InvalidInput(format!("'{}' is not a rational number", literal));
+
+        if let Some((numerator, denominator)) = literal.split_once('/') {
+            let numerator = BigInt::from_str(numerator.trim()).map_err(|_| invalid())?;
+            let denominator = BigInt::from_str(denominator.trim()).map_err(|_| invalid())?;
+            if denominator.is_zero() {
+                return Err(CalcError::DivisionByZero);
+            }
+            return Ok(BigRational::new(numerator, denominator));
+        }
+
+        let (whole, fraction) = literal.split_once('.').unwrap_or((literal, ""));
+        if fraction.starts_with(['+', '-']) {
+            return Err(invalid());
+        }
+        let digits = BigInt::from_str(&format!("{}{}", whole, fraction)).map_err(|_| invalid())?;
+        let denominator = BigInt::from(10).pow(fraction.len());
+        Ok(BigRational::new(digits, denominator))
+    }
+
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError> {
+        let integer = |value: &BigRational| {
+            require_integer(operator, value.is_integer().then(|| value.to_integer()))
+        };
+        Ok(match operator {
+            Operator::Add | Operator::Sub | Operator::Mul | Operator::Div
+                if rational_bits(&left) + rational_bits(&right) > MAX_RESULT_BITS =>
+            {
+                return Err(CalcError::Overflow)
+            }
+            Operator::Add => left + right,
+            Operator::Sub => left - right,
+            Operator::Mul => left * right,
+            Operator::Div if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Div => left / right,
+            Operator::Pow if !right.is_integer() => return Err(fractional_exponent()),
+            Operator::Pow if left.is_zero() && right.is_negative() => {
+                return Err(CalcError::DivisionByZero)
+            }
+            Operator::Pow => {
+                let exponent = right.to_integer();
+                let magnitude = exponent.abs();
+                let power = BigRational::new(
+                    integer_pow(left.numer(), &magnitude)?,
+                    integer_pow(left.denom(), &magnitude)?,
+                );
+                if exponent.is_negative() {
+                    power.recip()
+                } else {
+                    power
+                }
+            }
+            Operator::Mod if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Mod => left % right,
+            Operator::Gcd | Operator::Lcm => BigRational::from_integer(BigInt::apply(
+                operator,
+                integer(&left)?,
+                integer(&right)?,
+            )?),
+        })
+    }
+
+    fn negate(self) -> Result<Self, CalcError> {
+        Ok(-self)
+    }
+
+    /// Square roots are exact, irrational ones are a domain error.
+    fn call(function: Function, value: Self) -> Result<Self, CalcError> {
+        match function {
+            Function::Sqrt if value.is_negative() => Err(negative_square_root()),
+            Function::Sqrt => {
+                let (numerator, denominator) = (value.numer().sqrt(), value.denom().sqrt());
+                if &numerator * &numerator == *value.numer()
+                    && &denominator * &denominator == *value.denom()
+                {
+                    Ok(BigRational::new(numerator, denominator))
+                } else {
+                    Err(CalcError::Domain(format!(
+                        "{} has no rational square root",
+                        value
+                    )))
+                }
+            }
+            Function::Factorial => {
+                let integer = value.is_integer().then(|| value.to_integer());
+                Ok(BigRational::from_integer(integer_factorial(
+                    &require_integer(function, integer)?,
+                )?))
+            }
+            Function::Abs => Ok(value.abs()),
+        }
+    }
+}
+
+impl Number for Decimal {
+    fn parse(literal: &str) -> Result<Self, CalcError> {
+        Decimal::from_str(literal)
+            .map_err(|_| CalcError::InvalidInput(format!("'{}' is not a decimal number", literal)))
+    }
+
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError> {
+        match operator {
+            Operator::Add => left.checked_add(right),
+            Operator::Sub => left.checked_sub(right),
+            Operator::Mul => left.checked_mul(right),
+            Operator::Div if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Div => left.checked_div(right),
+            Operator::Pow if !right.fract().is_zero() => return Err(fractional_exponent()),
+            Operator::Pow if left.is_zero() && right.is_sign_negative() => {
+                return Err(CalcError::DivisionByZero)
+            }
+            Operator::Pow => decimal_pow(left, right),
+            Operator::Mod if right.is_zero() => return Err(CalcError::DivisionByZero),
+            Operator::Mod => left.checked_rem(right),
+            Operator::Gcd | Operator::Lcm => {
+                let result = BigInt::apply(
+                    operator,
+                    require_integer(operator, decimal_to_integer(left))?,
+                    require_integer(operator, decimal_to_integer(right))?,
+                )?;
+                Decimal::from_str(&result.to_string()).ok()
+            }
+        }
+        .ok_or(CalcError::Overflow)
+    }
+
+    fn negate(self) -> Result<Self, CalcError> {
+        Ok(-self)
+    }
+
+    fn call(function: Function, value: Self) -> Result<Self, CalcError> {
+        match function {
+            Function::Sqrt if value.is_sign_negative() && !value.is_zero() => {
+                Err(negative_square_root())
+            }
+            Function::Sqrt => value.sqrt().ok_or(CalcError::Overflow),
+            Function::Factorial => {
+                let integer = require_integer(function, decimal_to_integer(value))?;
+                Decimal::from_str(&integer_factorial(&integer)?.to_string())
+                    .map_err(|_| CalcError::Overflow)
+            }
+            Function::Abs => Ok(value.abs()),
+        }
+    }
+}
+
+fn decimal_to_integer(value: Decimal) -> Option<BigInt> {
+    if value.fract().is_zero() {
+        BigInt::from_str(&value.trunc().to_string()).ok()
+    } else {
+        None
+    }
+}
+
+impl Number for f64 {
+    fn parse(literal: &str) -> Result<Self, CalcError> {
+        match literal.parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(number),
+            _ => Err(CalcError::InvalidInput(format!(
+                "'{}' is not a finite number",
+                literal
+            ))),
+        }
+    }
+
+    fn apply(operator: Operator, left: Self, right: Self) -> Result<Self, CalcError> {
+        let integer = |value: f64| {
+            let integer = (value.fract() == 0.0)
+                .then(|| BigInt::from_f64(value))
+                .flatten();
+            require_integer(operator, integer)
+        };
+        let result = match operator {
+            Operator::Add => left + right,
+            Operator::Sub => left - right,
+            Operator::Mul => left * right,
+            Operator::Div if right == 0.0 => return Err(CalcError::DivisionByZero),
+            Operator::Div => left / right,
+            Operator::Pow if left == 0.0 && right < 0.0 => return Err(CalcError::DivisionByZero),
+            Operator::Pow => left.powf(right),
+            Operator::Mod if right == 0.0 => return Err(CalcError::DivisionByZero),
+            Operator::Mod => left % right,
+            Operator::Gcd | Operator::Lcm => {
+                BigInt::apply(operator, integer(left)?, integer(right)?)?
+                    .to_f64()
+                    .unwrap_or(f64::INFINITY)
+            }
+        };
+        float_result(result)
+    }
+
+    fn negate(self) -> Result<Self, CalcError> {
+        Ok(-self)
+    }
+
+    fn call(function: Function, value: Self) -> Result<Self, CalcError> {
+        let result = match function {
+            Function::Sqrt if value < 0.0 => return Err(negative_square_root()),
+            Function::Sqrt => value.sqrt(),
+            Function::Factorial => {
+                let integer = (value.fract() == 0.0)
+                    .then(|| BigInt::from_f64(value))
+                    .flatten();
+                integer_factorial(&require_integer(function, integer)?)?
+                    .to_f64()
+                    .unwrap_or(f64::INFINITY)
+            }
+            Function::Abs => value.abs(),
+        };
+        float_result(result)
+    }
+}
+
+/// A negative base with a fractional exponent has no real result.
+fn float_result(result: f64) -> Result<f64, CalcError> {
+    if result.is_nan() {
+        Err(CalcError::Domain("result is not a real number".to_string()))
+    } else if result.is_infinite() {
+        Err(CalcError::Overflow)
+    } else {
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Integer,
+    BigInteger,
+    Rational,
+    Decimal {
+        scale: u32,
+    },
+    Float,
+}
+
+impl Mode {
+    pub fn from_proto(mode: i32, scale: u32) -> Result<Self, CalcError> {
+        match NumberMode::from_i32(mode) {
+            Some(NumberMode::Integer) => Ok(Mode::Integer),
+            Some(NumberMode::BigInteger) => Ok(Mode::BigInteger),
+            Some(NumberMode::Rational) => Ok(Mode::Rational),
+            Some(NumberMode::Decimal) if scale > MAX_DECIMAL_SCALE => Err(CalcError::InvalidInput(
+                format!("decimal scale must be at most {}", MAX_DECIMAL_SCALE),
+            )),
+            Some(NumberMode::Decimal) => Ok(Mode::Decimal { scale }),
+            Some(NumberMode::Float) => Ok(Mode::Float),
+            None => Err(CalcError::InvalidInput(format!(
+                "unknown number mode {}",
+                mode
+            ))),
+        }
+    }
+
+    pub fn to_proto(self) -> (NumberMode, u32) {
+        match self {
+            Mode::Integer => (NumberMode::Integer, 0),
+            Mode::BigInteger => (NumberMode::BigInteger, 0),
+            Mode::Rational => (NumberMode::Rational, 0),
+            Mode::Decimal { scale } => (NumberMode::Decimal, scale),
+            Mode::Float => (NumberMode::Float, 0),
+        }
+    }
+
+    /// Applies a single operator to two number literals.
+    pub fn compute(self, operator: Operator, left: &str, right: &str) -> Result<String, CalcError> {
+        fn compute<N: Number>(operator: Operator, left: &str, right: &str) -> Result<N, CalcError> {
+            N::apply(operator, N::parse(left)?, N::parse(right)?)
+        }
+
+        Ok(match self {
+            Mode::Integer => compute::<i64>(operator, left, right)?.to_string(),
+            Mode::BigInteger => compute::<BigInt>(operator, left, right)?.to_string(),
+            Mode::Rational => compute::<BigRational>(operator, left, right)?.to_string(),
+            Mode::Decimal { scale } => {
+                render_decimal(compute::<Decimal>(operator, left, right)?, scale)
+            }
+            Mode::Float => compute::<f64>(operator, left, right)?.to_string(),
+        })
+    }
+
+    /// Applies a function to a single number literal.
+    pub fn call(self, function: Function, value: &str) -> Result<String, CalcError> {
+        fn call<N: Number>(function: Function, value: &str) -> Result<N, CalcError> {
+            N::call(function, N::parse(value)?)
+        }
+
+        Ok(match self {
+            Mode::Integer => call::<i64>(function, value)?.to_string(),
+            Mode::BigInteger => call::<BigInt>(function, value)?.to_string(),
+            Mode::Rational => call::<BigRational>(function, value)?.to_string(),
+            Mode::Decimal { scale } => render_decimal(call::<Decimal>(function, value)?, scale),
+            Mode::Float => call::<f64>(function, value)?.to_string(),
+        })
+    }
+
+    pub fn evaluate(self, expr: &Expr, variables: &Variables) -> Result<String, ExpressionError> {
+        Ok(match self {
+            Mode::Integer => expr.evaluate::<i64>(variables)?.to_string(),
+            Mode::BigInteger => expr.evaluate::<BigInt>(variables)?.to_string(),
+            Mode::Rational => expr.evaluate::<BigRational>(variables)?.to_string(),
+            Mode::Decimal { scale } => render_decimal(expr.evaluate::<Decimal>(variables)?, scale),
+            Mode::Float => expr.evaluate::<f64>(variables)?.to_string(),
+        })
+    }
+}
+
+fn render_decimal(mut number: Decimal, scale: u32) -> String {
+    number.rescale(scale);
+    number.to_string()
+}
+
+#[test]
+fn integer_division_truncates_but_rational_is_exact() {
+    assert_eq!(
+        Mode::Integer.compute(Operator::Div, "7", "2"),
+        Ok("3".into())
+    );
+    assert_eq!(
+        Mode::Rational.compute(Operator::Div, "7", "2"),
+        Ok("7/2".into())
+    );
+    assert_eq!(
+        Mode::Rational.compute(Operator::Add, "1/3", "0.5"),
+        Ok("5/6".into())
+    );
+    assert_eq!(
+        Mode::Rational.compute(Operator::Mul, "3/2", "2"),
+        Ok("3".into())
+    );
+}
+
+#[test]
+fn big_integers_do_not_overflow() {
+    assert_eq!(
+        Mode::Integer.compute(Operator::Mul, "9223372036854775807", "2"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        Mode::BigInteger.compute(Operator::Mul, "9223372036854775807", "2"),
+        Ok("18446744073709551614".into())
+    );
+}
+
+#[test]
+fn decimals_use_fixed_scale() {
+    let mode = Mode::Decimal { scale: 2 };
+    assert_eq!(mode.compute(Operator::Div, "7", "2"), Ok("3.50".into()));
+    assert_eq!(mode.compute(Operator::Div, "1", "3"), Ok("0.33".into()));
+    assert_eq!(
+        mode.compute(Operator::Div, "1", "0"),
+        Err(CalcError::DivisionByZero)
+    );
+}
+
+#[test]
+fn floats_reject_non_finite_results() {
+    assert_eq!(
+        Mode::Float.compute(Operator::Div, "7", "2"),
+        Ok("3.5".into())
+    );
+    assert_eq!(
+        Mode::Float.compute(Operator::Mul, "1e308", "10"),
+        Err(CalcError::Overflow)
+    );
+    assert!(Mode::Float.compute(Operator::Add, "NaN", "1").is_err());
+}
+
+#[test]
+fn modes_map_from_proto() {
+    assert_eq!(
+        Mode::from_proto(NumberMode::Decimal as i32, 4),
+        Ok(Mode::Decimal { scale: 4 })
+    );
+    assert!(Mode::from_proto(NumberMode::Decimal as i32, 29).is_err());
+    assert!(Mode::from_proto(42, 0).is_err());
+    assert_eq!(
+        BigRational::parse("1"),
+        Ok(BigRational::from_integer(BigInt::from(1)))
+    );
+}
+
+#[test]
+fn integer_powers_and_functions() {
+    assert_eq!(
+        Mode::Integer.compute(Operator::Pow, "-1", "9999999999"),
+        Ok("-1".into())
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Pow, "2", "63"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Mod, "-7", "2"),
+        Ok("-1".into())
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Mod, "7", "0"),
+        Err(CalcError::DivisionByZero)
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Gcd, "-12", "18"),
+        Ok("6".into())
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Lcm, "4", "-6"),
+        Ok("12".into())
+    );
+    assert_eq!(
+        Mode::Integer.compute(Operator::Gcd, "-9223372036854775808", "0"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(Mode::Integer.call(Function::Sqrt, "99"), Ok("9".into()));
+    assert_eq!(
+        Mode::Integer.call(Function::Factorial, "20"),
+        Ok("2432902008176640000".into())
+    );
+    assert_eq!(
+        Mode::Integer.call(Function::Factorial, "21"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        Mode::Integer.call(Function::Abs, "-9223372036854775808"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        Mode::BigInteger.call(Function::Factorial, "25"),
+        Ok("15511210043330985984000000".into())
+    );
+    assert_eq!(
+        Mode::BigInteger.compute(Operator::Pow, "10", "100000000"),
+        Err(CalcError::Overflow)
+    );
+}
+
+#[test]
+fn products_are_capped() {
+    let large = BigInt::from(1) << (MAX_RESULT_BITS / 2);
+    assert!(BigInt::apply(Operator::Mul, large.clone(), large.clone() >> 2).is_ok());
+    assert_eq!(
+        BigInt::apply(Operator::Mul, large.clone(), large.clone()),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        BigInt::apply(Operator::Lcm, large.clone() + 1, large.clone()),
+        Err(CalcError::Overflow)
+    );
+    let fraction = BigRational::new(BigInt::from(1), large);
+    assert_eq!(
+        BigRational::apply(
+            Operator::Add,
+            fraction.clone(),
+            fraction.recip() + BigInt::from(1)
+        ),
+        Err(CalcError::Overflow)
+    );
+}
+
+#[test]
+fn decimal_powers_with_huge_exponents() {
+    let decimal = Mode::Decimal { scale: 2 };
+    let huge = "9223372036854775807";
+    assert_eq!(decimal.compute(Operator::Pow, "1", huge), Ok("1.00".into()));
+    assert_eq!(
+        decimal.compute(Operator::Pow, "-1", huge),
+        Ok("-1.00".into())
+    );
+    assert_eq!(
+        decimal.compute(Operator::Pow, "-1.0", "100000000000000000000"),
+        Ok("1.00".into())
+    );
+    assert_eq!(decimal.compute(Operator::Pow, "0", huge), Ok("0.00".into()));
+    assert_eq!(
+        decimal.compute(Operator::Pow, "0.5", huge),
+        Ok("0.00".into())
+    );
+    assert_eq!(
+        decimal.compute(Operator::Pow, "1.01", huge),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        decimal.compute(Operator::Pow, "0.5", "-9000000000000000000"),
+        Err(CalcError::Overflow)
+    );
+    assert_eq!(
+        decimal.compute(Operator::Pow, "1.5", "3"),
+        Ok("3.38".into())
+    );
+}
+
+#[test]
+fn domain_errors() {
+    for mode in [
+        Mode::Integer,
+        Mode::BigInteger,
+        Mode::Rational,
+        Mode::Decimal { scale: 2 },
+        Mode::Float,
+    ] {
+        assert!(matches!(
+            mode.call(Function::Sqrt, "-4"),
+            Err(CalcError::Domain(_))
+        ));
+        assert!(matches!(
+            mode.call(Function::Factorial, "-1"),
+            Err(CalcError::Domain(_))
+        ));
+    }
+    assert_eq!(
+        Mode::Rational.call(Function::Sqrt, "2"),
+        Err(CalcError::Domain("2 has no rational square root".into()))
+    );
+    assert_eq!(
+        Mode::Rational.compute(Operator::Gcd, "1/2", "3"),
+        Err(CalcError::Domain("gcd is only defined for integers".into()))
+    );
+    assert!(matches!(
+        Mode::Float.compute(Operator::Pow, "-8", "0.5"),
+        Err(CalcError::Domain(_))
+    ));
+}
+
+#[test]
+fn functions_in_other_modes() {
+    assert_eq!(Mode::Rational.call(Function::Sqrt, "9/4"), Ok("3/2".into()));
+    assert_eq!(
+        Mode::Rational.compute(Operator::Pow, "2/3", "-2"),
+        Ok("9/4".into())
+    );
+    assert_eq!(
+        Mode::Rational.compute(Operator::Lcm, "4", "6.0"),
+        Ok("12".into())
+    );
+    let decimal = Mode::Decimal { scale: 3 };
+    assert_eq!(decimal.call(Function::Sqrt, "2"), Ok("1.414".into()));
+    assert_eq!(
+        decimal.compute(Operator::Pow, "1.5", "-1"),
+        Ok("0.667".into())
+    );
+    assert_eq!(
+        decimal.compute(Operator::Mod, "7.5", "2"),
+        Ok("1.500".into())
+    );
+    assert_eq!(
+        decimal.call(Function::Factorial, "5.00"),
+        Ok("120.000".into())
+    );
+    assert_eq!(
+        Mode::Float.compute(Operator::Pow, "2", "0.5"),
+        Ok(2f64.sqrt().to_string())
+    );
+    assert_eq!(Mode::Float.call(Function::Factorial, "5"), Ok("120".into()));
+    assert_eq!(Mode::Float.call(Function::Abs, "-2.5"), Ok("2.5".into()));
+}
@@ -0,0 +1,603 @@
+use crate::cache::{bypasses_cache, CacheKey, ResultCache};
+use crate::calculator_service::calculator_service_server::CalculatorService;
+use crate::calculator_service::{
+    calc_request, calc_response, evaluation_result, CalcInput, CalcOutput, CalcRequest,
+    CalcResponse, CreateSessionRequest, ErrorCode, EvaluationResult, ExpressionError,
+    ExpressionRequest, MemoryOperation, MemoryRequest, SessionInfo, UnaryInput,
+};
+use crate::error::{CalcError, ERROR_CODE_HEADER};
+use crate::expression::{self, Function, Operator, Variables};
+use crate::number::Mode;
+use crate::session::{SessionStore, SESSION_TOKEN_HEADER};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Expressions longer than this are rejected without being parsed.
+const MAX_EXPRESSION_LENGTH: usize = 4096;
+/// Number of batch items computed at the same time for a single stream.
+const BATCH_CONCURRENCY: usize = 64;
+
+/// Paths of the RPCs, requests for others are counted as unknown.
+pub const METHODS: &[&str] = &[
+    "/calculator_service.CalculatorService/Add",
+    "/calculator_service.CalculatorService/Sub",
+    "/calculator_service.CalculatorService/Mul",
+    "/calculator_service.CalculatorService/Div",
+    "/calculator_service.CalculatorService/Pow",
+    "/calculator_service.CalculatorService/Mod",
+    "/calculator_service.CalculatorService/Gcd",
+    "/calculator_service.CalculatorService/Lcm",
+    "/calculator_service.CalculatorService/Sqrt",
+    "/calculator_service.CalculatorService/Factorial",
+    "/calculator_service.CalculatorService/Abs",
+    "/calculator_service.CalculatorService/Evaluate",
+    "/calculator_service.CalculatorService/BatchCompute",
+    "/calculator_service.CalculatorService/CreateSession",
+    "/calculator_service.CalculatorService/Memory",
+];
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<CalcResponse, Status>> + Send>>;
+
+/// Implementation of `CalculatorService`, serve it through
+/// `CalculatorServiceServer`.
+#[derive(Default, Clone)]
+pub struct Calculator {
+    sessions: SessionStore,
+    cache: Option<ResultCache>,
+}
+
+fn session_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(SESSION_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+}
+
+fn failure(error: &CalcError, position: usize) -> ExpressionError {
+    ExpressionError {
+        message: error.to_string(),
+        position: position as u32,
+        code: error.code() as i32,
+    }
+}
+
+/// Tags responses that carry a failed calculation with its error code, for
+/// the request log.
+fn respond<T>(message: T, code: i32) -> Response<T> {
+    let mut response = Response::new(message);
+    match ErrorCode::from_i32(code) {
+        Some(ErrorCode::NoError) | None => {}
+        Some(code) => {
+            if let Ok(value) = format!("{:?}", code).parse() {
+                response.metadata_mut().insert(ERROR_CODE_HEADER, value);
+            }
+        }
+    }
+    response
+}
+
+fn output(result: Result<String, CalcError>) -> CalcOutput {
+    match result {
+        Ok(result) => CalcOutput {
+            result,
+            error: ErrorCode::NoError as i32,
+            message: String::new(),
+        },
+        Err(error) => CalcOutput {
+            result: String::new(),
+            error: error.code() as i32,
+            message: error.to_string(),
+        },
+    }
+}
+
+fn evaluation_response(outcome: evaluation_result::Outcome) -> Response<EvaluationResult> {
+    let code = match &outcome {
+        evaluation_result::Outcome::Value(_) => ErrorCode::NoError as i32,
+        evaluation_result::Outcome::Error(error) => error.code,
+    };
+    respond(
+        EvaluationResult {
+            outcome: Some(outcome),
+        },
+        code,
+    )
+}
+
+impl Calculator {
+    pub fn new(sessions: SessionStore) -> Self {
+        Calculator {
+            sessions,
+            cache: None,
+        }
+    }
+
+    /// Memoizes results of computations that do not depend on a session.
+    pub fn with_cache(self, cache: ResultCache) -> Self {
+        Calculator {
+            cache: Some(cache),
+            ..self
+        }
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    pub fn cache(&self) -> Option<&ResultCache> {
+        self.cache.as_ref()
+    }
+
+    /// The cache to use for a request, unless it asked to bypass it.
+    fn cache_for<T>(&self, request: &Request<T>) -> Option<&ResultCache> {
+        self.cache
+            .as_ref()
+            .filter(|_| !bypasses_cache(request.metadata()))
+    }
+
+    /// Only an invalid request is an error here, failed calculations are
+    /// reported inside the output message.
+    fn compute(
+        operator: Operator,
+        input: &CalcInput,
+        cache: Option<&ResultCache>,
+    ) -> Result<CalcOutput, CalcError> {
+        let mode = Mode::from_proto(input.mode, input.scale)?;
+        let compute = || output(mode.compute(operator, &input.a, &input.b));
+        Ok(match cache {
+            Some(cache) => {
+                let key = CacheKey::new(operator, &[&input.a, &input.b], input.mode, input.scale);
+                cache.output(key, compute)
+            }
+            None => compute(),
+        })
+    }
+
+    fn call(
+        function: Function,
+        input: &UnaryInput,
+        cache: Option<&ResultCache>,
+    ) -> Result<CalcOutput, CalcError> {
+        let mode = Mode::from_proto(input.mode, input.scale)?;
+        let compute = || output(mode.call(function, &input.a));
+        Ok(match cache {
+            Some(cache) => {
+                let key = CacheKey::new(function, &[&input.a], input.mode, input.scale);
+                cache.output(key, compute)
+            }
+            None => compute(),
+        })
+    }
+
+    /// Evaluates an expression that cannot see any session variables, so
+    /// its result only depends on the request.
+    fn evaluate_pure(
+        request: &ExpressionRequest,
+        cache: Option<&ResultCache>,
+    ) -> Result<evaluation_result::Outcome, CalcError> {
+        match cache {
+            // invalid requests are cheap to reject and not worth caching
+            Some(cache) if request.expression.len() <= MAX_EXPRESSION_LENGTH => {
+                Mode::from_proto(request.mode, request.scale)?;
+                let key = CacheKey::new(
+                    "evaluate",
+                    &[&request.expression],
+                    request.mode,
+                    request.scale,
+                );
+                Ok(cache.outcome(key, || {
+                    Self::evaluate(request, &mut Variables::new()).expect("request was validated")
+                }))
+            }
+            _ => Self::evaluate(request, &mut Variables::new()),
+        }
+    }
+
+    fn evaluate(
+        request: &ExpressionRequest,
+        variables: &mut Variables,
+    ) -> Result<evaluation_result::Outcome, CalcError> {
+        let input = &request.expression;
+        if input.len() > MAX_EXPRESSION_LENGTH {
+            return Err(CalcError::InvalidInput(format!(
+                "expression is longer than {} bytes",
+                MAX_EXPRESSION_LENGTH
+            )));
+        }
+
+        let mode = Mode::from_proto(request.mode, request.scale)?;
+
+        let outcome = match expression::evaluate(input, mode, variables) {
+            Ok(value) => evaluation_result::Outcome::Value(value),
+            Err(error) => evaluation_result::Outcome::Error(failure(&error.kind, error.position)),
+        };
+        Ok(outcome)
+    }
+
+    /// Computes the request inside its session, if it has one, and records
+    /// a successful result as the session's last answer.
+    fn compute_in_session<T>(
+        &self,
+        request: &Request<T>,
+        compute: impl FnOnce(&T, Option<&ResultCache>) -> Result<CalcOutput, CalcError>,
+    ) -> Result<CalcOutput, CalcError> {
+        let cache = self.cache_for(request);
+        let token = match session_token(request) {
+            Some(token) => token,
+            None => return compute(request.get_ref(), cache),
+        };
+        // computed outside the session, other sessions need not wait
+        self.sessions
+            .with(token, |_| ())
+            .ok_or(CalcError::UnknownSession)?;
+        let output = compute(request.get_ref(), cache)?;
+        if output.error == ErrorCode::NoError as i32 {
+            self.sessions
+                .with(token, |session| session.record_answer(&output.result))
+                .ok_or(CalcError::UnknownSession)?;
+        }
+        Ok(output)
+    }
+
+    fn evaluate_in_session(
+        &self,
+        request: &Request<ExpressionRequest>,
+    ) -> Result<evaluation_result::Outcome, CalcError> {
+        let token = match session_token(request) {
+            Some(token) => token,
+            None => return Self::evaluate_pure(request.get_ref(), self.cache_for(request)),
+        };
+        // evaluated on a copy, other sessions need not wait, and only the
+        // assigned variable is written back
+        let variables = self
+            .sessions
+            .with(token, |session| session.variables.clone())
+            .ok_or(CalcError::UnknownSession)?;
+        let mut updated = variables.clone();
+        let outcome = Self::evaluate(request.get_ref(), &mut updated)?;
+        let value = match &outcome {
+            evaluation_result::Outcome::Value(value) => value,
+            evaluation_result::Outcome::Error(_) => return Ok(outcome),
+        };
+        updated.retain(|name, value| variables.get(name) != Some(value));
+        let stored = self
+            .sessions
+            .with(token, |session| {
+                session.assign(updated)?;
+                session.record_answer(value);
+                Ok(())
+            })
+            .ok_or(CalcError::UnknownSession)?;
+        Ok(match stored {
+            Ok(()) => outcome,
+            Err(error) => evaluation_result::Outcome::Error(failure(&error, 0)),
+        })
+    }
+
+    fn memory(
+        &self,
+        request: &Request<MemoryRequest>,
+    ) -> Result<evaluation_result::Outcome, CalcError> {
+        let token = session_token(request).ok_or(CalcError::SessionRequired)?;
+        let input = request.get_ref();
+        let mode = Mode::from_proto(input.mode, input.scale)?;
+        let operation = MemoryOperation::from_i32(input.operation).ok_or_else(|| {
+            CalcError::InvalidInput(format!("unknown memory operation {}", input.operation))
+        })?;
+
+        let result = self
+            .sessions
+            .with(token, |session| session.memory(operation, mode))
+            .ok_or(CalcError::UnknownSession)?;
+        Ok(match result {
+            Ok(value) => evaluation_result::Outcome::Value(value),
+            Err(error) => evaluation_result::Outcome::Error(failure(&error, 0)),
+        })
+    }
+
+    /// Computes a single batch item. Every failure, including an invalid
+    /// request, is reported on the item itself so the stream keeps going.
+    fn process(
+        request: CalcRequest,
+        variables: &Variables,
+        cache: Option<&ResultCache>,
+    ) -> CalcResponse {
+        let single = |output: Result<CalcOutput, CalcError>| {
+            output.map(|output| {
+                if output.error == ErrorCode::NoError as i32 {
+                    calc_response::Outcome::Value(output.result)
+                } else {
+                    calc_response::Outcome::Error(ExpressionError {
+                        message: output.message,
+                        position: 0,
+                        code: output.error,
+                    })
+                }
+            })
+        };
+
+        let outcome = match request.operation {
+            Some(calc_request::Operation::Add(input)) => {
+                single(Self::compute(Operator::Add, &input, cache))
+            }
+            Some(calc_request::Operation::Sub(input)) => {
+                single(Self::compute(Operator::Sub, &input, cache))
+            }
+            Some(calc_request::Operation::Mul(input)) => {
+                single(Self::compute(Operator::Mul, &input, cache))
+            }
+            Some(calc_request::Operation::Div(input)) => {
+                single(Self::compute(Operator::Div, &input, cache))
+            }
+            Some(calc_request::Operation::Pow(input)) => {
+                single(Self::compute(Operator::Pow, &input, cache))
+            }
+            Some(calc_request::Operation::Mod(input)) => {
+                single(Self::compute(Operator::Mod, &input, cache))
+            }
+            Some(calc_request::Operation::Gcd(input)) => {
+                single(Self::compute(Operator::Gcd, &input, cache))
+            }
+            Some(calc_request::Operation::Lcm(input)) => {
+                single(Self::compute(Operator::Lcm, &input, cache))
+            }
+            Some(calc_request::Operation::Sqrt(input)) => {
+                single(Self::call(Function::Sqrt, &input, cache))
+            }
+            Some(calc_request::Operation::Factorial(input)) => {
+                single(Self::call(Function::Factorial, &input, cache))
+            }
+            Some(calc_request::Operation::Abs(input)) => {
+                single(Self::call(Function::Abs, &input, cache))
+            }
+            Some(calc_request::Operation::Evaluate(request)) => {
+                let outcome = if variables.is_empty() {
+                    Self::evaluate_pure(&request, cache)
+                } else {
+                    Self::evaluate(&request, &mut variables.clone())
+                };
+                outcome.map(|outcome| match outcome {
+                    evaluation_result::Outcome::Value(value) => {
+                        calc_response::Outcome::Value(value)
+                    }
+                    evaluation_result::Outcome::Error(error) => {
+                        calc_response::Outcome::Error(error)
+                    }
+                })
+            }
+            None => Err(CalcError::InvalidInput("missing operation".to_string())),
+        };
+
+        CalcResponse {
+            id: request.id,
+            outcome: Some(
+                outcome.unwrap_or_else(|error| calc_response::Outcome::Error(failure(&error, 0))),
+            ),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CalculatorService for Calculator {
+    async fn add(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Add, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn sub(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Sub, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn mul(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Mul, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn div(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Div, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn pow(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Pow, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn r#mod(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Mod, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn gcd(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Gcd, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn lcm(&self, request: Request<CalcInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::compute(Operator::Lcm, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn sqrt(&self, request: Request<UnaryInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::call(Function::Sqrt, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn factorial(
+        &self,
+        request: Request<UnaryInput>,
+    ) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::call(Function::Factorial, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn abs(&self, request: Request<UnaryInput>) -> Result<Response<CalcOutput>, Status> {
+        let output = self.compute_in_session(&request, |input, cache| {
+            Self::call(Function::Abs, input, cache)
+        })?;
+        let code = output.error;
+        Ok(respond(output, code))
+    }
+
+    async fn evaluate(
+        &self,
+        request: Request<ExpressionRequest>,
+    ) -> Result<Response<EvaluationResult>, Status> {
+        let outcome = self.evaluate_in_session(&request)?;
+        Ok(evaluation_response(outcome))
+    }
+
+    type BatchComputeStream = ResponseStream;
+
+    async fn batch_compute(
+        &self,
+        request: Request<Streaming<CalcRequest>>,
+    ) -> Result<Response<Self::BatchComputeStream>, Status> {
+        // items only get a snapshot of the session, their completion order
+        // is arbitrary so letting them update it would make no sense
+        let variables = match session_token(&request) {
+            Some(token) => self
+                .sessions
+                .with(token, |session| session.variables.clone())
+                .ok_or(CalcError::UnknownSession)?,
+            None => Variables::new(),
+        };
+        let variables = Arc::new(variables);
+        let cache = self.cache_for(&request).cloned();
+
+        let mut requests = request.into_inner();
+        let (sender, receiver) = mpsc::channel(BATCH_CONCURRENCY);
+        let permits = Arc::new(Semaphore::new(BATCH_CONCURRENCY));
+
+        tokio::spawn(async move {
+            loop {
+                let request = match requests.message().await {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(status) => {
+                        // the incoming stream is broken, nothing more can be read
+                        let _ = sender.send(Err(status)).await;
+                        break;
+                    }
+                };
+
+                let permit = match permits.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let sender = sender.clone();
+                let variables = variables.clone();
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    let response = Self::process(request, &variables, cache.as_ref());
+                    let _ = sender.send(Ok(response)).await;
+                    drop(permit);
+                });
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn create_session(
+        &self,
+        _request: Request<CreateSessionRequest>,
+    ) -> Result<Response<SessionInfo>, Status> {
+        Ok(Response::new(SessionInfo {
+            token: self
+                .sessions
+                .create()
+                .ok_or_else(|| Status::resource_exhausted("too many sessions"))?,
+            idle_timeout_seconds: self.sessions.idle_timeout().as_secs(),
+        }))
+    }
+
+    async fn memory(
+        &self,
+        request: Request<MemoryRequest>,
+    ) -> Result<Response<EvaluationResult>, Status> {
+        let outcome = Calculator::memory(self, &request)?;
+        Ok(evaluation_response(outcome))
+    }
+}
+
+#[test]
+fn methods_match_the_proto() {
+    use crate::calculator_service::FILE_DESCRIPTOR_SET;
+    use prost::Message;
+
+    // the parts of the descriptor.proto messages naming the methods
+    #[derive(Clone, PartialEq, Message)]
+    struct MethodDescriptor {
+        #[prost(string, tag = "1")]
+        name: String,
+    }
+    #[derive(Clone, PartialEq, Message)]
+    struct ServiceDescriptor {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(message, repeated, tag = "2")]
+        method: Vec<MethodDescriptor>,
+    }
+    #[derive(Clone, PartialEq, Message)]
+    struct FileDescriptor {
+        #[prost(string, tag = "2")]
+        package: String,
+        #[prost(message, repeated, tag = "6")]
+        service: Vec<ServiceDescriptor>,
+    }
+    #[derive(Clone, PartialEq, Message)]
+    struct FileDescriptorSet {
+        #[prost(message, repeated, tag = "1")]
+        file: Vec<FileDescriptor>,
+    }
+
+    let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+    let mut methods = vec![];
+    for file in &set.file {
+        for service in &file.service {
+            for method in &service.method {
+                methods.push(format!(
+                    "/{}.{}/{}",
+                    file.package, service.name, method.name
+                ));
+            }
+        }
+    }
+    assert_eq!(methods, METHODS);
+}
@@ -0,0 +1,264 @@
+use crate::calculator_service::MemoryOperation;
+use crate::error::CalcError;
+use crate::expression::{Operator, Variables};
+use crate::number::Mode;
+use dashmap::DashMap;
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Metadata key the client sends the session token under.
+pub const SESSION_TOKEN_HEADER: &str = "session-token";
+/// Variable holding the result of the last successful calculation.
+pub const ANSWER: &str = "ans";
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Sessions alive at the same time, more cannot be created until some
+    /// of them expired.
+    pub max_sessions: usize,
+    /// Variables a session holds at most, besides the last answer.
+    pub max_variables: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            max_sessions: 10_000,
+            max_variables: 100,
+        }
+    }
+}
+
+pub struct Session {
+    pub variables: Variables,
+    memory: Option<String>,
+    last_used: Instant,
+    max_variables: usize,
+}
+
+impl Session {
+    fn new(max_variables: usize) -> Self {
+        Session {
+            variables: Variables::new(),
+            memory: None,
+            last_used: Instant::now(),
+            max_variables,
+        }
+    }
+
+    pub fn record_answer(&mut self, value: &str) {
+        self.variables.insert(ANSWER.to_string(), value.to_string());
+    }
+
+    /// Stores assigned values, unless the session would hold more variables
+    /// than it may.
+    pub fn assign(&mut self, assigned: Variables) -> Result<(), CalcError> {
+        let added = assigned
+            .keys()
+            .filter(|name| !self.variables.contains_key(*name) && *name != ANSWER)
+            .count();
+        let held = self.variables.len() - usize::from(self.variables.contains_key(ANSWER));
+        if held + added > self.max_variables {
+            return Err(CalcError::InvalidInput(format!(
+                "a session holds at most {} variables",
+                self.max_variables
+            )));
+        }
+        self.variables.extend(assigned);
+        Ok(())
+    }
+
+    /// Applies a memory key and returns the value the calculator would
+    /// display afterwards. An empty memory register reads as zero.
+    pub fn memory(&mut self, operation: MemoryOperation, mode: Mode) -> Result<String, CalcError> {
+        let current = self.memory.clone().unwrap_or_else(|| "0".to_string());
+        let operator = match operation {
+            MemoryOperation::MemoryRecall => {
+                self.record_answer(&current);
+                return Ok(current);
+            }
+            MemoryOperation::MemoryClear => {
+                self.memory = None;
+                return Ok("0".to_string());
+            }
+            MemoryOperation::MemoryAdd => Operator::Add,
+            MemoryOperation::MemorySubtract => Operator::Sub,
+        };
+
+        let answer = self.variables.get(ANSWER).ok_or_else(|| {
+            CalcError::InvalidInput("there is no answer to store in memory yet".to_string())
+        })?;
+        let updated = mode.compute(operator, &current, answer)?;
+        self.memory = Some(updated.clone());
+        Ok(updated)
+    }
+}
+
+/// Sessions shared by all connections, keyed by token.
+#[derive(Clone)]
+pub struct SessionStore {
+    sessions: Arc<DashMap<String, Session>>,
+    idle_timeout: Duration,
+    config: SessionConfig,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        SessionStore::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration) -> Self {
+        SessionStore {
+            sessions: Arc::new(DashMap::new()),
+            idle_timeout,
+            config: SessionConfig::default(),
+        }
+    }
+
+    /// Caps the sessions and their variables, anyone may create a session.
+    pub fn with_limits(self, config: &SessionConfig) -> Self {
+        SessionStore {
+            config: config.clone(),
+            ..self
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Returns the token of a new session, or `None` when there are as many
+    /// as allowed.
+    pub fn create(&self) -> Option<String> {
+        if self.sessions.len() >= self.config.max_sessions {
+            return None;
+        }
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        self.sessions
+            .insert(token.clone(), Session::new(self.config.max_variables));
+        Some(token)
+    }
+
+    /// Runs `f` on the session and marks it as used. Returns `None` when the
+    /// session does not exist or has been idle for too long. Other sessions
+    /// may wait for `f`, it should not compute anything expensive.
+    pub fn with<R>(&self, token: &str, f: impl FnOnce(&mut Session) -> R) -> Option<R> {
+        let idle_timeout = self.idle_timeout;
+        self.sessions.remove_if(token, |_, session| {
+            session.last_used.elapsed() > idle_timeout
+        });
+
+        let mut session = self.sessions.get_mut(token)?;
+        session.last_used = Instant::now();
+        Some(f(&mut session))
+    }
+
+    pub fn purge_expired(&self) {
+        let idle_timeout = self.idle_timeout;
+        self.sessions
+            .retain(|_, session| session.last_used.elapsed() <= idle_timeout);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Periodically drops idle sessions nobody is going to touch again.
+    pub fn spawn_expiry(&self, interval: Duration) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                store.purge_expired();
+            }
+        })
+    }
+}
+
+#[test]
+fn memory_registers() {
+    let store = SessionStore::default();
+    let token = store.create().unwrap();
+    let memory = |operation| store.with(&token, |session| session.memory(operation, Mode::Integer));
+
+    assert!(memory(MemoryOperation::MemoryAdd).unwrap().is_err());
+    store.with(&token, |session| session.record_answer("5"));
+    assert_eq!(memory(MemoryOperation::MemoryAdd), Some(Ok("5".into())));
+    assert_eq!(memory(MemoryOperation::MemoryAdd), Some(Ok("10".into())));
+    store.with(&token, |session| session.record_answer("3"));
+    assert_eq!(
+        memory(MemoryOperation::MemorySubtract),
+        Some(Ok("7".into()))
+    );
+    assert_eq!(memory(MemoryOperation::MemoryRecall), Some(Ok("7".into())));
+    assert_eq!(
+        store.with(&token, |session| session.variables.get(ANSWER).cloned()),
+        Some(Some("7".into()))
+    );
+    assert_eq!(memory(MemoryOperation::MemoryClear), Some(Ok("0".into())));
+    assert_eq!(memory(MemoryOperation::MemoryRecall), Some(Ok("0".into())));
+    assert_eq!(store.with("missing", |_| ()), None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_sessions_expire() {
+    let store = SessionStore::new(Duration::from_secs(60));
+    let active = store.create().unwrap();
+    let idle = store.create().unwrap();
+
+    tokio::time::advance(Duration::from_secs(45)).await;
+    assert!(store.with(&active, |_| ()).is_some());
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert!(store.with(&active, |_| ()).is_some());
+    assert!(store.with(&idle, |_| ()).is_none());
+
+    let expiry = store.spawn_expiry(Duration::from_secs(10));
+    store.create().unwrap();
+    tokio::time::sleep(Duration::from_secs(75)).await;
+    assert!(store.is_empty());
+    expiry.abort();
+}
+
+#[test]
+fn sessions_and_variables_are_capped() {
+    let store = SessionStore::default().with_limits(&SessionConfig {
+        max_sessions: 2,
+        max_variables: 2,
+    });
+    let token = store.create().unwrap();
+    store.create().unwrap();
+    assert_eq!(store.create(), None);
+
+    let assign = |names: &[&str]| {
+        let assigned = names
+            .iter()
+            .map(|name| (name.to_string(), "1".to_string()))
+            .collect();
+        store
+            .with(&token, |session| session.assign(assigned))
+            .unwrap()
+    };
+    store.with(&token, |session| session.record_answer("1"));
+    assert_eq!(assign(&["x", "y"]), Ok(()));
+    // reassigning is fine, a third variable is not
+    assert_eq!(assign(&["x", ANSWER]), Ok(()));
+    assert!(assign(&["z"]).is_err());
+    assert_eq!(
+        store.with(&token, |session| session.variables.len()),
+        Some(3)
+    );
+}
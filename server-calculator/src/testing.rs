@@ -0,0 +1,152 @@
+use crate::auth::AuthInterceptor;
+use crate::calculator_service::calculator_service_client::CalculatorServiceClient;
+use crate::calculator_service::calculator_service_server::CalculatorServiceServer;
+use crate::config::ClientConfig;
+use crate::service::Calculator;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Channel, Server};
+
+/// A calculator server running in the background on an ephemeral port, for
+/// testing code that talks to it. It is stopped when dropped.
+///
+/// ```no_run
+/// # async fn example() {
+/// use server_calculator::calculator_service::CalcInput;
+/// use server_calculator::testing::TestServer;
+///
+/// let server = TestServer::start().await;
+/// let mut client = server.client().await;
+/// let output = client.add(CalcInput {
+///     a: "2".to_string(),
+///     b: "3".to_string(),
+///     ..CalcInput::default()
+/// });
+/// assert_eq!(output.await.unwrap().into_inner().result, "5");
+/// # }
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    calculator: Calculator,
+    stop: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct TestServerBuilder {
+    calculator: Calculator,
+    tokens: Vec<String>,
+}
+
+impl TestServerBuilder {
+    /// Serves this calculator, e.g. one with a cache or short-lived sessions.
+    pub fn calculator(mut self, calculator: Calculator) -> Self {
+        self.calculator = calculator;
+        self
+    }
+
+    /// Requires clients to authenticate with one of these bearer tokens.
+    pub fn tokens(mut self, tokens: Vec<String>) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    pub async fn start(self) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind an ephemeral port");
+        let addr = listener.local_addr().expect("listener has no address");
+
+        let (mut health, health_service) = tonic_health::server::health_reporter();
+        health
+            .set_serving::<CalculatorServiceServer<Calculator>>()
+            .await;
+        let service = CalculatorServiceServer::with_interceptor(
+            self.calculator.clone(),
+            AuthInterceptor::new(self.tokens),
+        );
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = Server::builder()
+            .add_service(health_service)
+            .add_service(service)
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                let _ = stopped.await;
+            });
+        let handle = tokio::spawn(async move {
+            server.await.expect("test server failed");
+        });
+
+        TestServer {
+            addr,
+            calculator: self.calculator,
+            stop: Some(stop),
+            handle,
+        }
+    }
+}
+
+impl TestServer {
+    /// Starts a server without authentication.
+    pub async fn start() -> Self {
+        Self::builder().start().await
+    }
+
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn uri(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The calculator being served, to inspect its sessions or cache.
+    pub fn calculator(&self) -> &Calculator {
+        &self.calculator
+    }
+
+    /// Configuration for a `CalculatorClient` connecting to this server.
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            host: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            ..ClientConfig::default()
+        }
+    }
+
+    pub async fn channel(&self) -> Channel {
+        Channel::from_shared(self.uri())
+            .expect("invalid test server uri")
+            .connect()
+            .await
+            .expect("failed to connect to the test server")
+    }
+
+    /// A plain generated client, it sends no credentials by itself.
+    pub async fn client(&self) -> CalculatorServiceClient<Channel> {
+        CalculatorServiceClient::new(self.channel().await)
+    }
+
+    /// Stops accepting connections and waits for in-flight requests.
+    pub async fn stop(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        (&mut self.handle).await.expect("test server panicked");
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
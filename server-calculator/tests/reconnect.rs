@@ -0,0 +1,167 @@
+use server_calculator::calculator_service::calculator_service_server::CalculatorServiceServer;
+use server_calculator::calculator_service::{
+    evaluation_result, CalcInput, ExpressionRequest, MemoryOperation, MemoryRequest,
+};
+use server_calculator::client::CalculatorClient;
+use server_calculator::config::{ClientConfig, RetryConfig};
+use server_calculator::service::Calculator;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use tonic::Code;
+
+/// A server that can be stopped and started again on the same port, each
+/// time with a fresh `Calculator` as if the process had restarted.
+struct Restartable {
+    port: u16,
+    running: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl Restartable {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut server = Restartable {
+            port: listener.local_addr().unwrap().port(),
+            running: None,
+        };
+        server.serve(listener);
+        server
+    }
+
+    fn serve(&mut self, listener: TcpListener) {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = Server::builder()
+            .add_service(CalculatorServiceServer::new(Calculator::default()))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                let _ = stopped.await;
+            });
+        let handle = tokio::spawn(async move { server.await.unwrap() });
+        self.running = Some((stop, handle));
+    }
+
+    async fn stop(&mut self) {
+        let (stop, handle) = self.running.take().expect("server is not running");
+        stop.send(()).unwrap();
+        handle.await.unwrap();
+    }
+
+    async fn restart(&mut self) {
+        let listener = TcpListener::bind(("127.0.0.1", self.port)).await.unwrap();
+        self.serve(listener);
+    }
+
+    fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            host: "127.0.0.1".to_string(),
+            port: self.port,
+            deadline_ms: Some(1000),
+            retry: RetryConfig {
+                max_retries: 20,
+                initial_backoff_ms: 20,
+                max_backoff_ms: 100,
+            },
+            ..ClientConfig::default()
+        }
+    }
+}
+
+async fn add(client: &mut CalculatorClient) -> Result<String, tonic::Status> {
+    let input = CalcInput {
+        a: "2".to_string(),
+        b: "3".to_string(),
+        ..CalcInput::default()
+    };
+    let response = client
+        .call(true, input, |mut client, request| async move {
+            client.add(request).await
+        })
+        .await?;
+    Ok(response.into_inner().result)
+}
+
+async fn evaluate(client: &mut CalculatorClient, expression: &str) -> evaluation_result::Outcome {
+    let request = ExpressionRequest {
+        expression: expression.to_string(),
+        ..ExpressionRequest::default()
+    };
+    client
+        .call(true, request, |mut client, request| async move {
+            client.evaluate(request).await
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome
+        .unwrap()
+}
+
+#[tokio::test]
+async fn calls_survive_a_server_restart() {
+    let mut server = Restartable::start().await;
+    let mut client = CalculatorClient::connect(&server.client_config())
+        .await
+        .unwrap();
+    assert_eq!(add(&mut client).await.unwrap(), "5");
+    assert_eq!(client.retries(), 0);
+    evaluate(&mut client, "x = 1").await;
+
+    server.stop().await;
+    let restart = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        server.restart().await;
+        server
+    });
+
+    assert_eq!(add(&mut client).await.unwrap(), "5");
+    assert!(client.retries() > 0);
+    let mut server = restart.await.unwrap();
+
+    // the restarted server does not know the old session, the client moves
+    // on to a new one and the variable is gone
+    assert!(matches!(
+        evaluate(&mut client, "x").await,
+        evaluation_result::Outcome::Error(_)
+    ));
+    assert_eq!(client.retries(), 0);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn calls_that_change_state_are_not_retried() {
+    let mut server = Restartable::start().await;
+    let mut client = CalculatorClient::connect(&server.client_config())
+        .await
+        .unwrap();
+    server.stop().await;
+
+    let request = MemoryRequest {
+        operation: MemoryOperation::MemoryAdd as i32,
+        ..MemoryRequest::default()
+    };
+    let status = client
+        .call(false, request, |mut client, request| async move {
+            client.memory(request).await
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    assert_eq!(client.retries(), 0);
+}
+
+#[tokio::test]
+async fn retries_give_up() {
+    let mut server = Restartable::start().await;
+    let mut config = server.client_config();
+    config.retry.max_retries = 2;
+    let mut client = CalculatorClient::connect(&config).await.unwrap();
+    server.stop().await;
+
+    assert_eq!(
+        add(&mut client).await.unwrap_err().code(),
+        Code::Unavailable
+    );
+    assert_eq!(client.retries(), 2);
+}
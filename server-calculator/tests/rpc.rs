@@ -0,0 +1,364 @@
+use server_calculator::auth::AUTHORIZATION_HEADER;
+use server_calculator::calculator_service::calculator_service_client::CalculatorServiceClient;
+use server_calculator::calculator_service::{
+    calc_request, calc_response, evaluation_result, CalcInput, CalcOutput, CalcRequest,
+    CreateSessionRequest, ErrorCode, ExpressionRequest, MemoryOperation, MemoryRequest, NumberMode,
+    UnaryInput,
+};
+use server_calculator::client::CalculatorClient;
+use server_calculator::error::ERROR_CODE_HEADER;
+use server_calculator::session::SESSION_TOKEN_HEADER;
+use server_calculator::testing::TestServer;
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+use tonic_health::proto::health_check_response::ServingStatus;
+use tonic_health::proto::health_client::HealthClient;
+use tonic_health::proto::HealthCheckRequest;
+
+type Client = CalculatorServiceClient<Channel>;
+
+fn binary(a: &str, b: &str) -> CalcInput {
+    CalcInput {
+        a: a.to_string(),
+        b: b.to_string(),
+        ..CalcInput::default()
+    }
+}
+
+fn unary(a: &str) -> UnaryInput {
+    UnaryInput {
+        a: a.to_string(),
+        ..UnaryInput::default()
+    }
+}
+
+fn expression(expression: &str) -> ExpressionRequest {
+    ExpressionRequest {
+        expression: expression.to_string(),
+        ..ExpressionRequest::default()
+    }
+}
+
+fn in_session<T>(token: &str, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert(SESSION_TOKEN_HEADER, token.parse().unwrap());
+    request
+}
+
+fn value(outcome: Option<evaluation_result::Outcome>) -> String {
+    match outcome {
+        Some(evaluation_result::Outcome::Value(value)) => value,
+        other => panic!("expected a value, got {:?}", other),
+    }
+}
+
+fn error_code(output: &CalcOutput) -> Option<ErrorCode> {
+    ErrorCode::from_i32(output.error)
+}
+
+async fn create_session(client: &mut Client) -> String {
+    client
+        .create_session(CreateSessionRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .token
+}
+
+#[tokio::test]
+async fn binary_operations() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let result = |output: tonic::Response<CalcOutput>| output.into_inner().result;
+    assert_eq!(result(client.add(binary("2", "3")).await.unwrap()), "5");
+    assert_eq!(result(client.sub(binary("2", "3")).await.unwrap()), "-1");
+    assert_eq!(result(client.mul(binary("4", "5")).await.unwrap()), "20");
+    assert_eq!(result(client.div(binary("7", "2")).await.unwrap()), "3");
+    assert_eq!(result(client.pow(binary("2", "10")).await.unwrap()), "1024");
+    assert_eq!(result(client.r#mod(binary("7", "3")).await.unwrap()), "1");
+    assert_eq!(result(client.gcd(binary("12", "18")).await.unwrap()), "6");
+    assert_eq!(result(client.lcm(binary("4", "6")).await.unwrap()), "12");
+
+    let rational = CalcInput {
+        mode: NumberMode::Rational as i32,
+        ..binary("1/3", "1/6")
+    };
+    assert_eq!(result(client.add(rational).await.unwrap()), "1/2");
+}
+
+#[tokio::test]
+async fn unary_operations() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let result = |output: tonic::Response<CalcOutput>| output.into_inner().result;
+    assert_eq!(result(client.sqrt(unary("16")).await.unwrap()), "4");
+    assert_eq!(result(client.factorial(unary("5")).await.unwrap()), "120");
+    assert_eq!(result(client.abs(unary("-7")).await.unwrap()), "7");
+}
+
+#[tokio::test]
+async fn failed_calculations_are_reported_in_the_output() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let response = client.div(binary("1", "0")).await.unwrap();
+    assert_eq!(
+        response.metadata().get(ERROR_CODE_HEADER).unwrap(),
+        "DivisionByZero"
+    );
+    let output = response.into_inner();
+    assert_eq!(error_code(&output), Some(ErrorCode::DivisionByZero));
+    assert!(output.result.is_empty());
+
+    let output = client.add(binary("1", "two")).await.unwrap().into_inner();
+    assert_eq!(error_code(&output), Some(ErrorCode::InvalidInput));
+    let output = client.sqrt(unary("-1")).await.unwrap().into_inner();
+    assert_eq!(error_code(&output), Some(ErrorCode::DomainError));
+    let output = client
+        .mul(binary("9223372036854775807", "2"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(error_code(&output), Some(ErrorCode::Overflow));
+}
+
+#[tokio::test]
+async fn invalid_requests_are_rejected() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let unknown_mode = CalcInput {
+        mode: 42,
+        ..binary("1", "2")
+    };
+    let status = client.add(unknown_mode).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let status = client
+        .evaluate(expression(&"1+".repeat(4096)))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn evaluate() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let outcome = client
+        .evaluate(expression("2 * (3 + 4) ^ 2"))
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome;
+    assert_eq!(value(outcome), "98");
+
+    let outcome = client
+        .evaluate(expression("1 + (2"))
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome;
+    match outcome {
+        Some(evaluation_result::Outcome::Error(error)) => {
+            assert_eq!(
+                ErrorCode::from_i32(error.code),
+                Some(ErrorCode::InvalidInput)
+            );
+            assert_eq!(error.position, 6);
+        }
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn sessions_keep_variables_and_memory() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+    let token = create_session(&mut client).await;
+    assert_eq!(server.calculator().sessions().len(), 1);
+
+    client
+        .evaluate(in_session(&token, expression("x = 6")))
+        .await
+        .unwrap();
+    let outcome = client
+        .evaluate(in_session(&token, expression("x * 7")))
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome;
+    assert_eq!(value(outcome), "42");
+
+    let memory = |operation: MemoryOperation| {
+        in_session(
+            &token,
+            MemoryRequest {
+                operation: operation as i32,
+                ..MemoryRequest::default()
+            },
+        )
+    };
+    client
+        .memory(memory(MemoryOperation::MemoryAdd))
+        .await
+        .unwrap();
+    client
+        .add(in_session(&token, binary("1", "2")))
+        .await
+        .unwrap();
+    client
+        .memory(memory(MemoryOperation::MemorySubtract))
+        .await
+        .unwrap();
+    let recalled = client
+        .memory(memory(MemoryOperation::MemoryRecall))
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome;
+    assert_eq!(value(recalled), "39");
+
+    // a fresh session sees none of it
+    let other = create_session(&mut client).await;
+    let outcome = client
+        .evaluate(in_session(&other, expression("x")))
+        .await
+        .unwrap()
+        .into_inner()
+        .outcome;
+    assert!(matches!(
+        outcome,
+        Some(evaluation_result::Outcome::Error(_))
+    ));
+}
+
+#[tokio::test]
+async fn session_errors() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let status = client
+        .add(in_session("no-such-session", binary("1", "2")))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert_eq!(
+        status.metadata().get(ERROR_CODE_HEADER).unwrap(),
+        "UnknownSession"
+    );
+
+    let status = client.memory(MemoryRequest::default()).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn batch_compute() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let requests = vec![
+        CalcRequest {
+            id: 1,
+            operation: Some(calc_request::Operation::Add(binary("1", "2"))),
+        },
+        CalcRequest {
+            id: 2,
+            operation: Some(calc_request::Operation::Div(binary("1", "0"))),
+        },
+        CalcRequest {
+            id: 3,
+            operation: Some(calc_request::Operation::Factorial(unary("4"))),
+        },
+        CalcRequest {
+            id: 4,
+            operation: Some(calc_request::Operation::Evaluate(expression("2 ^ 8"))),
+        },
+        CalcRequest {
+            id: 5,
+            operation: None,
+        },
+    ];
+    let mut responses = client
+        .batch_compute(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut outcomes = Vec::new();
+    while let Some(response) = responses.message().await.unwrap() {
+        outcomes.push((response.id, response.outcome.unwrap()));
+    }
+    // items complete in any order
+    outcomes.sort_by_key(|(id, _)| *id);
+
+    let values: Vec<_> = outcomes
+        .iter()
+        .map(|(id, outcome)| match outcome {
+            calc_response::Outcome::Value(value) => (*id, Ok(value.clone())),
+            calc_response::Outcome::Error(error) => (*id, Err(ErrorCode::from_i32(error.code))),
+        })
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            (1, Ok("3".to_string())),
+            (2, Err(Some(ErrorCode::DivisionByZero))),
+            (3, Ok("24".to_string())),
+            (4, Ok("256".to_string())),
+            (5, Err(Some(ErrorCode::InvalidInput))),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn authentication() {
+    let server = TestServer::builder()
+        .tokens(vec!["s3cret".to_string()])
+        .start()
+        .await;
+    let mut client = server.client().await;
+
+    let status = client.add(binary("1", "2")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut request = Request::new(binary("1", "2"));
+    request
+        .metadata_mut()
+        .insert(AUTHORIZATION_HEADER, "Bearer s3cret".parse().unwrap());
+    assert_eq!(client.add(request).await.unwrap().into_inner().result, "3");
+
+    // health checks work without a token
+    let mut health = HealthClient::new(server.channel().await);
+    let response = health
+        .check(HealthCheckRequest {
+            service: "calculator_service.CalculatorService".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.status, ServingStatus::Serving as i32);
+}
+
+#[tokio::test]
+async fn library_client() {
+    let server = TestServer::start().await;
+    let mut client = CalculatorClient::connect(&server.client_config())
+        .await
+        .unwrap();
+    let output = client
+        .call(true, binary("40", "2"), |mut client, request| async move {
+            client.add(request).await
+        })
+        .await
+        .unwrap();
+    assert_eq!(output.into_inner().result, "42");
+    assert_eq!(server.calculator().sessions().len(), 1);
+
+    server.stop().await;
+}
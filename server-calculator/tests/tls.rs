@@ -0,0 +1,162 @@
+use rcgen::{BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa};
+use server_calculator::auth::{AuthInterceptor, AUTHORIZATION_HEADER};
+use server_calculator::calculator_service::calculator_service_client::CalculatorServiceClient;
+use server_calculator::calculator_service::calculator_service_server::CalculatorServiceServer;
+use server_calculator::calculator_service::CalcInput;
+use server_calculator::config::{ClientConfig, TlsConfig};
+use server_calculator::service::Calculator;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Endpoint, Server};
+use tonic::{Code, Status};
+
+const TOKEN: &str = "s3cret";
+
+/// A throwaway CA with a server and a client certificate signed by it,
+/// written as PEM files into a temporary directory.
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn generate(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("calculator-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        let issue = |name: &str, purpose| {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+            params.extended_key_usages = vec![purpose];
+            let cert = Certificate::from_params(params).unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), pem).unwrap();
+            std::fs::write(
+                dir.join(format!("{}.key", name)),
+                cert.serialize_private_key_pem(),
+            )
+            .unwrap();
+        };
+        issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+        issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+
+        Pki { dir }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn server(&self, mutual: bool) -> TlsConfig {
+        TlsConfig {
+            cert: self.path("server.pem"),
+            key: self.path("server.key"),
+            client_ca: mutual.then(|| self.path("ca.pem")),
+        }
+    }
+
+    fn client(&self, port: u16, with_certificate: bool) -> ClientConfig {
+        let file = |name: &str| with_certificate.then(|| self.path(name));
+        ClientConfig {
+            port,
+            tls_ca: Some(self.path("ca.pem")),
+            tls_cert: file("client.pem"),
+            tls_key: file("client.key"),
+            ..ClientConfig::default()
+        }
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn start(tls: &TlsConfig, tokens: Vec<String>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let service = CalculatorServiceServer::with_interceptor(
+        Calculator::default(),
+        AuthInterceptor::new(tokens),
+    );
+    let server = Server::builder()
+        .tls_config(tls.load().unwrap())
+        .unwrap()
+        .add_service(service)
+        .serve_with_incoming(TcpListenerStream::new(listener));
+    tokio::spawn(server);
+    port
+}
+
+/// Connects and adds two numbers, sending `token` if there is one.
+async fn add(config: &ClientConfig, token: Option<&str>) -> Result<String, Status> {
+    let endpoint = Endpoint::from_shared(config.uri())
+        .unwrap()
+        .tls_config(config.tls_config().unwrap().unwrap())
+        .unwrap();
+    let channel = endpoint
+        .connect()
+        .await
+        .map_err(|error| Status::unavailable(error.to_string()))?;
+
+    let mut request = tonic::Request::new(CalcInput {
+        a: "2".to_string(),
+        b: "3".to_string(),
+        ..CalcInput::default()
+    });
+    if let Some(token) = token {
+        let authorization = format!("Bearer {}", token).parse().unwrap();
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_HEADER, authorization);
+    }
+    let mut client = CalculatorServiceClient::new(channel);
+    Ok(client.add(request).await?.into_inner().result)
+}
+
+#[tokio::test]
+async fn mutual_tls_with_bearer_token() {
+    let pki = Pki::generate("mutual");
+    let port = start(&pki.server(true), vec![TOKEN.to_string()]).await;
+    let client = pki.client(port, true);
+
+    assert_eq!(add(&client, Some(TOKEN)).await.unwrap(), "5");
+    for token in [None, Some("guess")] {
+        let status = add(&client, token).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
+
+#[tokio::test]
+async fn mutual_tls_requires_client_certificate() {
+    let pki = Pki::generate("anonymous");
+    let port = start(&pki.server(true), Vec::new()).await;
+
+    assert!(add(&pki.client(port, false), None).await.is_err());
+    assert_eq!(add(&pki.client(port, true), None).await.unwrap(), "5");
+}
+
+#[tokio::test]
+async fn server_only_tls() {
+    let pki = Pki::generate("server-only");
+    let port = start(&pki.server(false), Vec::new()).await;
+
+    assert_eq!(add(&pki.client(port, false), None).await.unwrap(), "5");
+}
+
+#[test]
+fn missing_certificate_files_are_reported() {
+    let tls = TlsConfig {
+        cert: Path::new("/nonexistent/server.pem").to_path_buf(),
+        key: Path::new("/nonexistent/server.key").to_path_buf(),
+        client_ca: None,
+    };
+    let error = tls.load().unwrap_err().to_string();
+    assert!(error.contains("/nonexistent/server.pem"), "{}", error);
+}